@@ -0,0 +1,255 @@
+use crate::{NoteDivision, NoteModifier, NoteValue};
+
+// Length of the longest accent pattern
+pub const MAX_ARP_STEPS: usize = 16;
+pub const MAX_ARP_OCTAVES: u8 = 4;
+// Longest pattern: every note over every octave, up and back down
+pub const MAX_PATTERN_NOTES: usize = 2 * 128 * MAX_ARP_OCTAVES as usize;
+// Arpeggiated notes play on a channel past the 16 MIDI ones, so they don't
+// share voices with keys played straight through
+pub const ARPEGGIATOR_CHANNEL: u8 = 17;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ArpMode {
+    Up,
+    Down,
+    UpDown,
+    Random,
+    AsPlayed,
+    Chord,
+}
+
+impl ArpMode {
+    pub const ALL: [ArpMode; 6] = [
+        ArpMode::Up,
+        ArpMode::Down,
+        ArpMode::UpDown,
+        ArpMode::Random,
+        ArpMode::AsPlayed,
+        ArpMode::Chord,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            ArpMode::Up => "Up",
+            ArpMode::Down => "Down",
+            ArpMode::UpDown => "Up/Down",
+            ArpMode::Random => "Random",
+            ArpMode::AsPlayed => "As Played",
+            ArpMode::Chord => "Chord",
+        }
+    }
+}
+
+// Note starts and stops produced by the arpeggiator for the synth to play
+#[derive(Clone, Copy)]
+pub enum NoteEvent {
+    On { note: u8, velocity: f32 },
+    Off { note: u8 },
+}
+
+pub struct Arpeggiator {
+    pub enabled: bool,
+    pub mode: ArpMode,
+    pub octaves: u8,
+    pub rate: NoteDivision,
+    // Fraction of each step the note sounds for
+    pub gate: f32,
+    // Fraction of a step every second step is pushed late by
+    pub swing: f32,
+    // Keep playing the last chord after the keys are let go
+    pub latch: bool,
+    pub steps: usize,
+    pub velocities: [f32; MAX_ARP_STEPS],
+    // Keys physically down, in the order they were pressed
+    held: Vec<u8>,
+    // Notes being arpeggiated; outlives `held` while latched
+    notes: Vec<u8>,
+    // The notes a cycle steps through, rebuilt each step in place so the
+    // audio thread doesn't allocate
+    pattern: Vec<u8>,
+    sounding: Vec<u8>,
+    // Steps played since the notes started, for the pattern and accents
+    counter: usize,
+    // Clock in quarter notes, following the transport while it plays
+    clock: f64,
+    last_step: Option<i64>,
+    gate_end: f64,
+}
+
+impl Arpeggiator {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            mode: ArpMode::Up,
+            octaves: 1,
+            rate: NoteDivision::new(NoteValue::Sixteenth, NoteModifier::Straight),
+            gate: 0.5,
+            swing: 0.0,
+            latch: false,
+            steps: 4,
+            velocities: [1.0; MAX_ARP_STEPS],
+            held: Vec::new(),
+            notes: Vec::with_capacity(128),
+            pattern: Vec::with_capacity(MAX_PATTERN_NOTES),
+            sounding: Vec::with_capacity(MAX_PATTERN_NOTES),
+            counter: 0,
+            clock: 0.0,
+            last_step: None,
+            gate_end: 0.0,
+        }
+    }
+
+    pub fn key_down(&mut self, note: u8) {
+        if self.held.contains(&note) {
+            return;
+        }
+        // The first key after a latched chord was let go starts a new chord
+        if self.held.is_empty() {
+            self.notes.clear();
+        }
+        if self.notes.is_empty() {
+            self.restart();
+        }
+        self.held.push(note);
+        if !self.notes.contains(&note) {
+            self.notes.push(note);
+        }
+    }
+
+    pub fn key_up(&mut self, note: u8) {
+        self.held.retain(|&held| held != note);
+        if !self.latch {
+            self.notes.retain(|&playing| playing != note);
+        }
+    }
+
+    // Whether a key went to the arpeggiator and hasn't come up
+    pub fn holds(&self, note: u8) -> bool {
+        self.held.contains(&note)
+    }
+
+    // Dropping latch lets go of everything no longer held
+    pub fn set_latch(&mut self, latch: bool) {
+        self.latch = latch;
+        if !latch {
+            self.notes.clone_from(&self.held);
+        }
+    }
+
+    // Forgets every key and returns the notes still sounding
+    pub fn clear(&mut self, events: &mut Vec<NoteEvent>) {
+        self.held.clear();
+        self.notes.clear();
+        self.stop_sounding(events);
+    }
+
+    fn restart(&mut self) {
+        self.counter = 0;
+        self.clock = 0.0;
+        self.last_step = None;
+    }
+
+    fn stop_sounding(&mut self, events: &mut Vec<NoteEvent>) {
+        events.extend(self.sounding.drain(..).map(|note| NoteEvent::Off { note }));
+    }
+
+    // Fills `pattern` with the notes a cycle steps through, lowest octave
+    // first
+    fn fill_pattern(&mut self) {
+        let pattern = &mut self.pattern;
+        pattern.clear();
+        pattern.extend_from_slice(&self.notes);
+        if self.mode != ArpMode::AsPlayed {
+            pattern.sort_unstable();
+        }
+        let base = pattern.len();
+        for octave in 1..self.octaves.max(1) as u16 {
+            for index in 0..base {
+                let note = pattern[index] as u16 + octave * 12;
+                if note <= 127 {
+                    pattern.push(note as u8);
+                }
+            }
+        }
+        match self.mode {
+            ArpMode::Down => pattern.reverse(),
+            // Turn around without repeating the top and bottom notes
+            ArpMode::UpDown if pattern.len() > 2 => {
+                for index in (1..pattern.len() - 1).rev() {
+                    pattern.push(pattern[index]);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Start of the step and its length, both in quarter notes. Swing delays
+    // the odd steps, shortening them by as much as it lengthens the even ones.
+    fn step_at(&self, clock: f64) -> (i64, f64, f64) {
+        let length = self.rate.beats() as f64;
+        let swing = self.swing as f64 * length;
+        let pair = (clock / (2.0 * length)).floor();
+        let pair_start = pair * 2.0 * length;
+        if clock - pair_start < length + swing {
+            (pair as i64 * 2, pair_start, length + swing)
+        } else {
+            (pair as i64 * 2 + 1, pair_start + length + swing, length - swing)
+        }
+    }
+
+    // Runs the clock on by one sample; `position` is the transport position
+    // while it plays, otherwise the arpeggiator keeps its own time
+    pub fn process(
+        &mut self,
+        position: Option<f64>,
+        tempo: f32,
+        sample_rate: f32,
+        events: &mut Vec<NoteEvent>,
+    ) {
+        if self.notes.is_empty() {
+            self.stop_sounding(events);
+            return;
+        }
+        let clock = match position {
+            Some(position) => position,
+            None => self.clock + tempo as f64 / 60.0 / sample_rate as f64,
+        };
+        // Catch the first step when the notes start with the transport stopped
+        let (step, start, length) = self.step_at(if self.last_step.is_none() && position.is_none() {
+            self.clock
+        } else {
+            clock
+        });
+        self.clock = clock;
+
+        if self.last_step != Some(step) {
+            self.last_step = Some(step);
+            self.stop_sounding(events);
+            self.fill_pattern();
+            let count = self.pattern.len();
+            if count > 0 {
+                let velocity = self.velocities[self.counter % self.steps.clamp(1, MAX_ARP_STEPS)];
+                let chosen = match self.mode {
+                    ArpMode::Chord => 0..count,
+                    ArpMode::Random => {
+                        let index = rand::random::<usize>() % count;
+                        index..index + 1
+                    }
+                    _ => {
+                        let index = self.counter % count;
+                        index..index + 1
+                    }
+                };
+                for &note in &self.pattern[chosen] {
+                    events.push(NoteEvent::On { note, velocity });
+                    self.sounding.push(note);
+                }
+                self.counter += 1;
+            }
+            self.gate_end = start + length * self.gate as f64;
+        } else if clock >= self.gate_end {
+            self.stop_sounding(events);
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use crate::scale::Scale;
+use std::collections::HashMap;
+
+const BUILTIN_CHORDS: [(&str, &[u8]); 15] = [
+    ("Major", &[0, 4, 7]),
+    ("Minor", &[0, 3, 7]),
+    ("Diminished", &[0, 3, 6]),
+    ("Augmented", &[0, 4, 8]),
+    ("Sus2", &[0, 2, 7]),
+    ("Sus4", &[0, 5, 7]),
+    ("Major 7", &[0, 4, 7, 11]),
+    ("Minor 7", &[0, 3, 7, 10]),
+    ("Dominant 7", &[0, 4, 7, 10]),
+    ("Half-Diminished 7", &[0, 3, 6, 10]),
+    ("Diminished 7", &[0, 3, 6, 9]),
+    ("Major 6", &[0, 4, 7, 9]),
+    ("Minor 6", &[0, 3, 7, 9]),
+    ("Add 9", &[0, 4, 7, 14]),
+    ("Dominant 9", &[0, 4, 7, 10, 14]),
+];
+
+#[derive(Clone)]
+pub struct Chord {
+    pub name: String,
+    // Semitones above the pressed key, ascending and starting at zero
+    pub intervals: Vec<u8>,
+    // Learned from held notes rather than a preset
+    pub learned: bool,
+}
+
+pub fn builtin() -> Vec<Chord> {
+    BUILTIN_CHORDS
+        .iter()
+        .map(|(name, intervals)| Chord {
+            name: name.to_string(),
+            intervals: intervals.to_vec(),
+            learned: false,
+        })
+        .collect()
+}
+
+// Every scale in a scale table as a chord of its pitches
+pub fn from_scales(scales: &[Scale]) -> Vec<Chord> {
+    scales
+        .iter()
+        .map(|scale| Chord {
+            name: scale.name.clone(),
+            intervals: scale.intervals(),
+            learned: false,
+        })
+        .filter(|chord| !chord.intervals.is_empty())
+        .collect()
+}
+
+// Plays a stored chord for every key while enabled. Keys are tracked even
+// when it is off, so chords can be learned and a key let go after the mode
+// changed still stops what it started.
+pub struct ChordMemory {
+    pub enabled: bool,
+    pub chords: Vec<Chord>,
+    pub selected: usize,
+    // Notes moved up an octave from the bottom of the chord
+    pub inversion: usize,
+    // Every other note raised an octave for an open voicing
+    pub spread: bool,
+    // The second note from the top dropped an octave
+    pub drop2: bool,
+    // Notes sounding for each key, by channel and note
+    sounding: HashMap<(u8, u8), Vec<u8>>,
+    learned: usize,
+}
+
+impl ChordMemory {
+    pub fn new(chords: Vec<Chord>) -> Self {
+        Self {
+            enabled: false,
+            chords,
+            selected: 0,
+            inversion: 0,
+            spread: false,
+            drop2: false,
+            sounding: HashMap::new(),
+            learned: 0,
+        }
+    }
+
+    // The selected chord on `note` with the voicing options applied
+    pub fn voice(&self, note: u8) -> Vec<u8> {
+        let Some(chord) = self.chords.get(self.selected) else {
+            return vec![note];
+        };
+        let mut intervals: Vec<i32> = chord.intervals.iter().map(|&interval| interval as i32).collect();
+        let count = intervals.len();
+        for interval in intervals.iter_mut().take(self.inversion.min(count.saturating_sub(1))) {
+            *interval += 12;
+        }
+        intervals.sort_unstable();
+        if self.drop2 && count >= 3 {
+            intervals[count - 2] -= 12;
+            intervals.sort_unstable();
+        }
+        if self.spread {
+            for interval in intervals.iter_mut().skip(1).step_by(2) {
+                *interval += 12;
+            }
+        }
+        let mut notes: Vec<u8> = intervals
+            .into_iter()
+            .map(|interval| note as i32 + interval)
+            .filter(|note| (0..=127).contains(note))
+            .map(|note| note as u8)
+            .collect();
+        notes.sort_unstable();
+        notes.dedup();
+        notes
+    }
+
+    // Notes to start for a key; empty when the key is already down
+    pub fn press(&mut self, channel: u8, note: u8) -> Vec<u8> {
+        if self.sounding.contains_key(&(channel, note)) {
+            return Vec::new();
+        }
+        let notes = match self.enabled {
+            true => self.voice(note),
+            false => vec![note],
+        };
+        self.sounding.insert((channel, note), notes.clone());
+        notes
+    }
+
+    // Notes to stop for a key, keeping any another held chord still uses
+    pub fn release(&mut self, channel: u8, note: u8) -> Vec<u8> {
+        let Some(mut notes) = self.sounding.remove(&(channel, note)) else {
+            return vec![note];
+        };
+        notes.retain(|played| {
+            !self
+                .sounding
+                .iter()
+                .any(|(key, others)| key.0 == channel && others.contains(played))
+        });
+        notes
+    }
+
+    // Keys held down, lowest first
+    pub fn held(&self) -> Vec<u8> {
+        let mut held: Vec<u8> = self.sounding.keys().map(|&(_, note)| note).collect();
+        held.sort_unstable();
+        held.dedup();
+        held
+    }
+
+    // Stores the held keys as a new chord and selects it. Needs at least
+    // two keys.
+    pub fn learn(&mut self) -> bool {
+        let held = self.held();
+        let Some(&lowest) = held.first().filter(|_| held.len() >= 2) else {
+            return false;
+        };
+        self.learned += 1;
+        self.chords.push(Chord {
+            name: format!("Learned {}", self.learned),
+            intervals: held.iter().map(|note| note - lowest).collect(),
+            learned: true,
+        });
+        self.selected = self.chords.len() - 1;
+        true
+    }
+}
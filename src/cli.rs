@@ -0,0 +1,463 @@
+use crate::{midi, patch, smf, tui, Effect, EffectAddress, EffectStack, Synth};
+use cpal::traits::{DeviceTrait, HostTrait};
+use std::process::ExitCode;
+
+const DEFAULT_PATCH: &str = "patch.toml";
+const DEFAULT_SAMPLE_RATE: u32 = 48000;
+// Seconds rendered after the last MIDI event, for releases and tails
+const DEFAULT_TAIL: f64 = 2.0;
+
+const USAGE: &str = "\
+Usage: synth [COMMAND]
+
+Commands:
+  gui                       Open the synthesizer window (the default)
+  tui                       Play in the terminal
+  render --midi FILE -o WAV [--patch FILE] [--sample-rate HZ] [--tail SECONDS]
+                            Play a MIDI file through a patch into a WAV file
+  play [--patch FILE]       Play headless from the first MIDI input
+  patch show [FILE]         Print a patch's effects, buses and patterns
+  patch convert FILE [-o FILE]
+                            Rewrite a patch in the current format
+  patch validate [FILE]     Check a patch loads and everything it refers to exists
+  devices                   List audio outputs and MIDI inputs
+  help                      Print this message
+
+patch show and patch validate default to patch.toml. render and play use
+the built-in sound unless --patch is given.";
+
+// Bad arguments exit with 2, anything failing while running with 1
+pub enum CliError {
+    Usage(String),
+    Failed(String),
+}
+
+impl From<String> for CliError {
+    fn from(message: String) -> Self {
+        CliError::Failed(message)
+    }
+}
+
+struct RenderOptions {
+    patch: Option<String>,
+    midi: String,
+    output: String,
+    sample_rate: u32,
+    tail: f64,
+}
+
+enum PatchCommand {
+    Show(String),
+    Convert { input: String, output: String },
+    Validate(String),
+}
+
+enum Command {
+    Gui,
+    Tui,
+    Render(RenderOptions),
+    Play { patch: Option<String> },
+    Patch(PatchCommand),
+    Devices,
+    Help,
+}
+
+pub fn main() -> ExitCode {
+    let result = parse(std::env::args().skip(1).collect()).and_then(run);
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(CliError::Usage(message)) => {
+            eprintln!("synth: {}", message);
+            eprintln!("Run `synth help` for usage.");
+            ExitCode::from(2)
+        }
+        Err(CliError::Failed(message)) => {
+            eprintln!("synth: {}", message);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// Hands out arguments in order, taking a flag's value along with it
+struct Arguments {
+    args: std::vec::IntoIter<String>,
+}
+
+impl Arguments {
+    fn next(&mut self) -> Option<String> {
+        self.args.next()
+    }
+
+    fn value(&mut self, flag: &str) -> Result<String, CliError> {
+        self.args
+            .next()
+            .ok_or_else(|| CliError::Usage(format!("{} needs a value", flag)))
+    }
+
+    fn number<T: std::str::FromStr>(&mut self, flag: &str) -> Result<T, CliError> {
+        let value = self.value(flag)?;
+        value
+            .parse()
+            .map_err(|_| CliError::Usage(format!("invalid value for {}: {}", flag, value)))
+    }
+
+    // The only positional argument left, if there is one
+    fn last(&mut self) -> Result<Option<String>, CliError> {
+        let value = self.next();
+        match self.next() {
+            Some(extra) => Err(unexpected(&extra)),
+            None => Ok(value),
+        }
+    }
+}
+
+fn unexpected(argument: &str) -> CliError {
+    CliError::Usage(format!("unexpected argument {}", argument))
+}
+
+fn parse(args: Vec<String>) -> Result<Command, CliError> {
+    let mut args = Arguments { args: args.into_iter() };
+    let command = match args.next().as_deref() {
+        None | Some("gui") => Command::Gui,
+        Some("tui") => Command::Tui,
+        Some("render") => {
+            let mut patch = None;
+            let mut midi = None;
+            let mut output = None;
+            let mut sample_rate = DEFAULT_SAMPLE_RATE;
+            let mut tail = DEFAULT_TAIL;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--patch" => patch = Some(args.value(&arg)?),
+                    "--midi" => midi = Some(args.value(&arg)?),
+                    "-o" | "--output" => output = Some(args.value(&arg)?),
+                    "--sample-rate" => sample_rate = args.number(&arg)?,
+                    "--tail" => tail = args.number(&arg)?,
+                    _ => return Err(unexpected(&arg)),
+                }
+            }
+            if !(8000..=384000).contains(&sample_rate) {
+                return Err(CliError::Usage(format!("sample rate {} out of range", sample_rate)));
+            }
+            if !(0.0..=600.0).contains(&tail) {
+                return Err(CliError::Usage(format!("tail {} out of range", tail)));
+            }
+            Command::Render(RenderOptions {
+                patch,
+                midi: midi.ok_or(CliError::Usage("render needs --midi".to_string()))?,
+                output: output.ok_or(CliError::Usage("render needs -o".to_string()))?,
+                sample_rate,
+                tail,
+            })
+        }
+        Some("play") => {
+            let mut patch = None;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--patch" => patch = Some(args.value(&arg)?),
+                    _ => return Err(unexpected(&arg)),
+                }
+            }
+            Command::Play { patch }
+        }
+        Some("patch") => {
+            let patch_command = match args.next().as_deref() {
+                Some("show") => PatchCommand::Show(args.last()?.unwrap_or(DEFAULT_PATCH.to_string())),
+                Some("validate") => PatchCommand::Validate(args.last()?.unwrap_or(DEFAULT_PATCH.to_string())),
+                Some("convert") => {
+                    let mut input = None;
+                    let mut output = None;
+                    while let Some(arg) = args.next() {
+                        match arg.as_str() {
+                            "-o" | "--output" => output = Some(args.value(&arg)?),
+                            _ if input.is_none() && !arg.starts_with('-') => input = Some(arg),
+                            _ => return Err(unexpected(&arg)),
+                        }
+                    }
+                    let input = input.ok_or(CliError::Usage("patch convert needs a file".to_string()))?;
+                    // Converted in place unless told otherwise
+                    let output = output.unwrap_or_else(|| input.clone());
+                    PatchCommand::Convert { input, output }
+                }
+                Some(other) => return Err(CliError::Usage(format!("unknown patch command {}", other))),
+                None => return Err(CliError::Usage("patch needs show, convert or validate".to_string())),
+            };
+            Command::Patch(patch_command)
+        }
+        Some("devices") => Command::Devices,
+        Some("help" | "-h" | "--help") => Command::Help,
+        Some(other) => return Err(CliError::Usage(format!("unknown command {}", other))),
+    };
+    if let Some(extra) = args.next() {
+        return Err(unexpected(&extra));
+    }
+    Ok(command)
+}
+
+fn run(command: Command) -> Result<(), CliError> {
+    match command {
+        Command::Gui => crate::run_gui()?,
+        Command::Tui => tui::run().map_err(|err| CliError::Failed(err.to_string()))?,
+        Command::Render(options) => render(&options)?,
+        Command::Play { patch } => play(patch.as_deref())?,
+        Command::Patch(PatchCommand::Show(path)) => show_patch(&path)?,
+        Command::Patch(PatchCommand::Convert { input, output }) => {
+            let loaded = patch::load(&input, DEFAULT_SAMPLE_RATE as f32)?;
+            let text = patch::to_string(&loaded.effects, &loaded.buses, &loaded.sequencer)?;
+            patch::write(&output, &text)?;
+            println!("Wrote {}", output);
+        }
+        Command::Patch(PatchCommand::Validate(path)) => {
+            let problems = validate_patch(&path)?;
+            if !problems.is_empty() {
+                for problem in &problems {
+                    println!("{}", problem);
+                }
+                return Err(CliError::Failed(format!("{} has {} problem(s)", path, problems.len())));
+            }
+            println!("{} is valid", path);
+        }
+        Command::Devices => devices()?,
+        Command::Help => println!("{}", USAGE),
+    }
+    Ok(())
+}
+
+// Plays the MIDI file into a fresh synth as fast as it renders and writes
+// 32-bit float stereo
+fn render(options: &RenderOptions) -> Result<(), String> {
+    let song = smf::read(&options.midi)?;
+    let sample_rate = options.sample_rate as f32;
+    let mut synth = Synth::new(sample_rate);
+    if let Some(path) = &options.patch {
+        synth.load_patch(patch::load(path, sample_rate)?);
+    }
+    // Tempo-synced effects follow the song from its first beat
+    synth.transport.play();
+
+    let spec = hound::WavSpec {
+        channels: 2,
+        sample_rate: options.sample_rate,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    let write_error = |err: hound::Error| format!("Failed to write {}: {}", options.output, err);
+    let mut writer = hound::WavWriter::create(&options.output, spec).map_err(write_error)?;
+
+    let mut parameters = midi::RegisteredParameters::default();
+    let mut messages = song.messages.iter().peekable();
+    let mut tempos = song.tempos.iter().peekable();
+    let duration = song.duration() + options.tail;
+    let frames = (duration * options.sample_rate as f64).ceil() as u64;
+    for frame in 0..frames {
+        let time = frame as f64 / options.sample_rate as f64;
+        while let Some((_, tempo)) = tempos.next_if(|(at, _)| *at <= time) {
+            synth.transport.tempo = *tempo;
+        }
+        while let Some(message) = messages.next_if(|message| message.time <= time) {
+            let timestamp = (message.time * 1_000_000.0) as u64;
+            midi::handle_message(&mut synth, &mut parameters, &message.message, timestamp);
+        }
+        let [left, right] = synth.get_next_frame();
+        writer.write_sample(left).map_err(write_error)?;
+        writer.write_sample(right).map_err(write_error)?;
+    }
+    writer.finalize().map_err(write_error)?;
+    println!("Rendered {:.1}s to {}", duration, options.output);
+    Ok(())
+}
+
+// Plays from the first MIDI input with no window until Enter is pressed
+fn play(patch_path: Option<&str>) -> Result<(), String> {
+    let (synth, _stream) = crate::start_audio()?;
+    if let Some(path) = patch_path {
+        let sample_rate = synth.lock().unwrap().sample_rate;
+        let loaded = patch::load(path, sample_rate)?;
+        synth.lock().unwrap().load_patch(loaded);
+    }
+    let midi = midi::connect(synth.clone()).ok_or("No MIDI input port found")?;
+    println!("Playing from {}. Press Enter to stop.", midi.port_name);
+
+    let mut line = String::new();
+    match std::io::stdin().read_line(&mut line) {
+        Ok(0) => {
+            // Without a terminal to read from, play until killed
+            loop {
+                std::thread::park();
+            }
+        }
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!("Failed to read input: {}", err)),
+    }
+}
+
+fn print_stack(stack: &mut EffectStack, bus: Option<usize>) {
+    if stack.slots.is_empty() {
+        println!("  (empty)");
+    }
+    let root = EffectAddress { bus, path: Vec::new() };
+    stack.for_each_slot(&root, &mut |address, slot| {
+        let slots: Vec<String> = address.path.iter().map(|index| (index + 1).to_string()).collect();
+        let indent = "  ".repeat(address.path.len() / 2 + 1);
+        println!(
+            "{indent}{} {}{} (mix {:.2}, gain {:.2})",
+            slots.join("."),
+            slot.effect.name(),
+            if slot.bypass { " [bypassed]" } else { "" },
+            slot.mix.target,
+            slot.gain.target,
+        );
+        if let Effect::Send(params) = &slot.effect {
+            println!("{indent}    to bus {}", params.bus + 1);
+        }
+        for parameter in slot.effect.parameters_mut() {
+            println!("{indent}    {}: {:.3}", parameter.name, parameter.param.target);
+        }
+    });
+}
+
+fn show_patch(path: &str) -> Result<(), String> {
+    let mut loaded = patch::load(path, DEFAULT_SAMPLE_RATE as f32)?;
+    println!("Effects:");
+    print_stack(&mut loaded.effects, None);
+    for (index, bus) in loaded.buses.iter_mut().enumerate() {
+        println!(
+            "Bus {} \"{}\" (voice send {:.2}, return {:.2}):",
+            index + 1,
+            bus.name,
+            bus.voice_send.target,
+            bus.return_level.target,
+        );
+        print_stack(&mut bus.effects, Some(index));
+    }
+    println!("Patterns:");
+    for (index, pattern) in loaded.sequencer.patterns.iter().enumerate() {
+        let steps = &pattern.steps[..pattern.length];
+        println!(
+            "  {} {}: {} steps of {}, {}, {} with notes, {} locks",
+            index + 1,
+            pattern.name,
+            pattern.length,
+            pattern.rate.label(),
+            if pattern.polyphonic { "polyphonic" } else { "monophonic" },
+            steps.iter().filter(|step| !step.notes.is_empty()).count(),
+            steps.iter().map(|step| step.locks.len()).sum::<usize>(),
+        );
+    }
+    if !loaded.sequencer.chain.is_empty() {
+        let chain: Vec<String> = loaded.sequencer.chain.iter().map(|index| (index + 1).to_string()).collect();
+        println!("Chain: {}", chain.join(" "));
+    }
+    Ok(())
+}
+
+// Everything in a patch that refers to something missing
+fn validate_patch(path: &str) -> Result<Vec<String>, String> {
+    let mut loaded = patch::read(path)?;
+    // A patch that can't be played isn't run to look for more
+    let mut problems = patch::problems(&mut loaded);
+    if !problems.is_empty() {
+        return Ok(problems);
+    }
+    patch::prepare(&mut loaded, DEFAULT_SAMPLE_RATE as f32);
+    let mut synth = Synth::new(DEFAULT_SAMPLE_RATE as f32);
+    synth.load_patch(loaded);
+
+    let bus_count = synth.buses.len();
+    let bus_names: Vec<String> = synth.buses.iter().map(|bus| bus.name.clone()).collect();
+    for bus in std::iter::once(None).chain((0..bus_count).map(Some)) {
+        let Some(stack) = synth.stack_mut(bus) else {
+            continue;
+        };
+        let root = EffectAddress { bus, path: Vec::new() };
+        stack.for_each_slot(&root, &mut |address, slot| {
+            let location = bus.map_or("Effect".to_string(), |bus| format!("{} effect", bus_names[bus]));
+            let slots: Vec<String> = address.path.iter().map(|index| (index + 1).to_string()).collect();
+            let label = format!("{} {} ({})", location, slots.join("."), slot.effect.name());
+            match &slot.effect {
+                Effect::Send(params) if params.bus >= bus_count => {
+                    problems.push(format!("{} sends to bus {}, which doesn't exist", label, params.bus + 1));
+                }
+                Effect::Send(params) if bus.is_some_and(|bus| params.bus <= bus) => {
+                    problems.push(format!("{} sends to bus {}, which runs before it", label, params.bus + 1));
+                }
+                Effect::Convolution(params) if !params.path.is_empty() && params.engine.is_none() => {
+                    problems.push(format!("{}: {}", label, params.status));
+                }
+                _ => {}
+            }
+        });
+    }
+
+    let sequencer = std::mem::take(&mut synth.sequencer);
+    let pattern_count = sequencer.patterns.len();
+    for &entry in sequencer.chain.iter().filter(|&&entry| entry >= pattern_count) {
+        problems.push(format!("Chain refers to pattern {}, which doesn't exist", entry + 1));
+    }
+    for pattern in &sequencer.patterns {
+        for (index, step) in pattern.steps.iter().enumerate().take(pattern.length) {
+            for lock in &step.locks {
+                if synth.parameter_mut(&lock.target).is_none() {
+                    problems.push(format!(
+                        "{} step {} locks a parameter that doesn't exist",
+                        pattern.name,
+                        index + 1
+                    ));
+                }
+            }
+        }
+    }
+    Ok(problems)
+}
+
+fn devices() -> Result<(), String> {
+    let default_host = cpal::default_host().id();
+    println!("Audio outputs:");
+    // A host that fails is reported and the others are still listed
+    for host_id in cpal::available_hosts() {
+        let marker = if host_id == default_host { " (default)" } else { "" };
+        println!("  {}{}", host_id.name(), marker);
+        let host = match cpal::host_from_id(host_id) {
+            Ok(host) => host,
+            Err(err) => {
+                println!("    Failed to open audio host: {}", err);
+                continue;
+            }
+        };
+        let default_name = host.default_output_device().and_then(|device| device.name().ok());
+        let outputs = match host.output_devices() {
+            Ok(outputs) => outputs,
+            Err(err) => {
+                println!("    Failed to list devices: {}", err);
+                continue;
+            }
+        };
+        for device in outputs {
+            let name = device.name().unwrap_or_else(|_| "Unknown".to_string());
+            let marker = if Some(&name) == default_name.as_ref() { " (default)" } else { "" };
+            let config = match device.default_output_config() {
+                Ok(config) => format!(
+                    "{} channels, {} Hz, {}",
+                    config.channels(),
+                    config.sample_rate().0,
+                    config.sample_format()
+                ),
+                Err(err) => err.to_string(),
+            };
+            println!("    {}{}: {}", name, marker, config);
+        }
+    }
+    println!("MIDI inputs:");
+    match midi::input_ports() {
+        Ok(ports) if ports.is_empty() => println!("  (none)"),
+        Ok(ports) => {
+            for (index, port) in ports.iter().enumerate() {
+                let marker = if index == 0 { " (used)" } else { "" };
+                println!("  {}{}", port, marker);
+            }
+        }
+        // Audio devices are still worth listing without MIDI
+        Err(message) => println!("  {}", message),
+    }
+    Ok(())
+}
@@ -0,0 +1,393 @@
+use crate::{EffectAddress, ProcessContext, SmoothedParam};
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+// Partition length in samples, which is also the added latency
+const BLOCK_SIZE: usize = 256;
+
+// Impulse response as read from disk, one buffer per channel
+pub struct ImpulseResponse {
+    pub channels: Vec<Vec<f32>>,
+    pub sample_rate: u32,
+}
+
+impl ImpulseResponse {
+    pub fn load(path: &str) -> Result<Self, hound::Error> {
+        let mut reader = hound::WavReader::open(path)?;
+        let spec = reader.spec();
+        let samples: Vec<f32> = match spec.sample_format {
+            hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+            hound::SampleFormat::Int => {
+                let scale = 1.0 / (1i64 << (spec.bits_per_sample - 1)) as f32;
+                reader
+                    .samples::<i32>()
+                    .map(|sample| sample.map(|value| value as f32 * scale))
+                    .collect::<Result<_, _>>()?
+            }
+        };
+
+        // Wider files are folded down to two channels, even channels into the
+        // left and odd ones into the right, averaging what lands on each side
+        let channel_count = spec.channels as usize;
+        let outputs = channel_count.min(2);
+        let mut channels: Vec<Vec<f32>> = vec![Vec::with_capacity(samples.len() / channel_count); outputs];
+        for frame in samples.chunks_exact(channel_count) {
+            for (output, channel) in channels.iter_mut().enumerate() {
+                let (sum, count) = frame
+                    .iter()
+                    .skip(output)
+                    .step_by(outputs)
+                    .fold((0.0, 0), |(sum, count), sample| (sum + sample, count + 1));
+                channel.push(sum / count as f32);
+            }
+        }
+
+        Ok(Self {
+            channels,
+            sample_rate: spec.sample_rate,
+        })
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.channels.first().map_or(0, Vec::len) as f32 / self.sample_rate as f32
+    }
+}
+
+// How the loaded response is cut and scaled before it is partitioned
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct ImpulseSettings {
+    // Seconds skipped at the start of the response
+    pub trim_start: f32,
+    // Seconds kept after the start; zero keeps the whole response
+    pub length: f32,
+    // Time stretch factor, above 1.0 makes the space larger
+    pub stretch: f32,
+}
+
+// Trims, stretches and resamples one channel to the engine rate, then
+// normalizes it to unit energy so different responses play at similar levels
+fn prepare_channel(
+    samples: &[f32],
+    source_rate: u32,
+    sample_rate: f32,
+    settings: &ImpulseSettings,
+) -> Vec<f32> {
+    let source_rate = source_rate as f32;
+    let start = ((settings.trim_start * source_rate) as usize).min(samples.len());
+    let end = if settings.length > 0.0 {
+        (start + (settings.length * source_rate) as usize).min(samples.len())
+    } else {
+        samples.len()
+    };
+    let trimmed = &samples[start..end];
+
+    let ratio = sample_rate / source_rate * settings.stretch;
+    let length = (trimmed.len() as f32 * ratio) as usize;
+    let mut output: Vec<f32> = (0..length)
+        .map(|i| {
+            let position = i as f32 / ratio;
+            let index = position as usize;
+            let fraction = position - index as f32;
+            let current = trimmed.get(index).copied().unwrap_or(0.0);
+            let next = trimmed.get(index + 1).copied().unwrap_or(0.0);
+            current + (next - current) * fraction
+        })
+        .collect();
+
+    // Fade out the last few milliseconds so a trimmed tail doesn't click
+    let fade = ((0.01 * sample_rate) as usize).min(output.len());
+    let fade_start = output.len() - fade;
+    for (i, sample) in output[fade_start..].iter_mut().enumerate() {
+        *sample *= 1.0 - i as f32 / fade as f32;
+    }
+
+    let energy: f32 = output.iter().map(|sample| sample * sample).sum();
+    if energy > 0.0 {
+        let gain = 1.0 / energy.sqrt();
+        output.iter_mut().for_each(|sample| *sample *= gain);
+    }
+    output
+}
+
+// Uniformly partitioned overlap-save convolution of one channel
+#[derive(Clone)]
+struct PartitionedConvolver {
+    fft: Arc<dyn Fft<f32>>,
+    ifft: Arc<dyn Fft<f32>>,
+    // Spectra of the response partitions
+    partitions: Vec<Vec<Complex<f32>>>,
+    // Spectra of the most recent input blocks, newest at `history_position`
+    history: Vec<Vec<Complex<f32>>>,
+    history_position: usize,
+    // Previous and current input block
+    input: Vec<f32>,
+    output: Vec<f32>,
+    fill: usize,
+    buffer: Vec<Complex<f32>>,
+    accumulator: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+}
+
+impl PartitionedConvolver {
+    fn new(response: &[f32], planner: &mut FftPlanner<f32>) -> Self {
+        let fft_size = BLOCK_SIZE * 2;
+        let fft = planner.plan_fft_forward(fft_size);
+        let ifft = planner.plan_fft_inverse(fft_size);
+        let scratch_size = fft
+            .get_inplace_scratch_len()
+            .max(ifft.get_inplace_scratch_len());
+        let mut scratch = vec![Complex::default(); scratch_size];
+
+        let partitions: Vec<Vec<Complex<f32>>> = response
+            .chunks(BLOCK_SIZE)
+            .map(|chunk| {
+                let mut spectrum = vec![Complex::default(); fft_size];
+                for (bin, &sample) in spectrum.iter_mut().zip(chunk) {
+                    bin.re = sample;
+                }
+                fft.process_with_scratch(&mut spectrum, &mut scratch);
+                spectrum
+            })
+            .collect();
+        let partition_count = partitions.len().max(1);
+
+        Self {
+            fft,
+            ifft,
+            partitions,
+            history: vec![vec![Complex::default(); fft_size]; partition_count],
+            history_position: 0,
+            input: vec![0.0; fft_size],
+            output: vec![0.0; BLOCK_SIZE],
+            fill: 0,
+            buffer: vec![Complex::default(); fft_size],
+            accumulator: vec![Complex::default(); fft_size],
+            scratch,
+        }
+    }
+
+    fn process(&mut self, sample: f32) -> f32 {
+        self.input[BLOCK_SIZE + self.fill] = sample;
+        let output = self.output[self.fill];
+        self.fill += 1;
+        if self.fill == BLOCK_SIZE {
+            self.process_block();
+            self.fill = 0;
+        }
+        output
+    }
+
+    fn process_block(&mut self) {
+        for (bin, &sample) in self.buffer.iter_mut().zip(&self.input) {
+            *bin = Complex::new(sample, 0.0);
+        }
+        self.fft.process_with_scratch(&mut self.buffer, &mut self.scratch);
+
+        let count = self.history.len();
+        self.history_position = (self.history_position + 1) % count;
+        self.history[self.history_position].copy_from_slice(&self.buffer);
+
+        // Each partition meets the input block it is delayed by
+        self.accumulator.fill(Complex::default());
+        for (k, partition) in self.partitions.iter().enumerate() {
+            let spectrum = &self.history[(self.history_position + count - k) % count];
+            for ((sum, a), b) in self.accumulator.iter_mut().zip(spectrum).zip(partition) {
+                *sum += a * b;
+            }
+        }
+        self.ifft.process_with_scratch(&mut self.accumulator, &mut self.scratch);
+
+        // Overlap-save keeps only the second half of the circular result
+        let scale = 1.0 / (BLOCK_SIZE * 2) as f32;
+        for (output, bin) in self.output.iter_mut().zip(&self.accumulator[BLOCK_SIZE..]) {
+            *output = bin.re * scale;
+        }
+        self.input.copy_within(BLOCK_SIZE.., 0);
+    }
+
+    fn reset(&mut self) {
+        for spectrum in self.history.iter_mut() {
+            spectrum.fill(Complex::default());
+        }
+        self.input.fill(0.0);
+        self.output.fill(0.0);
+        self.fill = 0;
+    }
+}
+
+#[derive(Clone)]
+pub struct ConvolutionEngine {
+    convolvers: [PartitionedConvolver; 2],
+    pub length: f32,
+    pub stereo: bool,
+}
+
+impl ConvolutionEngine {
+    // Mono responses are used for both channels, stereo ones per channel
+    pub fn new(impulse: &ImpulseResponse, sample_rate: f32, settings: &ImpulseSettings) -> Self {
+        let mut planner = FftPlanner::new();
+        let prepared: Vec<Vec<f32>> = impulse
+            .channels
+            .iter()
+            .map(|samples| prepare_channel(samples, impulse.sample_rate, sample_rate, settings))
+            .collect();
+        let left = prepared.first().map_or(&[][..], Vec::as_slice);
+        let right = prepared.get(1).map_or(left, Vec::as_slice);
+
+        Self {
+            convolvers: [
+                PartitionedConvolver::new(left, &mut planner),
+                PartitionedConvolver::new(right, &mut planner),
+            ],
+            length: left.len() as f32 / sample_rate,
+            stereo: prepared.len() > 1,
+        }
+    }
+}
+
+// Only the file path and settings are saved; the response is read again on load
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ConvolutionParameters {
+    pub path: String,
+    #[serde(skip)]
+    pub impulse: Option<Arc<ImpulseResponse>>,
+    pub settings: ImpulseSettings,
+    #[serde(skip)]
+    pub engine: Option<Box<ConvolutionEngine>>,
+    #[serde(skip)]
+    pub status: String,
+    pub level: SmoothedParam,
+    pub mix: SmoothedParam,
+}
+
+impl ConvolutionParameters {
+    pub fn new(mix: f32) -> Self {
+        Self {
+            path: String::new(),
+            impulse: None,
+            settings: ImpulseSettings {
+                trim_start: 0.0,
+                length: 0.0,
+                stretch: 1.0,
+            },
+            engine: None,
+            status: "No impulse response loaded".to_string(),
+            level: SmoothedParam::new(1.0),
+            mix: SmoothedParam::new(mix),
+        }
+    }
+
+    pub fn process(&mut self, input: [f32; 2], context: &ProcessContext) -> [f32; 2] {
+        let level = self.level.next(context.smoothing);
+        let mix = self.mix.next(context.smoothing);
+        let Some(engine) = &mut self.engine else {
+            return input;
+        };
+        let mut output = [0.0; 2];
+        for channel in 0..2 {
+            let wet = engine.convolvers[channel].process(input[channel]) * level;
+            output[channel] = input[channel] * (1.0 - mix) + wet * mix;
+        }
+        output
+    }
+
+    pub fn reset(&mut self) {
+        if let Some(engine) = &mut self.engine {
+            for convolver in engine.convolvers.iter_mut() {
+                convolver.reset();
+            }
+        }
+    }
+
+    pub fn prepare(&mut self, sample_rate: f32) {
+        if self.path.is_empty() {
+            self.status = "No impulse response loaded".to_string();
+            return;
+        }
+        match build_engine(&self.path, None, &self.settings, sample_rate) {
+            Ok((impulse, engine)) => drop(self.install(impulse, engine)),
+            Err(message) => self.status = message,
+        }
+    }
+
+    // Swaps in a new response and engine, handing back the old ones so they
+    // can be freed outside the audio lock
+    pub fn install(&mut self, impulse: Arc<ImpulseResponse>, engine: Box<ConvolutionEngine>) -> Replaced {
+        self.status = format!(
+            "{} {:.2}s at {} Hz, using {:.2}s",
+            if engine.stereo { "Stereo" } else { "Mono" },
+            impulse.duration(),
+            impulse.sample_rate,
+            engine.length,
+        );
+        (self.impulse.replace(impulse), self.engine.replace(engine))
+    }
+}
+
+// The response and engine an install took the place of
+pub type Replaced = (Option<Arc<ImpulseResponse>>, Option<Box<ConvolutionEngine>>);
+
+// Work queued by the UI and done without holding the audio lock
+pub struct ImpulseRequest {
+    pub address: EffectAddress,
+    pub path: String,
+    // Reuse an already loaded response instead of reading the file again
+    pub impulse: Option<Arc<ImpulseResponse>>,
+    pub settings: ImpulseSettings,
+    pub sample_rate: f32,
+}
+
+impl ImpulseRequest {
+    // Reads and partitions the response on its own thread, so a long file
+    // doesn't hold up the UI
+    pub fn spawn(self) -> ImpulseLoad {
+        ImpulseLoad {
+            address: self.address.clone(),
+            thread: std::thread::spawn(move || {
+                build_engine(&self.path, self.impulse, &self.settings, self.sample_rate)
+            }),
+        }
+    }
+}
+
+type LoadResult = Result<(Arc<ImpulseResponse>, Box<ConvolutionEngine>), String>;
+
+// A response being built in the background for the effect at `address`
+pub struct ImpulseLoad {
+    pub address: EffectAddress,
+    thread: JoinHandle<LoadResult>,
+}
+
+impl ImpulseLoad {
+    pub fn finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    pub fn join(self) -> LoadResult {
+        self.thread
+            .join()
+            .unwrap_or_else(|_| Err("Impulse loading thread panicked".to_string()))
+    }
+}
+
+fn build_engine(
+    path: &str,
+    impulse: Option<Arc<ImpulseResponse>>,
+    settings: &ImpulseSettings,
+    sample_rate: f32,
+) -> LoadResult {
+    let impulse = match impulse {
+        Some(impulse) => impulse,
+        None => Arc::new(
+            ImpulseResponse::load(path).map_err(|err| format!("Failed to load {}: {}", path, err))?,
+        ),
+    };
+    if impulse.channels.is_empty() {
+        return Err(format!("{} contains no audio", path));
+    }
+    let engine = Box::new(ConvolutionEngine::new(&impulse, sample_rate, settings));
+    Ok((impulse, engine))
+}
@@ -0,0 +1,405 @@
+use crate::{one_pole_coefficient, Biquad, BiquadKind, DelayLine, ProcessContext, SmoothedParam};
+use serde::{Deserialize, Serialize};
+use std::f32::consts::{FRAC_1_SQRT_2, PI};
+
+// Taps of each half-band filter in the oversampling chain. One more than a
+// multiple of 16, so the up and down filters of every stage together delay
+// by whole base-rate samples.
+const HALFBAND_TAPS: usize = 49;
+const MAX_OVERSAMPLING_STAGES: usize = 3;
+const MAX_OVERSAMPLING: usize = 1 << MAX_OVERSAMPLING_STAGES;
+// Points in a user-drawn transfer curve, spread evenly over -1..1
+pub const CUSTOM_CURVE_POINTS: usize = 33;
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ShaperCurve {
+    SoftClip,
+    HardClip,
+    Foldback,
+    Tube,
+    Custom,
+}
+
+impl ShaperCurve {
+    pub const ALL: [ShaperCurve; 5] = [
+        ShaperCurve::SoftClip,
+        ShaperCurve::HardClip,
+        ShaperCurve::Foldback,
+        ShaperCurve::Tube,
+        ShaperCurve::Custom,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            ShaperCurve::SoftClip => "Soft Clip",
+            ShaperCurve::HardClip => "Hard Clip",
+            ShaperCurve::Foldback => "Foldback",
+            ShaperCurve::Tube => "Tube",
+            ShaperCurve::Custom => "Custom",
+        }
+    }
+}
+
+// Maps an already driven sample through the transfer curve
+pub fn shape(curve: ShaperCurve, sample: f32, custom: &[f32]) -> f32 {
+    match curve {
+        ShaperCurve::SoftClip => sample.tanh(),
+        ShaperCurve::HardClip => sample.clamp(-1.0, 1.0),
+        // Reflects the signal back each time it crosses ±1
+        ShaperCurve::Foldback => {
+            let phase = (sample + 1.0).rem_euclid(4.0);
+            if phase < 2.0 {
+                phase - 1.0
+            } else {
+                3.0 - phase
+            }
+        }
+        // A biased tanh clips the two halves differently, adding even harmonics
+        ShaperCurve::Tube => {
+            const BIAS: f32 = 0.3;
+            (sample + BIAS).tanh() - BIAS.tanh()
+        }
+        ShaperCurve::Custom => {
+            if custom.len() < 2 {
+                return sample.clamp(-1.0, 1.0);
+            }
+            let position = (sample.clamp(-1.0, 1.0) + 1.0) * 0.5 * (custom.len() - 1) as f32;
+            let index = (position as usize).min(custom.len() - 2);
+            let fraction = position - index as f32;
+            custom[index] + (custom[index + 1] - custom[index]) * fraction
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Oversampling {
+    Off,
+    X2,
+    X4,
+    X8,
+}
+
+impl Oversampling {
+    pub const ALL: [Oversampling; 4] = [
+        Oversampling::Off,
+        Oversampling::X2,
+        Oversampling::X4,
+        Oversampling::X8,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Oversampling::Off => "Off",
+            Oversampling::X2 => "2×",
+            Oversampling::X4 => "4×",
+            Oversampling::X8 => "8×",
+        }
+    }
+
+    fn stages(self) -> usize {
+        match self {
+            Oversampling::Off => 0,
+            Oversampling::X2 => 1,
+            Oversampling::X4 => 2,
+            Oversampling::X8 => 3,
+        }
+    }
+
+    // Delay added by the filters, in samples at the base rate
+    fn latency(self) -> f32 {
+        let delay = (HALFBAND_TAPS - 1) as f32;
+        (1..=self.stages()).map(|stage| delay / (1 << stage) as f32).sum()
+    }
+}
+
+// Blackman-windowed sinc with its cutoff at a quarter of the sample rate.
+// Every other tap is zero, so only the rest are kept with their offsets.
+fn halfband_coefficients() -> Vec<(usize, f32)> {
+    let middle = (HALFBAND_TAPS - 1) as f32 / 2.0;
+    let mut coefficients: Vec<f32> = (0..HALFBAND_TAPS)
+        .map(|n| {
+            let offset = n as f32 - middle;
+            let sinc = if offset == 0.0 {
+                1.0
+            } else if offset % 2.0 == 0.0 {
+                0.0
+            } else {
+                (PI * offset * 0.5).sin() / (PI * offset * 0.5)
+            };
+            let phase = 2.0 * PI * n as f32 / (HALFBAND_TAPS - 1) as f32;
+            let window = 0.42 - 0.5 * phase.cos() + 0.08 * (2.0 * phase).cos();
+            sinc * window
+        })
+        .collect();
+    let sum: f32 = coefficients.iter().sum();
+    coefficients.iter_mut().for_each(|coefficient| *coefficient /= sum);
+    coefficients
+        .into_iter()
+        .enumerate()
+        .filter(|(_, coefficient)| *coefficient != 0.0)
+        .collect()
+}
+
+#[derive(Clone, Default)]
+struct Fir {
+    history: Vec<f32>,
+    position: usize,
+}
+
+impl Fir {
+    fn push(&mut self, sample: f32) {
+        self.position = (self.position + 1) % self.history.len();
+        self.history[self.position] = sample;
+    }
+
+    fn output(&self, coefficients: &[(usize, f32)]) -> f32 {
+        let length = self.history.len();
+        coefficients
+            .iter()
+            .map(|(tap, coefficient)| coefficient * self.history[(self.position + length - tap) % length])
+            .sum()
+    }
+}
+
+// One 2× step: interpolating filter on the way up, decimating filter on the way down
+#[derive(Clone, Default)]
+struct HalfbandStage {
+    up: Fir,
+    down: Fir,
+}
+
+#[derive(Clone, Default)]
+struct Oversampler {
+    coefficients: Vec<(usize, f32)>,
+    stages: Vec<HalfbandStage>,
+}
+
+impl Oversampler {
+    fn new() -> Self {
+        let stage = HalfbandStage {
+            up: Fir {
+                history: vec![0.0; HALFBAND_TAPS],
+                position: 0,
+            },
+            down: Fir {
+                history: vec![0.0; HALFBAND_TAPS],
+                position: 0,
+            },
+        };
+        Self {
+            coefficients: halfband_coefficients(),
+            stages: vec![stage; MAX_OVERSAMPLING_STAGES],
+        }
+    }
+
+    // Runs `shaper` at the oversampled rate and returns one base-rate sample
+    fn process(&mut self, sample: f32, stage_count: usize, mut shaper: impl FnMut(f32) -> f32) -> f32 {
+        if stage_count == 0 || self.stages.is_empty() {
+            return shaper(sample);
+        }
+        let coefficients = &self.coefficients;
+        let mut buffer = [0.0; MAX_OVERSAMPLING];
+        let mut scratch = [0.0; MAX_OVERSAMPLING];
+        buffer[0] = sample;
+        let mut length = 1;
+
+        for stage in self.stages[..stage_count].iter_mut() {
+            for i in 0..length {
+                // Zero stuffing halves the level, so the inserted sample is doubled
+                stage.up.push(buffer[i] * 2.0);
+                scratch[2 * i] = stage.up.output(coefficients);
+                stage.up.push(0.0);
+                scratch[2 * i + 1] = stage.up.output(coefficients);
+            }
+            length *= 2;
+            buffer[..length].copy_from_slice(&scratch[..length]);
+        }
+
+        buffer[..length].iter_mut().for_each(|sample| *sample = shaper(*sample));
+
+        for stage in self.stages[..stage_count].iter_mut().rev() {
+            length /= 2;
+            for i in 0..length {
+                stage.down.push(buffer[2 * i]);
+                scratch[i] = stage.down.output(coefficients);
+                stage.down.push(buffer[2 * i + 1]);
+            }
+            buffer[..length].copy_from_slice(&scratch[..length]);
+        }
+        buffer[0]
+    }
+
+    fn reset(&mut self) {
+        for stage in self.stages.iter_mut() {
+            stage.up.history.fill(0.0);
+            stage.down.history.fill(0.0);
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DistortionParameters {
+    pub curve: ShaperCurve,
+    pub custom_curve: Vec<f32>,
+    pub oversampling: Oversampling,
+    pub drive: SmoothedParam,
+    // High-pass before the shaper, tightens the low end
+    pub pre_cut: SmoothedParam,
+    // Low-pass after the shaper, tames the fizz
+    pub post_cut: SmoothedParam,
+    pub mix: SmoothedParam,
+    #[serde(skip)]
+    oversamplers: [Oversampler; 2],
+    // Oversampling the filters are currently primed for
+    #[serde(skip)]
+    active_oversampling: Option<Oversampling>,
+    // Delays the dry signal by the oversampling latency so the mix stays aligned
+    #[serde(skip)]
+    dry_lines: [DelayLine; 2],
+    #[serde(skip)]
+    tone_filters: [[Biquad; 2]; 2],
+    #[serde(skip)]
+    tone_tuning: Option<(f32, f32)>,
+    #[serde(skip)]
+    dc_blockers: [[f32; 2]; 2],
+}
+
+impl DistortionParameters {
+    pub fn new(drive: f32, mix: f32) -> Self {
+        Self {
+            curve: ShaperCurve::SoftClip,
+            custom_curve: (0..CUSTOM_CURVE_POINTS)
+                .map(|i| i as f32 / (CUSTOM_CURVE_POINTS - 1) as f32 * 2.0 - 1.0)
+                .collect(),
+            oversampling: Oversampling::X4,
+            drive: SmoothedParam::new(drive),
+            pre_cut: SmoothedParam::new(20.0),
+            post_cut: SmoothedParam::new(20000.0),
+            mix: SmoothedParam::new(mix),
+            oversamplers: Default::default(),
+            active_oversampling: None,
+            dry_lines: Default::default(),
+            tone_filters: Default::default(),
+            tone_tuning: None,
+            dc_blockers: [[0.0; 2]; 2],
+        }
+    }
+
+    pub fn process(&mut self, input: [f32; 2], context: &ProcessContext) -> [f32; 2] {
+        let drive = self.drive.next(context.smoothing);
+        let pre_cut = self.pre_cut.next(context.smoothing);
+        let post_cut = self.post_cut.next(context.smoothing);
+        let mix = self.mix.next(context.smoothing);
+
+        if self.active_oversampling != Some(self.oversampling) {
+            self.active_oversampling = Some(self.oversampling);
+            for oversampler in self.oversamplers.iter_mut() {
+                oversampler.reset();
+            }
+        }
+        if self.tone_tuning != Some((pre_cut, post_cut)) {
+            self.tone_tuning = Some((pre_cut, post_cut));
+            for [pre, post] in self.tone_filters.iter_mut() {
+                pre.set(BiquadKind::HighPass, pre_cut, FRAC_1_SQRT_2, 0.0, context.sample_rate);
+                post.set(BiquadKind::LowPass, post_cut, FRAC_1_SQRT_2, 0.0, context.sample_rate);
+            }
+        }
+
+        let stages = self.oversampling.stages();
+        let latency = self.oversampling.latency();
+        let dc_coefficient = one_pole_coefficient(10.0, context.sample_rate);
+        let curve = self.curve;
+        let custom = &self.custom_curve;
+        let mut output = [0.0; 2];
+        for channel in 0..2 {
+            let [pre, post] = &mut self.tone_filters[channel];
+            let filtered = pre.process(input[channel]);
+            let shaped = self.oversamplers[channel]
+                .process(filtered, stages, |sample| shape(curve, sample * drive, custom));
+
+            // Asymmetric curves leave an offset behind
+            let [previous_input, previous_output] = &mut self.dc_blockers[channel];
+            let blocked = shaped - *previous_input + (1.0 - dc_coefficient) * *previous_output;
+            *previous_input = shaped;
+            *previous_output = blocked;
+            let wet = post.process(blocked);
+
+            let line = &mut self.dry_lines[channel];
+            line.write(input[channel]);
+            // A read delay of 1.0 is the sample just written
+            let dry = if stages == 0 { input[channel] } else { line.read(latency + 1.0) };
+            output[channel] = dry * (1.0 - mix) + wet * mix;
+        }
+        output
+    }
+
+    pub fn reset(&mut self) {
+        for oversampler in self.oversamplers.iter_mut() {
+            oversampler.reset();
+        }
+        for line in self.dry_lines.iter_mut() {
+            line.clear();
+        }
+        for filter in self.tone_filters.iter_mut().flatten() {
+            filter.reset();
+        }
+        self.dc_blockers = [[0.0; 2]; 2];
+    }
+
+    pub fn prepare(&mut self) {
+        self.oversamplers = [Oversampler::new(), Oversampler::new()];
+        let length = Oversampling::X8.latency() as usize + 5;
+        self.dry_lines = [DelayLine::new(length), DelayLine::new(length)];
+        self.active_oversampling = None;
+        self.tone_tuning = None;
+    }
+}
+
+// Reduces bit depth and sample rate for lo-fi aliasing and quantization noise
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BitCrusherParameters {
+    // Fractional depths blend smoothly between step sizes
+    pub bits: SmoothedParam,
+    // Hz the signal is resampled to by sample-and-hold
+    pub rate: SmoothedParam,
+    pub mix: SmoothedParam,
+    #[serde(skip)]
+    phase: f32,
+    #[serde(skip)]
+    held: [f32; 2],
+}
+
+impl BitCrusherParameters {
+    pub fn new(bits: f32, rate: f32, mix: f32) -> Self {
+        Self {
+            bits: SmoothedParam::new(bits),
+            rate: SmoothedParam::new(rate),
+            mix: SmoothedParam::new(mix),
+            phase: 0.0,
+            held: [0.0; 2],
+        }
+    }
+
+    pub fn process(&mut self, input: [f32; 2], context: &ProcessContext) -> [f32; 2] {
+        let bits = self.bits.next(context.smoothing);
+        let rate = self.rate.next(context.smoothing);
+        let mix = self.mix.next(context.smoothing);
+
+        self.phase += rate / context.sample_rate;
+        if self.phase >= 1.0 {
+            self.phase -= self.phase.floor();
+            let steps = 2.0f32.powf(bits - 1.0);
+            self.held = input.map(|sample| (sample * steps).round() / steps);
+        }
+
+        [
+            input[0] * (1.0 - mix) + self.held[0] * mix,
+            input[1] * (1.0 - mix) + self.held[1] * mix,
+        ]
+    }
+
+    pub fn reset(&mut self) {
+        self.phase = 0.0;
+        self.held = [0.0; 2];
+    }
+}
@@ -0,0 +1,239 @@
+use crate::{smoothing_coefficient, ProcessContext, SmoothedParam};
+use serde::{Deserialize, Serialize};
+
+// Detector levels are floored here so silence doesn't read as -inf
+const SILENCE_DB: f32 = -120.0;
+// Largest gain change the transient shaper applies either way
+const MAX_SHAPER_GAIN: f32 = 24.0;
+
+fn to_db(level: f32) -> f32 {
+    if level > 0.0 {
+        (20.0 * level.log10()).max(SILENCE_DB)
+    } else {
+        SILENCE_DB
+    }
+}
+
+fn from_db(db: f32) -> f32 {
+    10.0f32.powf(db / 20.0)
+}
+
+// Peak of both channels, taken from the effect input or from the dry voice sum
+fn detector_input(input: [f32; 2], context: &ProcessContext, sidechain: bool) -> f32 {
+    let key = if sidechain { context.sidechain } else { input };
+    key[0].abs().max(key[1].abs())
+}
+
+// One-pole follower with separate rise and fall coefficients
+#[derive(Clone, Copy, Default)]
+struct Ballistics {
+    value: f32,
+}
+
+impl Ballistics {
+    fn process(&mut self, input: f32, rise: f32, fall: f32) -> f32 {
+        let coefficient = if input > self.value { rise } else { fall };
+        self.value += (input - self.value) * coefficient;
+        self.value
+    }
+}
+
+// Gain change in dB for a level `over` dB above the threshold, with the
+// knee blended quadratically across `knee` dB around it
+fn compression_curve(over: f32, ratio: f32, knee: f32) -> f32 {
+    let slope = 1.0 / ratio - 1.0;
+    if knee > 0.0 && over.abs() <= knee / 2.0 {
+        slope * (over + knee / 2.0).powi(2) / (2.0 * knee)
+    } else if over > 0.0 {
+        slope * over
+    } else {
+        0.0
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CompressorParameters {
+    // dB
+    pub threshold: SmoothedParam,
+    pub ratio: SmoothedParam,
+    // Width of the soft knee in dB
+    pub knee: f32,
+    // Seconds
+    pub attack: f32,
+    pub release: f32,
+    // dB
+    pub makeup: SmoothedParam,
+    // Detect on the dry voice sum instead of the effect input
+    pub sidechain: bool,
+    #[serde(skip)]
+    gain: Ballistics,
+    // Current reduction in dB, read by the meter
+    #[serde(skip)]
+    pub gain_reduction: f32,
+}
+
+impl CompressorParameters {
+    pub fn new(threshold: f32, ratio: f32) -> Self {
+        Self {
+            threshold: SmoothedParam::new(threshold),
+            ratio: SmoothedParam::new(ratio),
+            knee: 6.0,
+            attack: 0.01,
+            release: 0.15,
+            makeup: SmoothedParam::new(0.0),
+            sidechain: false,
+            gain: Ballistics::default(),
+            gain_reduction: 0.0,
+        }
+    }
+
+    pub fn process(&mut self, input: [f32; 2], context: &ProcessContext) -> [f32; 2] {
+        let threshold = self.threshold.next(context.smoothing);
+        let ratio = self.ratio.next(context.smoothing).max(1.0);
+        let makeup = self.makeup.next(context.smoothing);
+        let level = to_db(detector_input(input, context, self.sidechain));
+        let target = compression_curve(level - threshold, ratio, self.knee);
+
+        // Reduction deepens at the attack rate and recovers at the release rate
+        let attack = smoothing_coefficient(self.attack, context.sample_rate);
+        let release = smoothing_coefficient(self.release, context.sample_rate);
+        let gain = self.gain.process(target, release, attack);
+        self.gain_reduction = -gain;
+
+        let gain = from_db(gain + makeup);
+        input.map(|sample| sample * gain)
+    }
+
+    pub fn reset(&mut self) {
+        self.gain = Ballistics::default();
+        self.gain_reduction = 0.0;
+    }
+}
+
+// Downward expander; a high ratio with a deep range makes it a noise gate
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ExpanderParameters {
+    // dB
+    pub threshold: SmoothedParam,
+    pub ratio: SmoothedParam,
+    // Deepest attenuation in dB
+    pub range: SmoothedParam,
+    // Seconds
+    pub attack: f32,
+    pub hold: f32,
+    pub release: f32,
+    pub sidechain: bool,
+    #[serde(skip)]
+    gain: Ballistics,
+    // Samples left before the release may start
+    #[serde(skip)]
+    hold_remaining: f32,
+    #[serde(skip)]
+    pub gain_reduction: f32,
+}
+
+impl ExpanderParameters {
+    pub fn new(threshold: f32, ratio: f32, range: f32) -> Self {
+        Self {
+            threshold: SmoothedParam::new(threshold),
+            ratio: SmoothedParam::new(ratio),
+            range: SmoothedParam::new(range),
+            attack: 0.001,
+            hold: 0.05,
+            release: 0.1,
+            sidechain: false,
+            gain: Ballistics::default(),
+            hold_remaining: 0.0,
+            gain_reduction: 0.0,
+        }
+    }
+
+    pub fn process(&mut self, input: [f32; 2], context: &ProcessContext) -> [f32; 2] {
+        let threshold = self.threshold.next(context.smoothing);
+        let ratio = self.ratio.next(context.smoothing).max(1.0);
+        let range = self.range.next(context.smoothing);
+        let level = to_db(detector_input(input, context, self.sidechain));
+
+        let target = if level >= threshold {
+            self.hold_remaining = self.hold * context.sample_rate;
+            0.0
+        } else if self.hold_remaining > 0.0 {
+            self.hold_remaining -= 1.0;
+            0.0
+        } else {
+            ((ratio - 1.0) * (level - threshold)).max(-range)
+        };
+
+        // Opening follows the attack time, closing the release time
+        let attack = smoothing_coefficient(self.attack, context.sample_rate);
+        let release = smoothing_coefficient(self.release, context.sample_rate);
+        let gain = self.gain.process(target, attack, release);
+        self.gain_reduction = -gain;
+
+        let gain = from_db(gain);
+        input.map(|sample| sample * gain)
+    }
+
+    pub fn reset(&mut self) {
+        self.gain = Ballistics::default();
+        self.hold_remaining = 0.0;
+        self.gain_reduction = 0.0;
+    }
+}
+
+// Boosts or cuts attacks and tails by comparing envelope followers with
+// different timing, independent of the absolute level
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TransientShaperParameters {
+    // -1 softens attacks, 1 emphasizes them
+    pub attack: SmoothedParam,
+    // -1 shortens tails, 1 lengthens them
+    pub sustain: SmoothedParam,
+    // dB
+    pub output: SmoothedParam,
+    // Fast and slow attack followers, then fast and slow release followers
+    #[serde(skip)]
+    envelopes: [Ballistics; 4],
+    // Current gain change in dB, positive for a boost
+    #[serde(skip)]
+    pub gain_change: f32,
+}
+
+impl TransientShaperParameters {
+    pub fn new(attack: f32, sustain: f32) -> Self {
+        Self {
+            attack: SmoothedParam::new(attack),
+            sustain: SmoothedParam::new(sustain),
+            output: SmoothedParam::new(0.0),
+            envelopes: Default::default(),
+            gain_change: 0.0,
+        }
+    }
+
+    pub fn process(&mut self, input: [f32; 2], context: &ProcessContext) -> [f32; 2] {
+        let attack = self.attack.next(context.smoothing);
+        let sustain = self.sustain.next(context.smoothing);
+        let output = self.output.next(context.smoothing);
+        let level = input[0].abs().max(input[1].abs());
+
+        let coefficient = |time| smoothing_coefficient(time, context.sample_rate);
+        let [fast_attack, slow_attack, fast_release, slow_release] = &mut self.envelopes;
+        let fast_attack = to_db(fast_attack.process(level, coefficient(0.001), coefficient(0.1)));
+        let slow_attack = to_db(slow_attack.process(level, coefficient(0.025), coefficient(0.1)));
+        let fast_release = to_db(fast_release.process(level, coefficient(0.001), coefficient(0.05)));
+        let slow_release = to_db(slow_release.process(level, coefficient(0.001), coefficient(0.5)));
+
+        // The fast follower leads during an attack, the slow one lingers in a tail
+        let gain = (attack * (fast_attack - slow_attack) + sustain * (slow_release - fast_release))
+            .clamp(-MAX_SHAPER_GAIN, MAX_SHAPER_GAIN);
+        self.gain_change = gain;
+
+        let gain = from_db(gain + output);
+        input.map(|sample| sample * gain)
+    }
+
+    pub fn reset(&mut self) {
+        self.envelopes = Default::default();
+        self.gain_change = 0.0;
+    }
+}
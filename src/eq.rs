@@ -0,0 +1,196 @@
+use crate::{Biquad, BiquadKind, ProcessContext, SmoothedParam};
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+use serde::{Deserialize, Serialize};
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+// Samples kept for the spectrum display
+pub const ANALYZER_SIZE: usize = 2048;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct EqBand {
+    pub kind: BiquadKind,
+    pub frequency: SmoothedParam,
+    pub q: SmoothedParam,
+    // dB, ignored by the pass filters
+    pub gain: SmoothedParam,
+    pub enabled: bool,
+    #[serde(skip)]
+    filters: [Biquad; 2],
+    // Settings the filters were last tuned to
+    #[serde(skip)]
+    tuning: Option<(BiquadKind, f32, f32, f32)>,
+    // Whether the filters ran on the last sample; a band switched back on
+    // starts from silence rather than what it held when switched off
+    #[serde(skip)]
+    running: bool,
+}
+
+impl EqBand {
+    pub fn new(kind: BiquadKind, frequency: f32, q: f32, gain: f32) -> Self {
+        Self {
+            kind,
+            frequency: SmoothedParam::new(frequency),
+            q: SmoothedParam::new(q),
+            gain: SmoothedParam::new(gain),
+            enabled: true,
+            filters: Default::default(),
+            tuning: None,
+            running: false,
+        }
+    }
+
+    // The response the band is heading to, for drawing the curve
+    pub fn target_filter(&self, sample_rate: f32) -> Biquad {
+        let mut filter = Biquad::default();
+        filter.set(self.kind, self.frequency.target, self.q.target, self.gain.target, sample_rate);
+        filter
+    }
+}
+
+// Rolling window of the output, analysed by the UI on demand
+#[derive(Clone, Default)]
+pub struct Analyzer {
+    samples: Vec<f32>,
+    position: usize,
+}
+
+impl Analyzer {
+    fn push(&mut self, sample: f32) {
+        if self.samples.is_empty() {
+            return;
+        }
+        self.samples[self.position] = sample;
+        self.position = (self.position + 1) % self.samples.len();
+    }
+
+    // Copies the window into a view, oldest sample first. This is all
+    // that happens under the audio lock; the view transforms it later.
+    pub fn copy_to(&self, view: &mut SpectrumView) {
+        view.samples.clear();
+        view.samples.extend_from_slice(&self.samples[self.position..]);
+        view.samples.extend_from_slice(&self.samples[..self.position]);
+        view.fresh = true;
+    }
+}
+
+// An EQ's spectrum as the editor draws it, with the FFT planned once
+pub struct SpectrumView {
+    samples: Vec<f32>,
+    // Magnitudes in dB, where a full-scale sine reads 0 dB
+    pub levels: Vec<f32>,
+    // Whether samples were copied since the last transform
+    fresh: bool,
+    fft: Arc<dyn Fft<f32>>,
+    buffer: Vec<Complex<f32>>,
+}
+
+impl SpectrumView {
+    pub fn new() -> Self {
+        Self {
+            samples: Vec::with_capacity(ANALYZER_SIZE),
+            levels: Vec::new(),
+            fresh: false,
+            fft: FftPlanner::new().plan_fft_forward(ANALYZER_SIZE),
+            buffer: Vec::with_capacity(ANALYZER_SIZE),
+        }
+    }
+
+    // Hann-windowed magnitude spectrum of the copied samples. Returns false
+    // when nothing was copied since last time, so the view can be dropped.
+    pub fn transform(&mut self) -> bool {
+        if !std::mem::take(&mut self.fresh) {
+            return false;
+        }
+        let size = self.samples.len();
+        if size != ANALYZER_SIZE {
+            self.levels.clear();
+            return true;
+        }
+        self.buffer.clear();
+        self.buffer.extend(self.samples.iter().enumerate().map(|(i, sample)| {
+            let window = 0.5 - 0.5 * (2.0 * PI * i as f32 / size as f32).cos();
+            Complex::new(sample * window, 0.0)
+        }));
+        self.fft.process(&mut self.buffer);
+        // The Hann window halves the amplitude and the spectrum is one-sided
+        let scale = 4.0 / size as f32;
+        self.levels.clear();
+        self.levels.extend(
+            self.buffer[..size / 2]
+                .iter()
+                .map(|bin| 20.0 * (bin.norm() * scale).max(1e-6).log10()),
+        );
+        true
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct EqParameters {
+    pub bands: Vec<EqBand>,
+    #[serde(skip)]
+    pub analyzer: Analyzer,
+}
+
+impl EqParameters {
+    pub fn new() -> Self {
+        Self {
+            bands: vec![
+                EqBand::new(BiquadKind::LowShelf, 100.0, 0.7, 0.0),
+                EqBand::new(BiquadKind::Peaking, 500.0, 1.0, 0.0),
+                EqBand::new(BiquadKind::Peaking, 2000.0, 1.0, 0.0),
+                EqBand::new(BiquadKind::HighShelf, 8000.0, 0.7, 0.0),
+            ],
+            analyzer: Analyzer::default(),
+        }
+    }
+
+    pub fn process(&mut self, input: [f32; 2], context: &ProcessContext) -> [f32; 2] {
+        let mut output = input;
+        for band in self.bands.iter_mut() {
+            if !band.enabled {
+                band.running = false;
+                continue;
+            }
+            if !band.running {
+                band.running = true;
+                for filter in band.filters.iter_mut() {
+                    filter.reset();
+                }
+            }
+            let frequency = band.frequency.next(context.smoothing);
+            let q = band.q.next(context.smoothing);
+            let gain = band.gain.next(context.smoothing);
+            // Only retune while a setting is still gliding
+            let tuning = Some((band.kind, frequency, q, gain));
+            if band.tuning != tuning {
+                band.tuning = tuning;
+                for filter in band.filters.iter_mut() {
+                    filter.set(band.kind, frequency, q, gain, context.sample_rate);
+                }
+            }
+            for (sample, filter) in output.iter_mut().zip(band.filters.iter_mut()) {
+                *sample = filter.process(*sample);
+            }
+        }
+        self.analyzer.push((output[0] + output[1]) * 0.5);
+        output
+    }
+
+    pub fn reset(&mut self) {
+        for band in self.bands.iter_mut() {
+            for filter in band.filters.iter_mut() {
+                filter.reset();
+            }
+        }
+        self.analyzer.samples.fill(0.0);
+    }
+
+    pub fn prepare(&mut self) {
+        self.analyzer = Analyzer {
+            samples: vec![0.0; ANALYZER_SIZE],
+            position: 0,
+        };
+    }
+}
@@ -0,0 +1,285 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+pub const KEYBOARD_FILE: &str = "keyboard.toml";
+pub const MAX_OCTAVE_SHIFT: i32 = 4;
+
+// Physical rows of note keys from the bottom up, named by what they print on
+// a US keyboard, with the column of their first key. Each row sits half a
+// key to the left of the one below, so the key up and to the left of a key
+// has its column and the key up and to the right the next one.
+pub const KEY_ROWS: [(&str, i32); 4] = [
+    ("zxcvbnm,./", 0),
+    ("asdfghjkl;'", 0),
+    ("qwertyuiop[]\\", 0),
+    ("`1234567890-=", -1),
+];
+
+// Semitones of the white keys in an octave
+const WHITE_KEYS: [i32; 7] = [0, 2, 4, 5, 7, 9, 11];
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Layout {
+    // A semitone per key along a row and a fourth between rows
+    Fourths,
+    Fifths,
+    // Tracker style: white keys on the letter rows, black keys above them,
+    // an octave higher on the upper pair of rows
+    Piano,
+    // Whole tones along a row, a fourth up-left and a fifth up-right
+    WickiHayden,
+    // Whole tones along a row, a semitone up-right and down up-left
+    Janko,
+    // Edited key by key
+    Custom,
+}
+
+impl Layout {
+    pub const ALL: [Layout; 6] = [
+        Layout::Fourths,
+        Layout::Fifths,
+        Layout::Piano,
+        Layout::WickiHayden,
+        Layout::Janko,
+        Layout::Custom,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Layout::Fourths => "Fourths",
+            Layout::Fifths => "Fifths",
+            Layout::Piano => "Piano (Tracker)",
+            Layout::WickiHayden => "Wicki-Hayden",
+            Layout::Janko => "Janko",
+            Layout::Custom => "Custom",
+        }
+    }
+
+    // Note of the bottom left key
+    fn lowest(self) -> i32 {
+        match self {
+            Layout::Fourths | Layout::Fifths => crate::BASE_NOTE as i32,
+            Layout::Piano => 48,
+            Layout::WickiHayden | Layout::Janko | Layout::Custom => 57,
+        }
+    }
+
+    // Note of a key by its row from the bottom and its column
+    fn note(self, row: usize, column: i32) -> Option<i32> {
+        let row = row as i32;
+        let offset = match self {
+            Layout::Fourths => column + row * 5,
+            Layout::Fifths => column + row * 7,
+            Layout::WickiHayden => column * 2 + row * 5,
+            Layout::Janko => column * 2 - row,
+            Layout::Piano => {
+                let white = |index: i32| {
+                    let octave = index.div_euclid(7);
+                    octave * 12 + WHITE_KEYS[index.rem_euclid(7) as usize]
+                };
+                match row {
+                    0 | 2 if column >= 0 => white(column) + (row / 2) * 12,
+                    // A black key sits between the white keys below-left and
+                    // below-right of it, where they are a whole tone apart
+                    1 | 3 if column >= 1 => {
+                        let below = white(column - 1) + (row / 2) * 12;
+                        let next = white(column) + (row / 2) * 12;
+                        (next - below == 2).then_some(below + 1)?
+                    }
+                    _ => return None,
+                }
+            }
+            Layout::Custom => return None,
+        };
+        Some(self.lowest() + offset)
+    }
+}
+
+// The note keys as saved to disk. Custom notes are stored before the octave
+// shift.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct KeyboardMap {
+    pub layout: Layout,
+    #[serde(default)]
+    pub octave: i32,
+    #[serde(default)]
+    pub custom: BTreeMap<String, u8>,
+}
+
+impl Default for KeyboardMap {
+    fn default() -> Self {
+        Self {
+            layout: Layout::Fourths,
+            octave: 0,
+            custom: BTreeMap::new(),
+        }
+    }
+}
+
+// Every note key with its row from the bottom and column
+fn keys() -> impl Iterator<Item = (char, usize, i32)> {
+    KEY_ROWS.into_iter().enumerate().flat_map(|(row, (keys, first))| {
+        keys.chars()
+            .enumerate()
+            .map(move |(index, key)| (key, row, first + index as i32))
+    })
+}
+
+impl KeyboardMap {
+    // Note a key plays before the octave shift
+    fn base_note(&self, key: char, row: usize, column: i32) -> Option<i32> {
+        match self.layout {
+            Layout::Custom => self.custom.get(&key.to_string()).map(|&note| note as i32),
+            layout => layout.note(row, column),
+        }
+    }
+
+    // Every key that plays a note, with the note
+    pub fn notes(&self) -> Vec<(char, u8)> {
+        keys()
+            .filter_map(|(key, row, column)| {
+                let note = self.base_note(key, row, column)? + self.octave * 12;
+                (0..=127).contains(&note).then_some((key, note as u8))
+            })
+            .collect()
+    }
+
+    pub fn shift_octave(&mut self, octaves: i32) {
+        self.octave = (self.octave + octaves).clamp(-MAX_OCTAVE_SHIFT, MAX_OCTAVE_SHIFT);
+    }
+
+    // Sets one key to play `note`, or nothing, turning a preset into a
+    // custom map that starts from it
+    pub fn set_note(&mut self, key: char, note: Option<u8>) {
+        if self.layout != Layout::Custom {
+            self.custom = keys()
+                .filter_map(|(key, row, column)| {
+                    let note = self.layout.note(row, column)?;
+                    (0..=127).contains(&note).then(|| (key.to_string(), note as u8))
+                })
+                .collect();
+            self.layout = Layout::Custom;
+        }
+        match note {
+            Some(note) => {
+                let base = (note as i32 - self.octave * 12).clamp(0, 127);
+                self.custom.insert(key.to_string(), base as u8)
+            }
+            None => self.custom.remove(&key.to_string()),
+        };
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|err| format!("Failed to read {}: {}", path, err))?;
+        toml::from_str(&text).map_err(|err| format!("Failed to parse {}: {}", path, err))
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let text = toml::to_string_pretty(self).map_err(|err| format!("Failed to encode key map: {}", err))?;
+        std::fs::write(path, text).map_err(|err| format!("Failed to write {}: {}", path, err))
+    }
+}
+
+// The saved key map, or the default one with a note of why
+pub fn load_or_default() -> (KeyboardMap, String) {
+    match KeyboardMap::load(KEYBOARD_FILE) {
+        Ok(map) => (map, format!("Loaded {}", KEYBOARD_FILE)),
+        Err(_) if !std::path::Path::new(KEYBOARD_FILE).exists() => (KeyboardMap::default(), String::new()),
+        Err(message) => (KeyboardMap::default(), message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notes(layout: Layout) -> BTreeMap<char, u8> {
+        KeyboardMap {
+            layout,
+            ..KeyboardMap::default()
+        }
+        .notes()
+        .into_iter()
+        .collect()
+    }
+
+    fn row(notes: &BTreeMap<char, u8>, keys: &str) -> Vec<Option<u8>> {
+        keys.chars().map(|key| notes.get(&key).copied()).collect()
+    }
+
+    #[test]
+    fn piano_layout() {
+        let notes = notes(Layout::Piano);
+        let white = |notes: &[u8]| notes.iter().copied().map(Some).collect::<Vec<_>>();
+        // White keys from C3 on the bottom row and C4 on the top letter row
+        assert_eq!(row(&notes, "zxcvbnm,./"), white(&[48, 50, 52, 53, 55, 57, 59, 60, 62, 64]));
+        assert_eq!(
+            row(&notes, "qwertyuiop[]\\"),
+            white(&[60, 62, 64, 65, 67, 69, 71, 72, 74, 76, 77, 79, 81])
+        );
+        // Black keys only where there is one between the white keys below
+        assert_eq!(
+            row(&notes, "asdfghjkl;'"),
+            [None, Some(49), Some(51), None, Some(54), Some(56), Some(58), None, Some(61), Some(63), None]
+        );
+        assert_eq!(
+            row(&notes, "`1234567890-="),
+            [
+                None,
+                None,
+                Some(61),
+                Some(63),
+                None,
+                Some(66),
+                Some(68),
+                Some(70),
+                None,
+                Some(73),
+                Some(75),
+                None,
+                Some(78)
+            ]
+        );
+    }
+
+    #[test]
+    fn wicki_hayden_layout() {
+        let notes = notes(Layout::WickiHayden);
+        assert_eq!(row(&notes, "zxcv"), [57, 59, 61, 63].map(Some));
+        // A fourth up and to the left, a fifth up and to the right
+        assert_eq!(notes[&'a'], notes[&'z'] + 5);
+        assert_eq!(notes[&'s'], notes[&'z'] + 7);
+        assert_eq!(row(&notes, "aq1"), [62, 67, 72].map(Some));
+        assert_eq!(notes[&'`'], 70);
+        // The same interval shape from every key
+        for (lower, upper) in ["zxcvbnm,./", "asdfghjkl;", "qwertyuiop[]"].windows(2).map(|rows| (rows[0], rows[1])) {
+            for (below, above) in lower.chars().zip(upper.chars()) {
+                assert_eq!(notes[&above], notes[&below] + 5);
+            }
+        }
+    }
+
+    #[test]
+    fn octave_shift_and_custom_keys() {
+        let mut map = KeyboardMap {
+            layout: Layout::Piano,
+            ..KeyboardMap::default()
+        };
+        map.shift_octave(1);
+        assert_eq!(map.notes().iter().find(|(key, _)| *key == 'z'), Some(&('z', 60)));
+        map.shift_octave(10);
+        assert_eq!(map.octave, MAX_OCTAVE_SHIFT);
+
+        // Editing a key keeps the rest of the preset and stores notes
+        // before the shift
+        map.shift_octave(-MAX_OCTAVE_SHIFT - 1);
+        map.set_note('z', Some(40));
+        map.set_note('x', None);
+        assert!(map.layout == Layout::Custom);
+        assert_eq!(map.custom.get("z"), Some(&52));
+        let notes: BTreeMap<char, u8> = map.notes().into_iter().collect();
+        assert_eq!(notes.get(&'z'), Some(&40));
+        assert_eq!(notes.get(&'x'), None);
+        assert_eq!(notes.get(&'c'), Some(&40));
+    }
+}
@@ -0,0 +1,270 @@
+use crate::SmoothedParam;
+
+pub const LOOP_TRACKS: usize = 4;
+pub const MAX_LOOP_BARS: u32 = 64;
+
+type Layer = Vec<[f32; 2]>;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum LoopState {
+    Empty,
+    // Waiting for the transport to reach a bar line; holds the bar length
+    Armed { bar: f64 },
+    // Filling the first layer from `start`, in quarter notes
+    Recording { start: f64 },
+    Playing,
+    // Adding to the newest layer every time round
+    Overdubbing,
+}
+
+// One loop. Its layers are summed on playback, each repeating at its own
+// length, so a multiplied loop replays the shorter layers beneath the new
+// ones and halving only stops the second half being heard.
+pub struct LoopTrack {
+    // Length of the next recording
+    pub bars: u32,
+    pub volume: SmoothedParam,
+    pub muted: bool,
+    state: LoopState,
+    // Loop length in quarter notes, set when recording starts
+    length: f64,
+    // Frames per quarter note at the tempo the loop was recorded at; the
+    // loop plays faster or slower with the transport to stay in sync
+    frames_per_beat: f64,
+    layers: Vec<Layer>,
+    last_position: f64,
+    // Fades between muted and unmuted
+    gain: SmoothedParam,
+}
+
+impl LoopTrack {
+    fn new() -> Self {
+        Self {
+            bars: 2,
+            volume: SmoothedParam::new(1.0),
+            muted: false,
+            state: LoopState::Empty,
+            length: 0.0,
+            frames_per_beat: 0.0,
+            layers: Vec::new(),
+            last_position: 0.0,
+            gain: SmoothedParam::new(1.0),
+        }
+    }
+
+    pub fn state(&self) -> LoopState {
+        self.state
+    }
+
+    pub fn layer_count(&self) -> usize {
+        self.layers.len()
+    }
+
+    // Loop length in quarter notes, zero before anything is recorded
+    pub fn length(&self) -> f64 {
+        self.length
+    }
+
+    fn frames(&self) -> usize {
+        (self.length * self.frames_per_beat).round() as usize
+    }
+
+    // Frames of the next layer `action` records, when it needs a buffer
+    fn buffer_frames(&self, action: LoopAction, bar: f64, tempo: f32, sample_rate: f32) -> Option<usize> {
+        match action {
+            LoopAction::Record => {
+                let length = self.bars.clamp(1, MAX_LOOP_BARS) as f64 * bar;
+                Some((length * sample_rate as f64 * 60.0 / tempo as f64).round() as usize)
+            }
+            LoopAction::Overdub if self.state == LoopState::Playing => Some(self.frames()),
+            _ => None,
+        }
+    }
+
+    // Starts a new loop of `bars` at the next bar line into `layer`
+    fn record(&mut self, bar: f64, tempo: f32, sample_rate: f32, mut layer: Layer, freed: &mut Vec<Layer>) {
+        self.length = self.bars.clamp(1, MAX_LOOP_BARS) as f64 * bar;
+        self.frames_per_beat = sample_rate as f64 * 60.0 / tempo as f64;
+        // Only differs if the tempo moved since the buffer was made
+        layer.resize(self.frames(), [0.0; 2]);
+        freed.append(&mut self.layers);
+        self.layers.push(layer);
+        self.state = LoopState::Armed { bar };
+    }
+
+    // Adds `layer` to a playing loop, or finishes the one being added
+    fn toggle_overdub(&mut self, layer: Option<Layer>) {
+        match (self.state, layer) {
+            (LoopState::Playing, Some(layer)) => {
+                self.layers.push(layer);
+                self.state = LoopState::Overdubbing;
+            }
+            (LoopState::Overdubbing, _) => self.state = LoopState::Playing,
+            _ => {}
+        }
+    }
+
+    // Drops the newest layer; undoing the first one empties the track
+    fn undo(&mut self, freed: &mut Vec<Layer>) {
+        if matches!(self.state, LoopState::Armed { .. } | LoopState::Recording { .. }) {
+            return self.clear(freed);
+        }
+        freed.extend(self.layers.pop());
+        self.state = match self.layers.is_empty() {
+            true => LoopState::Empty,
+            false => LoopState::Playing,
+        };
+    }
+
+    fn clear(&mut self, freed: &mut Vec<Layer>) {
+        freed.append(&mut self.layers);
+        self.length = 0.0;
+        self.state = LoopState::Empty;
+    }
+
+    // Doubles the loop, repeating what is there
+    pub fn multiply(&mut self, bar: f64) {
+        if self.state == LoopState::Playing && self.length * 2.0 <= MAX_LOOP_BARS as f64 * bar {
+            self.length *= 2.0;
+        }
+    }
+
+    // Halves the loop down to a single beat at the shortest
+    pub fn halve(&mut self, beat: f64) {
+        if self.state == LoopState::Playing && self.length / 2.0 >= beat {
+            self.length /= 2.0;
+        }
+    }
+
+    // Records `input` and returns the loop's playback. Nothing moves while
+    // the transport is stopped.
+    fn process(&mut self, input: [f32; 2], position: Option<f64>, smoothing: f32) -> [f32; 2] {
+        self.gain.target = if self.muted { 0.0 } else { 1.0 };
+        let gain = self.gain.next(smoothing) * self.volume.next(smoothing);
+        let Some(position) = position else {
+            return [0.0; 2];
+        };
+        let jumped_back = position < self.last_position;
+        self.last_position = position;
+
+        match self.state {
+            LoopState::Empty => return [0.0; 2],
+            LoopState::Armed { bar } => {
+                let start = (position / bar).ceil() * bar;
+                self.state = LoopState::Recording { start };
+                return [0.0; 2];
+            }
+            LoopState::Recording { start } => {
+                if jumped_back {
+                    // Start over from the next bar line after a rewind
+                    let bar = self.length / self.bars.clamp(1, MAX_LOOP_BARS) as f64;
+                    self.layers[0].fill([0.0; 2]);
+                    self.state = LoopState::Armed { bar };
+                } else if position >= start + self.length {
+                    self.state = LoopState::Playing;
+                } else if position >= start {
+                    let index = self.index(position, self.layers[0].len());
+                    self.layers[0][index] = input;
+                }
+                if self.state != LoopState::Playing {
+                    return [0.0; 2];
+                }
+            }
+            LoopState::Playing | LoopState::Overdubbing => {}
+        }
+
+        let mut output = [0.0; 2];
+        for layer in self.layers.iter() {
+            let frame = layer[self.index(position, layer.len())];
+            output[0] += frame[0];
+            output[1] += frame[1];
+        }
+        if self.state == LoopState::Overdubbing {
+            let length = self.layers.last().map_or(0, |layer| layer.len());
+            let index = self.index(position, length);
+            if let Some(frame) = self.layers.last_mut().and_then(|layer| layer.get_mut(index)) {
+                frame[0] += input[0];
+                frame[1] += input[1];
+            }
+        }
+        [output[0] * gain, output[1] * gain]
+    }
+
+    // Frame of a layer `frames` long playing at `position`
+    fn index(&self, position: f64, frames: usize) -> usize {
+        if frames == 0 {
+            return 0;
+        }
+        let phase = position.rem_euclid(self.length);
+        (phase * self.frames_per_beat) as usize % frames
+    }
+}
+
+// What the editor asks of a track. Layers can be hundreds of megabytes, so
+// they are made before the audio lock is taken and freed after it is let go.
+#[derive(Clone, Copy)]
+pub enum LoopAction {
+    Record,
+    Overdub,
+    Undo,
+    Clear,
+}
+
+pub struct LoopRequest {
+    pub track: usize,
+    pub action: LoopAction,
+}
+
+// Loop tracks fed from the output of the effects and mixed back in
+pub struct Looper {
+    pub tracks: Vec<LoopTrack>,
+}
+
+impl Looper {
+    pub fn new() -> Self {
+        Self {
+            tracks: (0..LOOP_TRACKS).map(|_| LoopTrack::new()).collect(),
+        }
+    }
+
+    // Frames of the buffer a request needs, if any
+    pub fn buffer_frames(&self, request: &LoopRequest, bar: f64, tempo: f32, sample_rate: f32) -> Option<usize> {
+        let track = self.tracks.get(request.track)?;
+        track.buffer_frames(request.action, bar, tempo, sample_rate)
+    }
+
+    // Carries out a request with the buffer made for it and returns the
+    // layers it let go of
+    pub fn apply(
+        &mut self,
+        request: &LoopRequest,
+        buffer: Option<Layer>,
+        bar: f64,
+        tempo: f32,
+        sample_rate: f32,
+    ) -> Vec<Layer> {
+        let mut freed = Vec::new();
+        let Some(track) = self.tracks.get_mut(request.track) else {
+            return freed;
+        };
+        match (request.action, buffer) {
+            (LoopAction::Record, Some(layer)) => track.record(bar, tempo, sample_rate, layer, &mut freed),
+            (LoopAction::Record, None) => {}
+            (LoopAction::Overdub, layer) => track.toggle_overdub(layer),
+            (LoopAction::Undo, _) => track.undo(&mut freed),
+            (LoopAction::Clear, _) => track.clear(&mut freed),
+        }
+        freed
+    }
+
+    // `position` is the transport position while it plays
+    pub fn process(&mut self, input: [f32; 2], position: Option<f64>, smoothing: f32) -> [f32; 2] {
+        let mut output = input;
+        for track in self.tracks.iter_mut() {
+            let played = track.process(input, position, smoothing);
+            output[0] += played[0];
+            output[1] += played[1];
+        }
+        output
+    }
+}
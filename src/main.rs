@@ -1,341 +1,1473 @@
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use cpal::{SampleFormat, Stream};
 use eframe::egui;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::f32::consts::PI;
+use std::f32::consts::{FRAC_1_SQRT_2, PI};
+use std::ops::RangeInclusive;
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
 
-#[derive(Clone)]
-struct ChorusParameters {
-    buffers: Vec<Vec<f32>>,
-    positions: Vec<usize>,
-    rates: Vec<f32>,
-    depths: Vec<f32>,
-    phases: Vec<f32>,
-    mix: f32,
+mod arpeggiator;
+mod chords;
+mod cli;
+mod convolution;
+mod distortion;
+mod dynamics;
+mod eq;
+mod keyboard;
+mod looper;
+mod midi;
+mod modulation;
+mod patch;
+mod recorder;
+mod scale;
+mod sequencer;
+mod smf;
+mod transport;
+mod tui;
+
+// A parameter that glides towards its target with a one-pole lowpass, so
+// slider and controller steps don't produce zipper noise. Only the target
+// is saved.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(from = "f32", into = "f32")]
+struct SmoothedParam {
+    target: f32,
+    current: f32,
+}
+
+impl SmoothedParam {
+    fn new(value: f32) -> Self {
+        Self {
+            target: value,
+            current: value,
+        }
+    }
+
+    fn next(&mut self, coefficient: f32) -> f32 {
+        self.current += (self.target - self.current) * coefficient;
+        self.current
+    }
+
+    fn default_active() -> Self {
+        Self::new(1.0)
+    }
+}
+
+impl From<f32> for SmoothedParam {
+    fn from(value: f32) -> Self {
+        Self::new(value)
+    }
+}
+
+impl From<SmoothedParam> for f32 {
+    fn from(param: SmoothedParam) -> Self {
+        param.target
+    }
+}
+
+fn smoothing_coefficient(time: f32, sample_rate: f32) -> f32 {
+    if time <= 0.0 {
+        1.0
+    } else {
+        1.0 - (-1.0 / (time * sample_rate)).exp()
+    }
+}
+
+fn one_pole_coefficient(cutoff: f32, sample_rate: f32) -> f32 {
+    1.0 - (-2.0 * PI * cutoff / sample_rate).exp()
+}
+
+// Per-sample state shared by every effect in the stack
+#[derive(Clone, Copy)]
+struct ProcessContext {
+    sample_rate: f32,
+    smoothing: f32,
+    tempo: f32,
+    // Transport position in quarter notes, while it is playing
+    position: Option<f64>,
+    // Signal collected by send effects for each aux bus
+    sends: [[f32; 2]; MAX_AUX_BUSES],
+    // Dry voice sum, used as the key input by dynamics effects
+    sidechain: [f32; 2],
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+enum BiquadKind {
+    LowPass,
+    HighPass,
+    Peaking,
+    LowShelf,
+    HighShelf,
+}
+
+impl BiquadKind {
+    const ALL: [BiquadKind; 5] = [
+        BiquadKind::LowPass,
+        BiquadKind::HighPass,
+        BiquadKind::Peaking,
+        BiquadKind::LowShelf,
+        BiquadKind::HighShelf,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            BiquadKind::LowPass => "Low Pass",
+            BiquadKind::HighPass => "High Pass",
+            BiquadKind::Peaking => "Peaking",
+            BiquadKind::LowShelf => "Low Shelf",
+            BiquadKind::HighShelf => "High Shelf",
+        }
+    }
+
+    // Whether the gain setting has any effect on this shape
+    fn has_gain(self) -> bool {
+        matches!(self, BiquadKind::Peaking | BiquadKind::LowShelf | BiquadKind::HighShelf)
+    }
+}
+
+// Transposed direct form II biquad with RBJ cookbook coefficients
+#[derive(Clone, Copy, Default)]
+struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    z1: f32,
+    z2: f32,
+}
+
+impl Biquad {
+    // `gain` is in dB and only used by the peaking and shelving shapes
+    fn set(&mut self, kind: BiquadKind, frequency: f32, q: f32, gain: f32, sample_rate: f32) {
+        let omega = 2.0 * PI * frequency.clamp(10.0, sample_rate * 0.49) / sample_rate;
+        let (sin, cos) = omega.sin_cos();
+        let alpha = sin / (2.0 * q.max(0.01));
+        let a = 10.0f32.powf(gain / 40.0);
+        let shelf = 2.0 * a.sqrt() * alpha;
+        let (b0, b1, b2, a0, a1, a2) = match kind {
+            BiquadKind::LowPass => (
+                (1.0 - cos) / 2.0,
+                1.0 - cos,
+                (1.0 - cos) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ),
+            BiquadKind::HighPass => (
+                (1.0 + cos) / 2.0,
+                -(1.0 + cos),
+                (1.0 + cos) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ),
+            BiquadKind::Peaking => (
+                1.0 + alpha * a,
+                -2.0 * cos,
+                1.0 - alpha * a,
+                1.0 + alpha / a,
+                -2.0 * cos,
+                1.0 - alpha / a,
+            ),
+            BiquadKind::LowShelf => (
+                a * ((a + 1.0) - (a - 1.0) * cos + shelf),
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                a * ((a + 1.0) - (a - 1.0) * cos - shelf),
+                (a + 1.0) + (a - 1.0) * cos + shelf,
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                (a + 1.0) + (a - 1.0) * cos - shelf,
+            ),
+            BiquadKind::HighShelf => (
+                a * ((a + 1.0) + (a - 1.0) * cos + shelf),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - shelf),
+                (a + 1.0) - (a - 1.0) * cos + shelf,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - shelf,
+            ),
+        };
+        self.b0 = b0 / a0;
+        self.b1 = b1 / a0;
+        self.b2 = b2 / a0;
+        self.a1 = a1 / a0;
+        self.a2 = a2 / a0;
+    }
+
+    // Magnitude of the frequency response at `frequency`
+    fn magnitude(&self, frequency: f32, sample_rate: f32) -> f32 {
+        let omega = 2.0 * PI * frequency / sample_rate;
+        let (sin, cos) = omega.sin_cos();
+        let (sin2, cos2) = (2.0 * omega).sin_cos();
+        let numerator_re = self.b0 + self.b1 * cos + self.b2 * cos2;
+        let numerator_im = -(self.b1 * sin + self.b2 * sin2);
+        let denominator_re = 1.0 + self.a1 * cos + self.a2 * cos2;
+        let denominator_im = -(self.a1 * sin + self.a2 * sin2);
+        ((numerator_re.powi(2) + numerator_im.powi(2))
+            / (denominator_re.powi(2) + denominator_im.powi(2)))
+        .sqrt()
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let output = self.b0 * input + self.z1;
+        self.z1 = self.b1 * input - self.a1 * output + self.z2;
+        self.z2 = self.b2 * input - self.a2 * output;
+        output
+    }
+
+    fn reset(&mut self) {
+        self.z1 = 0.0;
+        self.z2 = 0.0;
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+enum NoteValue {
+    Whole,
+    Half,
+    Quarter,
+    Eighth,
+    Sixteenth,
+    ThirtySecond,
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+enum NoteModifier {
+    Straight,
+    Dotted,
+    Triplet,
+}
+
+// A tempo-relative duration such as a dotted eighth or a quarter triplet
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct NoteDivision {
+    value: NoteValue,
+    modifier: NoteModifier,
+}
+
+impl NoteDivision {
+    const VALUES: [NoteValue; 6] = [
+        NoteValue::Whole,
+        NoteValue::Half,
+        NoteValue::Quarter,
+        NoteValue::Eighth,
+        NoteValue::Sixteenth,
+        NoteValue::ThirtySecond,
+    ];
+    const MODIFIERS: [NoteModifier; 3] = [
+        NoteModifier::Straight,
+        NoteModifier::Dotted,
+        NoteModifier::Triplet,
+    ];
+
+    fn new(value: NoteValue, modifier: NoteModifier) -> Self {
+        Self { value, modifier }
+    }
+
+    // Length in quarter-note beats
+    fn beats(&self) -> f32 {
+        let base = match self.value {
+            NoteValue::Whole => 4.0,
+            NoteValue::Half => 2.0,
+            NoteValue::Quarter => 1.0,
+            NoteValue::Eighth => 0.5,
+            NoteValue::Sixteenth => 0.25,
+            NoteValue::ThirtySecond => 0.125,
+        };
+        match self.modifier {
+            NoteModifier::Straight => base,
+            NoteModifier::Dotted => base * 1.5,
+            NoteModifier::Triplet => base * 2.0 / 3.0,
+        }
+    }
+
+    fn seconds(&self, tempo: f32) -> f32 {
+        self.beats() * 60.0 / tempo
+    }
+
+    fn label(&self) -> String {
+        let value = match self.value {
+            NoteValue::Whole => "1/1",
+            NoteValue::Half => "1/2",
+            NoteValue::Quarter => "1/4",
+            NoteValue::Eighth => "1/8",
+            NoteValue::Sixteenth => "1/16",
+            NoteValue::ThirtySecond => "1/32",
+        };
+        match self.modifier {
+            NoteModifier::Straight => value.to_string(),
+            NoteModifier::Dotted => format!("{value}."),
+            NoteModifier::Triplet => format!("{value}T"),
+        }
+    }
+
+    // Combo box for picking a division, returns true when it changed
+    fn ui(&mut self, ui: &mut egui::Ui, id: impl std::hash::Hash) -> bool {
+        let mut changed = false;
+        egui::ComboBox::from_id_salt(id)
+            .selected_text(self.label())
+            .show_ui(ui, |ui| {
+                for value in Self::VALUES {
+                    for modifier in Self::MODIFIERS {
+                        let division = Self::new(value, modifier);
+                        changed |= ui
+                            .selectable_value(self, division, division.label())
+                            .changed();
+                    }
+                }
+            });
+        changed
+    }
+}
+
+// Circular buffer with fractional-delay reads. Empty until allocated by the
+// owning effect's `prepare`.
+#[derive(Clone, Default)]
+struct DelayLine {
+    buffer: Vec<f32>,
+    position: usize,
+}
+
+impl DelayLine {
+    fn new(max_samples: usize) -> Self {
+        Self {
+            buffer: vec![0.0; max_samples.max(4)],
+            position: 0,
+        }
+    }
+
+    fn write(&mut self, sample: f32) {
+        self.buffer[self.position] = sample;
+        self.position = (self.position + 1) % self.buffer.len();
+    }
+
+    fn at(&self, delay: usize) -> f32 {
+        let len = self.buffer.len();
+        self.buffer[(self.position + len - delay % len) % len]
+    }
+
+    // Cubic Hermite read of the sample written `delay` samples ago, where
+    // a delay of 1.0 is the most recent write
+    fn read(&self, delay: f32) -> f32 {
+        let delay = delay.clamp(1.0, (self.buffer.len() - 2) as f32);
+        let whole = delay.floor() as usize;
+        let t = delay - whole as f32;
+        // Nothing newer than the latest write exists, so repeat it
+        let y0 = self.at(whole.max(2) - 1);
+        let y1 = self.at(whole);
+        let y2 = self.at(whole + 1);
+        let y3 = self.at(whole + 2);
+        let c1 = 0.5 * (y2 - y0);
+        let c2 = y0 - 2.5 * y1 + 2.0 * y2 - 0.5 * y3;
+        let c3 = 0.5 * (y3 - y0) + 1.5 * (y1 - y2);
+        ((c3 * t + c2) * t + c1) * t + y1
+    }
+
+    // Linear read, cheaper than `read` but slightly dulls the top end
+    fn read_linear(&self, delay: f32) -> f32 {
+        let delay = delay.clamp(1.0, (self.buffer.len() - 2) as f32);
+        let whole = delay.floor() as usize;
+        let t = delay - whole as f32;
+        let y1 = self.at(whole);
+        y1 + (self.at(whole + 1) - y1) * t
+    }
+
+    fn clear(&mut self) {
+        self.buffer.fill(0.0);
+        self.position = 0;
+    }
 }
 
-#[derive(Clone)]
+// Freeverb tunings in samples at 44.1kHz
+const REVERB_COMB_TUNINGS: [f32; 8] = [1116.0, 1188.0, 1277.0, 1356.0, 1422.0, 1491.0, 1557.0, 1617.0];
+const REVERB_ALLPASS_TUNINGS: [f32; 4] = [556.0, 441.0, 341.0, 225.0];
+const REVERB_STEREO_SPREAD: f32 = 23.0;
+// Early reflection taps as (milliseconds, gain) for each channel
+const REVERB_EARLY_TAPS: [[(f32, f32); 6]; 2] = [
+    [(4.3, 0.84), (21.5, 0.50), (26.8, 0.38), (29.8, 0.35), (45.8, 0.29), (48.8, 0.27)],
+    [(5.1, 0.80), (19.7, 0.52), (24.2, 0.40), (33.1, 0.33), (41.3, 0.30), (52.6, 0.25)],
+];
+const MAX_REVERB_SIZE: f32 = 2.0;
+const MAX_PRE_DELAY: f32 = 0.25;
+
+#[derive(Clone, Serialize, Deserialize)]
 struct ReverbParameters {
-    comb_filters: Vec<Vec<f32>>,
-    comb_positions: Vec<usize>,
-    allpass_filters: Vec<Vec<f32>>,
-    allpass_positions: Vec<usize>,
-    feedback: f32,
-    mix: f32,
+    #[serde(skip)]
+    comb_lines: [Vec<DelayLine>; 2],
+    #[serde(skip)]
+    comb_damping: [Vec<f32>; 2],
+    #[serde(skip)]
+    allpass_lines: [Vec<DelayLine>; 2],
+    // Holds the dry input for the pre-delay and the early reflection taps
+    #[serde(skip)]
+    input_line: DelayLine,
+    // Scales every delay length, i.e. the room dimensions
+    size: SmoothedParam,
+    // Seconds for the tail to fall by 60dB
+    decay: SmoothedParam,
+    damping: SmoothedParam,
+    pre_delay: SmoothedParam,
+    width: SmoothedParam,
+    early_level: SmoothedParam,
+    mix: SmoothedParam,
+}
+
+impl ReverbParameters {
+    // Freeverb-style: 8 damped combs and 4 allpasses per channel, with
+    // every line sized for the largest room so it can be resized live
+    fn prepare(&mut self, sample_rate: f32) {
+        let scale = sample_rate / 44100.0;
+        let comb_line = |tuning: f32| {
+            DelayLine::new(((tuning + REVERB_STEREO_SPREAD) * scale * MAX_REVERB_SIZE) as usize + 4)
+        };
+        let allpass_line =
+            |tuning: f32| DelayLine::new(((tuning + REVERB_STEREO_SPREAD) * scale) as usize + 4);
+        let longest_tap = REVERB_EARLY_TAPS
+            .iter()
+            .flatten()
+            .map(|(time, _)| *time)
+            .fold(0.0, f32::max);
+        let input_size = (MAX_PRE_DELAY + longest_tap * 0.001 * MAX_REVERB_SIZE) * sample_rate;
+
+        self.comb_lines = [0, 1].map(|_| REVERB_COMB_TUNINGS.iter().map(|&t| comb_line(t)).collect());
+        self.comb_damping = [0, 1].map(|_| vec![0.0; REVERB_COMB_TUNINGS.len()]);
+        self.allpass_lines =
+            [0, 1].map(|_| REVERB_ALLPASS_TUNINGS.iter().map(|&t| allpass_line(t)).collect());
+        self.input_line = DelayLine::new(input_size as usize + 4);
+    }
+
+    fn process(&mut self, input: [f32; 2], context: &ProcessContext) -> [f32; 2] {
+        let sample_rate = context.sample_rate;
+        let smoothing = context.smoothing;
+        let scale = sample_rate / 44100.0;
+        // The size glides slowly so the delay lengths don't jump
+        let size = self.size.next(smoothing * 0.1);
+        let decay = self.decay.next(smoothing);
+        let damping = self.damping.next(smoothing);
+        let pre_delay = self.pre_delay.next(smoothing);
+        let width = self.width.next(smoothing);
+        let early_level = self.early_level.next(smoothing);
+        let mix = self.mix.next(smoothing);
+
+        self.input_line.write((input[0] + input[1]) * 0.5);
+        let pre_delay_samples = pre_delay * sample_rate;
+        let late_input = self.input_line.read(pre_delay_samples) * 0.015;
+
+        let mut wet = [0.0; 2];
+        for channel in 0..2 {
+            let spread = channel as f32 * REVERB_STEREO_SPREAD;
+
+            let mut early = 0.0;
+            for (time, gain) in REVERB_EARLY_TAPS[channel] {
+                let delay = pre_delay_samples + time * 0.001 * size * sample_rate;
+                early += self.input_line.read(delay) * gain;
+            }
+
+            let mut late = 0.0;
+            for (i, line) in self.comb_lines[channel].iter_mut().enumerate() {
+                let delay = (REVERB_COMB_TUNINGS[i] + spread) * scale * size;
+                let feedback = 10.0f32.powf(-3.0 * delay / (decay * sample_rate));
+                let output = line.read(delay);
+                let store = &mut self.comb_damping[channel][i];
+                *store = output * (1.0 - damping) + *store * damping;
+                line.write(late_input + *store * feedback);
+                late += output;
+            }
+
+            for (i, line) in self.allpass_lines[channel].iter_mut().enumerate() {
+                let delayed = line.read((REVERB_ALLPASS_TUNINGS[i] + spread) * scale);
+                line.write(late + delayed * 0.5);
+                late = delayed - late;
+            }
+
+            wet[channel] = late * 3.0 + early * early_level * 0.5;
+        }
+
+        let direct = (1.0 + width) * 0.5;
+        let cross = (1.0 - width) * 0.5;
+        [
+            input[0] * (1.0 - mix) + (wet[0] * direct + wet[1] * cross) * mix,
+            input[1] * (1.0 - mix) + (wet[1] * direct + wet[0] * cross) * mix,
+        ]
+    }
+
+    fn reset(&mut self) {
+        for channel in 0..2 {
+            for line in self.comb_lines[channel].iter_mut() {
+                line.clear();
+            }
+            for line in self.allpass_lines[channel].iter_mut() {
+                line.clear();
+            }
+            self.comb_damping[channel].fill(0.0);
+        }
+        self.input_line.clear();
+    }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 struct RingModParameters {
-    frequency: f32,
+    frequency: SmoothedParam,
+    #[serde(skip)]
     phase: f32,
-    mix: f32,
+    mix: SmoothedParam,
+}
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+enum DelayMode {
+    Mono,
+    Stereo,
+    PingPong,
 }
-#[derive(Clone)]
+
+#[derive(Clone, Serialize, Deserialize)]
 struct DelayParameters {
-    buffer: Vec<f32>,
-    position: usize,
-    delay_time: f32,
-    feedback: f32,
-    mix: f32,
+    #[serde(skip)]
+    lines: [DelayLine; 2],
+    // Seconds; follows the tempo when synced
+    delay_time: SmoothedParam,
+    sync: Option<NoteDivision>,
+    // How long the read head takes to reach a new delay time, which bends
+    // the pitch of the echoes like a tape delay
+    glide: f32,
+    // Right channel time offset as a fraction of the delay time
+    spread: SmoothedParam,
+    mode: DelayMode,
+    feedback: SmoothedParam,
+    // Filters applied to the echoes before they are fed back
+    low_cut: SmoothedParam,
+    high_cut: SmoothedParam,
+    #[serde(skip)]
+    filter_states: [[f32; 2]; 2],
+    mix: SmoothedParam,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 struct FilterParameters {
-    cutoff: f32,
+    cutoff: SmoothedParam,
     resonance: f32,
-    mix: f32,
-    prev_input: f32,
-    prev_output: f32,
+    mix: SmoothedParam,
+    #[serde(skip)]
+    prev_input: [f32; 2],
+    #[serde(skip)]
+    prev_output: [f32; 2],
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 struct TremoloParameters {
-    rate: f32,
-    depth: f32,
-    mix: f32,
-    phase: f32,
+    lfo: modulation::Lfo,
+    depth: SmoothedParam,
+    mix: SmoothedParam,
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+enum SplitMode {
+    // Every branch gets the full signal
+    Parallel,
+    // Branch n gets the band between crossover n-1 and crossover n
+    Bands,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Branch {
+    effects: EffectStack,
+    level: SmoothedParam,
+}
+
+// Splits the signal into parallel branches and sums them back together
+#[derive(Clone, Serialize, Deserialize)]
+struct SplitParameters {
+    mode: SplitMode,
+    branches: Vec<Branch>,
+    // Hz, one fewer than there are branches
+    crossovers: Vec<f32>,
+    // Two cascaded Butterworth sections per side make a Linkwitz-Riley
+    // crossover, stored as [lowpass, highpass] per crossover and channel
+    #[serde(skip)]
+    crossover_filters: Vec<[[[Biquad; 2]; 2]; 2]>,
+    #[serde(skip)]
+    crossover_tuning: Vec<f32>,
+}
+
+impl SplitParameters {
+    fn new(mode: SplitMode, branch_count: usize) -> Self {
+        let mut split = Self {
+            mode,
+            branches: Vec::new(),
+            crossovers: Vec::new(),
+            crossover_filters: Vec::new(),
+            crossover_tuning: Vec::new(),
+        };
+        for _ in 0..branch_count {
+            split.add_branch();
+        }
+        split
+    }
+
+    fn add_branch(&mut self) {
+        if !self.branches.is_empty() {
+            let last = self.crossovers.last().copied().unwrap_or(100.0);
+            self.crossovers.push((last * 8.0).min(16000.0));
+        }
+        self.branches.push(Branch {
+            effects: EffectStack::new(),
+            level: SmoothedParam::new(1.0),
+        });
+        self.prepare_crossovers();
+    }
+
+    fn remove_branch(&mut self, index: usize) -> Branch {
+        let branch = self.branches.remove(index);
+        if !self.crossovers.is_empty() {
+            self.crossovers.remove(index.min(self.crossovers.len() - 1));
+        }
+        self.prepare_crossovers();
+        branch
+    }
+
+    fn prepare_crossovers(&mut self) {
+        self.crossover_filters = vec![Default::default(); self.crossovers.len()];
+        self.crossover_tuning = vec![0.0; self.crossovers.len()];
+    }
+
+    fn process(&mut self, input: [f32; 2], context: &mut ProcessContext) -> [f32; 2] {
+        let mut output = [0.0; 2];
+        let mut rest = input;
+        let branch_count = self.branches.len();
+        for (index, branch) in self.branches.iter_mut().enumerate() {
+            let crossover = (
+                self.crossovers.get(index),
+                self.crossover_filters.get_mut(index),
+                self.crossover_tuning.get_mut(index),
+            );
+            let band = match crossover {
+                _ if self.mode == SplitMode::Parallel => rest,
+                (Some(&frequency), Some(filters), Some(tuning)) if index + 1 < branch_count => {
+                    if *tuning != frequency {
+                        *tuning = frequency;
+                        for [low, high] in filters.iter_mut() {
+                            for stage in low.iter_mut() {
+                                stage.set(BiquadKind::LowPass, frequency, FRAC_1_SQRT_2, 0.0, context.sample_rate);
+                            }
+                            for stage in high.iter_mut() {
+                                stage.set(BiquadKind::HighPass, frequency, FRAC_1_SQRT_2, 0.0, context.sample_rate);
+                            }
+                        }
+                    }
+                    let mut band = [0.0; 2];
+                    for channel in 0..2 {
+                        let [[low_a, low_b], [high_a, high_b]] = &mut filters[channel];
+                        band[channel] = low_b.process(low_a.process(rest[channel]));
+                        rest[channel] = high_b.process(high_a.process(rest[channel]));
+                    }
+                    band
+                }
+                // The last band, or one missing its crossover, takes
+                // everything that is left
+                _ => std::mem::take(&mut rest),
+            };
+            let level = branch.level.next(context.smoothing);
+            let processed = branch.effects.process(band, context);
+            output[0] += processed[0] * level;
+            output[1] += processed[1] * level;
+        }
+        output
+    }
+}
+
+// Copies the signal into an aux bus and passes it through unchanged
+#[derive(Clone, Serialize, Deserialize)]
+struct SendParameters {
+    bus: usize,
+    level: SmoothedParam,
+}
+
+// A continuously variable effect setting, with the range its control covers
+struct EffectParameter<'a> {
+    name: String,
+    param: &'a mut SmoothedParam,
+    range: RangeInclusive<f32>,
 }
 
 // Main effect enum
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 enum Effect {
     Delay(DelayParameters),
-    Distortion { drive: f32, mix: f32 },
+    Distortion(distortion::DistortionParameters),
+    BitCrusher(distortion::BitCrusherParameters),
     Filter(FilterParameters),
     Tremolo(TremoloParameters),
-    Chorus(ChorusParameters),
+    Chorus(modulation::ChorusParameters),
+    Flanger(modulation::FlangerParameters),
+    Phaser(modulation::PhaserParameters),
     Reverb(ReverbParameters),
     RingMod(RingModParameters),
+    Convolution(convolution::ConvolutionParameters),
+    Split(SplitParameters),
+    Send(SendParameters),
+    Compressor(dynamics::CompressorParameters),
+    Expander(dynamics::ExpanderParameters),
+    TransientShaper(dynamics::TransientShaperParameters),
+    Eq(eq::EqParameters),
 }
 
 impl Effect {
-    fn process(&mut self, sample: f32, sample_rate: f32) -> f32 {
+    fn process(&mut self, input: [f32; 2], context: &mut ProcessContext) -> [f32; 2] {
+        let sample_rate = context.sample_rate;
+        let smoothing = context.smoothing;
+        let mono = (input[0] + input[1]) * 0.5;
         match self {
             Effect::Delay(params) => {
-                let delayed = params.buffer[params.position];
-                params.buffer[params.position] = sample + delayed * params.feedback;
-                params.position = (params.position + 1) % params.buffer.len();
-                sample * (1.0 - params.mix) + delayed * params.mix
-            },
-            Effect::Distortion { drive, mix } => {
-                let processed = (sample * *drive).tanh();
-                sample * (1.0 - *mix) + processed * *mix
-            },
-            Effect::Filter(params) => {
-                let normalized_cutoff = 2.0 * std::f32::consts::PI * params.cutoff / sample_rate;
-                let alpha = normalized_cutoff / (1.0 + normalized_cutoff);
-                
-                let processed = params.prev_output + alpha * (sample - params.prev_output);
-                params.prev_output = processed;
-                params.prev_input = sample;
-                
-                sample * (1.0 - params.mix) + processed * params.mix
-            },
-            Effect::Tremolo(params) => {
-                let modulation = (1.0 + (params.phase * 2.0 * std::f32::consts::PI).sin() * params.depth) * 0.5;
-                params.phase = (params.phase + params.rate / sample_rate) % 1.0;
-                
-                let processed = sample * modulation;
-                sample * (1.0 - params.mix) + processed * params.mix
-            },
+                if let Some(division) = params.sync {
+                    params.delay_time.target = division.seconds(context.tempo);
+                }
+                params.delay_time.target = params.delay_time.target.clamp(0.001, MAX_DELAY_TIME);
+                let delay_time = params.delay_time.next(smoothing_coefficient(params.glide, sample_rate));
+                let spread = params.spread.next(smoothing);
+                let feedback = params.feedback.next(smoothing);
+                let low_cut = one_pole_coefficient(params.low_cut.next(smoothing), sample_rate);
+                let high_cut = one_pole_coefficient(params.high_cut.next(smoothing), sample_rate);
+                let mix = params.mix.next(smoothing);
+
+                let delays = [
+                    delay_time * sample_rate,
+                    delay_time * (1.0 + spread) * sample_rate,
+                ];
+                let mut delayed = [
+                    params.lines[0].read(delays[0]),
+                    params.lines[1].read(delays[1]),
+                ];
+                if params.mode == DelayMode::Mono {
+                    delayed[1] = delayed[0];
+                }
 
-            Effect::Chorus(params) => {
-                let mut output = 0.0;
+                // Band-limit the feedback path so repeats darken and thin out
+                let mut echoes = [0.0; 2];
+                for channel in 0..2 {
+                    let [low, high] = &mut params.filter_states[channel];
+                    *low += high_cut * (delayed[channel] - *low);
+                    *high += low_cut * (*low - *high);
+                    echoes[channel] = (*low - *high) * feedback;
+                }
 
-                for i in 0..params.buffers.len() {
-                    // Update LFO phase
-                    params.phases[i] = (params.phases[i] + params.rates[i] / sample_rate) % 1.0;
+                match params.mode {
+                    DelayMode::Mono => {
+                        params.lines[0].write(mono + echoes[0]);
+                        params.lines[1].write(0.0);
+                    }
+                    DelayMode::Stereo => {
+                        params.lines[0].write(input[0] + echoes[0]);
+                        params.lines[1].write(input[1] + echoes[1]);
+                    }
+                    // The dry signal enters on the left and each repeat crosses over
+                    DelayMode::PingPong => {
+                        params.lines[0].write(mono + echoes[1]);
+                        params.lines[1].write(echoes[0]);
+                    }
+                }
 
-                    // Calculate delay time with LFO modulation
-                    let mod_delay = (1.0 + (params.phases[i] * 2.0 * std::f32::consts::PI).sin() * params.depths[i]) * 0.5;
-                    let delay_samples = (mod_delay * (params.buffers[i].len() - 1) as f32) as usize;
+                [
+                    input[0] * (1.0 - mix) + delayed[0] * mix,
+                    input[1] * (1.0 - mix) + delayed[1] * mix,
+                ]
+            },
+            Effect::Distortion(params) => params.process(input, context),
+            Effect::BitCrusher(params) => params.process(input, context),
+            Effect::Filter(params) => {
+                let cutoff = params.cutoff.next(smoothing);
+                let mix = params.mix.next(smoothing);
+                let normalized_cutoff = 2.0 * std::f32::consts::PI * cutoff / sample_rate;
+                let alpha = normalized_cutoff / (1.0 + normalized_cutoff);
 
-                    // Read from buffer
-                    let read_pos = (params.positions[i] + params.buffers[i].len() - delay_samples) % params.buffers[i].len();
-                    output += params.buffers[i][read_pos];
+                let mut output = [0.0; 2];
+                for channel in 0..2 {
+                    let sample = input[channel];
+                    let processed = params.prev_output[channel] + alpha * (sample - params.prev_output[channel]);
+                    params.prev_output[channel] = processed;
+                    params.prev_input[channel] = sample;
 
-                    // Write to buffer
-                    params.buffers[i][params.positions[i]] = sample;
-                    params.positions[i] = (params.positions[i] + 1) % params.buffers[i].len();
+                    output[channel] = sample * (1.0 - mix) + processed * mix;
                 }
-
-                output /= params.buffers.len() as f32;
-                sample * (1.0 - params.mix) + output * params.mix
+                output
             },
-            Effect::Reverb(params) => {
-                // Process comb filters in parallel
-                let mut comb_output = 0.0;
-                for i in 0..params.comb_filters.len() {
-                    let delayed = params.comb_filters[i][params.comb_positions[i]];
-                    comb_output += delayed;
-                    params.comb_filters[i][params.comb_positions[i]] = sample + delayed * params.feedback;
-                    params.comb_positions[i] = (params.comb_positions[i] + 1) % params.comb_filters[i].len();
-                }
-                comb_output /= params.comb_filters.len() as f32;
-
-                // Process allpass filters in series
-                let mut allpass_output = comb_output;
-                for i in 0..params.allpass_filters.len() {
-                    let delayed = params.allpass_filters[i][params.allpass_positions[i]];
-                    let input = allpass_output;
-                    allpass_output = delayed - input;
-                    params.allpass_filters[i][params.allpass_positions[i]] = input + delayed * 0.5;
-                    params.allpass_positions[i] = (params.allpass_positions[i] + 1) % params.allpass_filters[i].len();
-                }
-
-                sample * (1.0 - params.mix) + allpass_output * params.mix
+            Effect::Tremolo(params) => {
+                params.lfo.advance(context);
+                let depth = params.depth.next(smoothing);
+                let mix = params.mix.next(smoothing);
+                let modulation = (1.0 + params.lfo.value(0.0) * depth) * 0.5;
+
+                input.map(|sample| {
+                    let processed = sample * modulation;
+                    sample * (1.0 - mix) + processed * mix
+                })
             },
+
+            Effect::Chorus(params) => params.process(input, context),
+            Effect::Flanger(params) => params.process(input, context),
+            Effect::Phaser(params) => params.process(input, context),
+            Effect::Reverb(params) => params.process(input, context),
             Effect::RingMod(params) => {
+                let frequency = params.frequency.next(smoothing);
+                let mix = params.mix.next(smoothing);
                 let modulator = (params.phase * 2.0 * std::f32::consts::PI).sin();
-                params.phase = (params.phase + params.frequency / sample_rate) % 1.0;
+                params.phase = (params.phase + frequency / sample_rate) % 1.0;
 
-                let processed = sample * modulator;
-                sample * (1.0 - params.mix) + processed * params.mix
+                input.map(|sample| {
+                    let processed = sample * modulator;
+                    sample * (1.0 - mix) + processed * mix
+                })
+            },
+            Effect::Convolution(params) => params.process(input, context),
+            Effect::Split(params) => params.process(input, context),
+            Effect::Send(params) => {
+                let level = params.level.next(smoothing);
+                if let Some(send) = context.sends.get_mut(params.bus) {
+                    send[0] += input[0] * level;
+                    send[1] += input[1] * level;
+                }
+                input
             },
+            Effect::Compressor(params) => params.process(input, context),
+            Effect::Expander(params) => params.process(input, context),
+            Effect::TransientShaper(params) => params.process(input, context),
+            Effect::Eq(params) => params.process(input, context),
         }
     }
 
     fn reset(&mut self) {
         match self {
             Effect::Delay(params) => {
-                params.buffer.fill(0.0);
-                params.position = 0;
+                for line in params.lines.iter_mut() {
+                    line.clear();
+                }
+                params.filter_states = [[0.0; 2]; 2];
             },
-            Effect::Distortion { .. } => {},
+            Effect::Distortion(params) => params.reset(),
+            Effect::BitCrusher(params) => params.reset(),
             Effect::Filter(params) => {
-                params.prev_input = 0.0;
-                params.prev_output = 0.0;
+                params.prev_input = [0.0; 2];
+                params.prev_output = [0.0; 2];
             },
-            Effect::Tremolo(params) => {
+            Effect::Tremolo(params) => params.lfo.reset(),
+            Effect::Chorus(params) => params.reset(),
+            Effect::Flanger(params) => params.reset(),
+            Effect::Phaser(params) => params.reset(),
+            Effect::Reverb(params) => params.reset(),
+            Effect::RingMod(params) => {
                 params.phase = 0.0;
             },
-            Effect::Chorus(params) => {
-                for buffer in params.buffers.iter_mut() {
-                    buffer.fill(0.0);
+            Effect::Convolution(params) => params.reset(),
+            Effect::Split(params) => {
+                for branch in params.branches.iter_mut() {
+                    branch.effects.reset();
                 }
-                params.positions.fill(0);
-                params.phases.fill(0.0);
-            },
-            Effect::Reverb(params) => {
-                for buffer in params.comb_filters.iter_mut() {
-                    buffer.fill(0.0);
-                }
-                for buffer in params.allpass_filters.iter_mut() {
-                    buffer.fill(0.0);
+                for filter in params.crossover_filters.iter_mut().flatten().flatten().flatten() {
+                    filter.reset();
                 }
-                params.comb_positions.fill(0);
-                params.allpass_positions.fill(0);
             },
-            Effect::RingMod(params) => {
-                params.phase = 0.0;
+            Effect::Send(_) => {},
+            Effect::Compressor(params) => params.reset(),
+            Effect::Expander(params) => params.reset(),
+            Effect::TransientShaper(params) => params.reset(),
+            Effect::Eq(params) => params.reset(),
+        }
+    }
+
+    // Allocates the buffers and other runtime state that isn't saved, for
+    // effects that were just built or loaded from a file
+    fn prepare(&mut self, sample_rate: f32) {
+        match self {
+            Effect::Delay(params) => {
+                // The buffer covers the longest possible delay so that changing the
+                // time only moves the read head
+                let buffer_size = (sample_rate * MAX_DELAY_TIME) as usize + 4;
+                params.lines = [DelayLine::new(buffer_size), DelayLine::new(buffer_size)];
+            },
+            Effect::Chorus(params) => params.prepare(sample_rate),
+            Effect::Flanger(params) => params.prepare(sample_rate),
+            Effect::Reverb(params) => params.prepare(sample_rate),
+            Effect::Convolution(params) => params.prepare(sample_rate),
+            Effect::Eq(params) => params.prepare(),
+            Effect::Distortion(params) => params.prepare(),
+            Effect::Split(params) => {
+                params.prepare_crossovers();
+                for branch in params.branches.iter_mut() {
+                    branch.effects.prepare(sample_rate);
+                }
             },
+            Effect::Filter(_)
+            | Effect::BitCrusher(_)
+            | Effect::Phaser(_)
+            | Effect::Tremolo(_)
+            | Effect::RingMod(_)
+            | Effect::Send(_)
+            | Effect::Compressor(_)
+            | Effect::Expander(_)
+            | Effect::TransientShaper(_) => {},
         }
     }
 }
 
 
 impl Effect {
+    fn name(&self) -> &'static str {
+        match self {
+            Effect::Delay(_) => "Delay",
+            Effect::Distortion(_) => "Distortion",
+            Effect::BitCrusher(_) => "Bit Crusher",
+            Effect::Filter(_) => "Filter",
+            Effect::Tremolo(_) => "Tremolo",
+            Effect::Chorus(_) => "Chorus",
+            Effect::Flanger(_) => "Flanger",
+            Effect::Phaser(_) => "Phaser",
+            Effect::Reverb(_) => "Reverb",
+            Effect::RingMod(_) => "Ring Mod",
+            Effect::Convolution(_) => "Convolution",
+            Effect::Split(_) => "Split",
+            Effect::Send(_) => "Send",
+            Effect::Compressor(_) => "Compressor",
+            Effect::Expander(_) => "Expander",
+            Effect::TransientShaper(_) => "Transient Shaper",
+            Effect::Eq(_) => "EQ",
+        }
+    }
+
+    // The continuously variable settings by name with the range their
+    // controls cover, for automation such as sequencer parameter locks
+    fn parameters_mut(&mut self) -> Vec<EffectParameter<'_>> {
+        fn named<'a>(params: Vec<(&str, &'a mut SmoothedParam, RangeInclusive<f32>)>) -> Vec<EffectParameter<'a>> {
+            params
+                .into_iter()
+                .map(|(name, param, range)| EffectParameter {
+                    name: name.to_string(),
+                    param,
+                    range,
+                })
+                .collect()
+        }
+        const LFO_RATE: RangeInclusive<f32> = 0.01..=20.0;
+        const MIX: RangeInclusive<f32> = 0.0..=1.0;
+        match self {
+            Effect::Delay(params) => named(vec![
+                ("Delay Time", &mut params.delay_time, 0.001..=MAX_DELAY_TIME),
+                ("Stereo Offset", &mut params.spread, -0.5..=0.5),
+                ("Feedback", &mut params.feedback, 0.0..=0.95),
+                ("Feedback Low Cut", &mut params.low_cut, 20.0..=2000.0),
+                ("Feedback High Cut", &mut params.high_cut, 500.0..=20000.0),
+                ("Mix", &mut params.mix, MIX),
+            ]),
+            Effect::Distortion(params) => named(vec![
+                ("Drive", &mut params.drive, 1.0..=50.0),
+                ("Pre Cut", &mut params.pre_cut, 20.0..=2000.0),
+                ("Post Cut", &mut params.post_cut, 500.0..=20000.0),
+                ("Mix", &mut params.mix, MIX),
+            ]),
+            Effect::BitCrusher(params) => named(vec![
+                ("Bits", &mut params.bits, 1.0..=16.0),
+                ("Rate", &mut params.rate, 100.0..=48000.0),
+                ("Mix", &mut params.mix, MIX),
+            ]),
+            Effect::Filter(params) => named(vec![
+                ("Cutoff", &mut params.cutoff, 20.0..=20000.0),
+                ("Mix", &mut params.mix, MIX),
+            ]),
+            Effect::Tremolo(params) => named(vec![
+                ("Rate", &mut params.lfo.rate, LFO_RATE),
+                ("Depth", &mut params.depth, 0.0..=1.0),
+                ("Mix", &mut params.mix, MIX),
+            ]),
+            Effect::Chorus(params) => named(vec![
+                ("Rate", &mut params.lfo.rate, LFO_RATE),
+                ("Delay", &mut params.delay, 0.0005..=modulation::MAX_CHORUS_DELAY * 0.6),
+                ("Depth", &mut params.depth, 0.0..=modulation::MAX_CHORUS_DELAY * 0.4),
+                ("Feedback", &mut params.feedback, -0.9..=0.9),
+                ("Spread", &mut params.spread, 0.0..=0.5),
+                ("Mix", &mut params.mix, MIX),
+            ]),
+            Effect::Flanger(params) => named(vec![
+                ("Rate", &mut params.lfo.rate, LFO_RATE),
+                ("Delay", &mut params.delay, 0.0001..=modulation::MAX_FLANGER_DELAY / 2.0),
+                ("Depth", &mut params.depth, 0.0..=modulation::MAX_FLANGER_DELAY / 2.0),
+                ("Feedback", &mut params.feedback, -0.95..=0.95),
+                ("Stereo Phase", &mut params.stereo_phase, 0.0..=0.5),
+                ("Mix", &mut params.mix, MIX),
+            ]),
+            Effect::Phaser(params) => named(vec![
+                ("Rate", &mut params.lfo.rate, LFO_RATE),
+                ("Min Frequency", &mut params.min_frequency, 20.0..=5000.0),
+                ("Max Frequency", &mut params.max_frequency, 100.0..=15000.0),
+                ("Feedback", &mut params.feedback, -0.95..=0.95),
+                ("Stereo Phase", &mut params.stereo_phase, 0.0..=0.5),
+                ("Mix", &mut params.mix, MIX),
+            ]),
+            Effect::Reverb(params) => named(vec![
+                ("Size", &mut params.size, 0.1..=MAX_REVERB_SIZE),
+                ("Decay", &mut params.decay, 0.1..=20.0),
+                ("Damping", &mut params.damping, 0.0..=0.95),
+                ("Pre-Delay", &mut params.pre_delay, 0.0..=MAX_PRE_DELAY),
+                ("Width", &mut params.width, 0.0..=1.0),
+                ("Early Level", &mut params.early_level, 0.0..=1.0),
+                ("Mix", &mut params.mix, MIX),
+            ]),
+            Effect::RingMod(params) => named(vec![
+                ("Frequency", &mut params.frequency, 1.0..=2000.0),
+                ("Mix", &mut params.mix, MIX),
+            ]),
+            Effect::Convolution(params) => named(vec![
+                ("Level", &mut params.level, 0.0..=4.0),
+                ("Mix", &mut params.mix, MIX),
+            ]),
+            Effect::Split(params) => params
+                .branches
+                .iter_mut()
+                .enumerate()
+                .map(|(index, branch)| EffectParameter {
+                    name: format!("Branch {} Level", index + 1),
+                    param: &mut branch.level,
+                    range: 0.0..=2.0,
+                })
+                .collect(),
+            Effect::Send(params) => named(vec![("Level", &mut params.level, 0.0..=1.0)]),
+            Effect::Compressor(params) => named(vec![
+                ("Threshold", &mut params.threshold, -60.0..=0.0),
+                ("Ratio", &mut params.ratio, 1.0..=20.0),
+                ("Makeup", &mut params.makeup, 0.0..=24.0),
+            ]),
+            Effect::Expander(params) => named(vec![
+                ("Threshold", &mut params.threshold, -80.0..=0.0),
+                ("Ratio", &mut params.ratio, 1.0..=100.0),
+                ("Range", &mut params.range, 0.0..=96.0),
+            ]),
+            Effect::TransientShaper(params) => named(vec![
+                ("Attack", &mut params.attack, -1.0..=1.0),
+                ("Sustain", &mut params.sustain, -1.0..=1.0),
+                ("Output", &mut params.output, -24.0..=24.0),
+            ]),
+            Effect::Eq(params) => params
+                .bands
+                .iter_mut()
+                .enumerate()
+                .flat_map(|(index, band)| {
+                    [
+                        EffectParameter {
+                            name: format!("Band {} Frequency", index + 1),
+                            param: &mut band.frequency,
+                            range: EQ_MIN_FREQUENCY..=EQ_MAX_FREQUENCY,
+                        },
+                        EffectParameter {
+                            name: format!("Band {} Q", index + 1),
+                            param: &mut band.q,
+                            range: 0.1..=18.0,
+                        },
+                        EffectParameter {
+                            name: format!("Band {} Gain", index + 1),
+                            param: &mut band.gain,
+                            range: -EQ_MAX_GAIN..=EQ_MAX_GAIN,
+                        },
+                    ]
+                })
+                .collect(),
+        }
+    }
+
     fn new_delay(sample_rate: f32, delay_time: f32, feedback: f32, mix: f32) -> Self {
-        let buffer_size = (sample_rate * delay_time) as usize;
-        Effect::Delay(DelayParameters {
-            buffer: vec![0.0; buffer_size.max(1)],
-            position: 0,
-            delay_time,
-            feedback,
-            mix,
-        })
+        let mut effect = Effect::Delay(DelayParameters {
+            lines: Default::default(),
+            delay_time: SmoothedParam::new(delay_time),
+            sync: None,
+            glide: 0.2,
+            spread: SmoothedParam::new(0.0),
+            mode: DelayMode::Stereo,
+            feedback: SmoothedParam::new(feedback),
+            low_cut: SmoothedParam::new(20.0),
+            high_cut: SmoothedParam::new(20000.0),
+            filter_states: [[0.0; 2]; 2],
+            mix: SmoothedParam::new(mix),
+        });
+        effect.prepare(sample_rate);
+        effect
     }
 
-    fn new_distortion(drive: f32, mix: f32) -> Self {
-        Effect::Distortion { drive, mix }
+    fn new_distortion(sample_rate: f32, drive: f32, mix: f32) -> Self {
+        let mut effect = Effect::Distortion(distortion::DistortionParameters::new(drive, mix));
+        effect.prepare(sample_rate);
+        effect
     }
 
     fn new_filter(cutoff: f32, resonance: f32, mix: f32) -> Self {
         Effect::Filter(FilterParameters {
-            cutoff,
+            cutoff: SmoothedParam::new(cutoff),
             resonance,
-            mix,
-            prev_input: 0.0,
-            prev_output: 0.0,
+            mix: SmoothedParam::new(mix),
+            prev_input: [0.0; 2],
+            prev_output: [0.0; 2],
         })
     }
 
     fn new_tremolo(rate: f32, depth: f32, mix: f32) -> Self {
         Effect::Tremolo(TremoloParameters {
-            rate,
-            depth,
-            mix,
-            phase: 0.0,
+            lfo: modulation::Lfo::new(rate),
+            depth: SmoothedParam::new(depth),
+            mix: SmoothedParam::new(mix),
         })
     }
 
     fn new_chorus(sample_rate: f32, voices: usize, mix: f32) -> Self {
-        let max_delay_samples = (sample_rate * 0.030) as usize; // 30ms max delay
-        let mut buffers = Vec::new();
-        let mut positions = Vec::new();
-        let mut rates = Vec::new();
-        let mut depths = Vec::new();
-        let mut phases = Vec::new();
-
-        for i in 0..voices {
-            buffers.push(vec![0.0; max_delay_samples]);
-            positions.push(0);
-            // Slightly different rates for each voice
-            rates.push(0.5 + (i as f32 * 0.2));
-            depths.push(0.7);
-            phases.push(0.0);
-        }
-
-        Effect::Chorus(ChorusParameters {
-            buffers,
-            positions,
-            rates,
-            depths,
-            phases,
-            mix,
-        })
+        let mut effect = Effect::Chorus(modulation::ChorusParameters::new(voices, mix));
+        effect.prepare(sample_rate);
+        effect
+    }
+
+    fn new_flanger(sample_rate: f32, mix: f32) -> Self {
+        let mut effect = Effect::Flanger(modulation::FlangerParameters::new(mix));
+        effect.prepare(sample_rate);
+        effect
     }
 
     fn new_reverb(sample_rate: f32, room_size: f32, mix: f32) -> Self {
-        // Schroeder reverb implementation
-        let comb_delays = [
-            (0.0297 * room_size),
-            (0.0371 * room_size),
-            (0.0411 * room_size),
-            (0.0437 * room_size),
-        ];
-        let allpass_delays = [0.0050, 0.0017];
-
-        let mut comb_filters = Vec::new();
-        let mut comb_positions = Vec::new();
-        let mut allpass_filters = Vec::new();
-        let mut allpass_positions = Vec::new();
-
-        for delay in comb_delays.iter() {
-            let size = (sample_rate * delay) as usize;
-            comb_filters.push(vec![0.0; size]);
-            comb_positions.push(0);
-        }
-
-        for delay in allpass_delays.iter() {
-            let size = (sample_rate * delay) as usize;
-            allpass_filters.push(vec![0.0; size]);
-            allpass_positions.push(0);
-        }
-
-        Effect::Reverb(ReverbParameters {
-            comb_filters,
-            comb_positions,
-            allpass_filters,
-            allpass_positions,
-            feedback: 0.84,
-            mix,
-        })
+        let mut effect = Effect::Reverb(ReverbParameters {
+            comb_lines: Default::default(),
+            comb_damping: Default::default(),
+            allpass_lines: Default::default(),
+            input_line: DelayLine::default(),
+            size: SmoothedParam::new(room_size.clamp(0.1, MAX_REVERB_SIZE)),
+            decay: SmoothedParam::new(2.5),
+            damping: SmoothedParam::new(0.5),
+            pre_delay: SmoothedParam::new(0.01),
+            width: SmoothedParam::new(1.0),
+            early_level: SmoothedParam::new(0.5),
+            mix: SmoothedParam::new(mix),
+        });
+        effect.prepare(sample_rate);
+        effect
     }
 
     fn new_ring_mod(frequency: f32, mix: f32) -> Self {
         Effect::RingMod(RingModParameters {
-            frequency,
+            frequency: SmoothedParam::new(frequency),
             phase: 0.0,
-            mix,
+            mix: SmoothedParam::new(mix),
+        })
+    }
+
+    fn new_eq(sample_rate: f32) -> Self {
+        let mut effect = Effect::Eq(eq::EqParameters::new());
+        effect.prepare(sample_rate);
+        effect
+    }
+
+    fn new_split(mode: SplitMode) -> Self {
+        Effect::Split(SplitParameters::new(mode, 2))
+    }
+
+    fn new_send(bus: usize, level: f32) -> Self {
+        Effect::Send(SendParameters {
+            bus,
+            level: SmoothedParam::new(level),
         })
     }
 }
 
-// Simplified effect stack
-struct EffectStack {
-    effects: Vec<Effect>,
+// An effect in the stack together with its routing controls
+#[derive(Clone, Serialize, Deserialize)]
+struct EffectSlot {
+    effect: Effect,
+    bypass: bool,
+    // Crossfades between the processed and the bypassed signal
+    #[serde(skip, default = "SmoothedParam::default_active")]
+    active: SmoothedParam,
+    mix: SmoothedParam,
+    gain: SmoothedParam,
 }
 
-impl EffectStack {
-    fn new() -> Self {
-        Self { effects: Vec::new() }
+impl EffectSlot {
+    fn new(effect: Effect) -> Self {
+        Self {
+            effect,
+            bypass: false,
+            active: SmoothedParam::new(1.0),
+            mix: SmoothedParam::new(1.0),
+            gain: SmoothedParam::new(1.0),
+        }
     }
 
-    fn add_effect(&mut self, effect: Effect) {
-        self.effects.push(effect);
+    fn prepare(&mut self, sample_rate: f32) {
+        self.active = SmoothedParam::new(if self.bypass { 0.0 } else { 1.0 });
+        self.effect.prepare(sample_rate);
     }
 
-    fn process(&mut self, sample: f32, sample_rate: f32) -> f32 {
-        let mut processed = sample;
-        for effect in self.effects.iter_mut() {
-            processed = effect.process(processed, sample_rate);
+    fn process(&mut self, input: [f32; 2], context: &mut ProcessContext) -> [f32; 2] {
+        self.active.target = if self.bypass { 0.0 } else { 1.0 };
+        let active = self.active.next(context.smoothing);
+        let mix = self.mix.next(context.smoothing) * active;
+        let gain = self.gain.next(context.smoothing);
+        // Once faded out a bypassed effect stops processing, which also cuts its tail
+        if self.bypass && active < 1e-4 {
+            return input;
         }
-        processed
+        let wet = self.effect.process(input, context);
+        [0, 1].map(|channel| {
+            let output = input[channel] * (1.0 - mix) + wet[channel] * mix;
+            output * (1.0 - active + gain * active)
+        })
     }
+}
 
-    fn reset(&mut self) {
-        for effect in self.effects.iter_mut() {
-            effect.reset();
+#[derive(Clone, Copy)]
+enum SlotAction {
+    Remove,
+    Duplicate,
+    MoveTo(usize),
+    // Takes a branch out of a split
+    RemoveBranch(usize),
+}
+
+// Carries out an editor's action on the slot at `address`. The lock is only
+// held to read the slot's settings and to swap slots in and out; a copy's
+// buffers are made, and a removed slot's freed, without it.
+fn edit_slot(synth: &Mutex<Synth>, address: &EffectAddress, action: SlotAction) {
+    let Some((&index, parent)) = address.path.split_last() else {
+        return;
+    };
+    let parent = EffectAddress {
+        bus: address.bus,
+        path: parent.to_vec(),
+    };
+    let copy = match action {
+        // The copy is rebuilt from the settings a saved patch would keep
+        SlotAction::Duplicate => {
+            let (settings, sample_rate) = {
+                let mut synth = synth.lock().unwrap();
+                let settings = synth
+                    .stack_mut(address.bus)
+                    .and_then(|stack| stack.slot_mut(&address.path))
+                    .and_then(|slot| toml::Value::try_from(&*slot).ok());
+                (settings, synth.sample_rate)
+            };
+            let Some(mut copy) = settings.and_then(|settings| settings.try_into::<EffectSlot>().ok()) else {
+                return;
+            };
+            copy.prepare(sample_rate);
+            Some(copy)
         }
+        _ => None,
+    };
+    let (mut removed_slot, mut removed_branch) = (None, None);
+    {
+        let mut synth = synth.lock().unwrap();
+        let synth = &mut *synth;
+        // The stack or split whose children were renumbered, and the new
+        // number of each; `None` for the one taken out
+        let (edited, count) = match action {
+            SlotAction::RemoveBranch(branch) => {
+                let Some(Effect::Split(params)) = synth.effect_mut(address) else {
+                    return;
+                };
+                let count = params.branches.len();
+                if branch >= count || count < 2 {
+                    return;
+                }
+                removed_branch = Some(params.remove_branch(branch));
+                (address, count)
+            }
+            _ => {
+                let Some(stack) = synth.stack_mut(address.bus).and_then(|stack| stack.stack_mut(&parent.path)) else {
+                    return;
+                };
+                let count = stack.slots.len();
+                if index >= count {
+                    return;
+                }
+                match copy {
+                    Some(copy) => stack.insert(index + 1, copy),
+                    None => removed_slot = stack.apply(index, action),
+                }
+                (&parent, count)
+            }
+        };
+        let take_out = |child: usize, removed: usize| match child.cmp(&removed) {
+            std::cmp::Ordering::Less => Some(child),
+            std::cmp::Ordering::Equal => None,
+            std::cmp::Ordering::Greater => Some(child - 1),
+        };
+        let renumber = |child: usize| match action {
+            SlotAction::Remove => take_out(child, index),
+            SlotAction::RemoveBranch(branch) => take_out(child, branch),
+            SlotAction::Duplicate => Some(child + usize::from(child > index)),
+            SlotAction::MoveTo(target) => {
+                let target = target.min(count - 1);
+                if child == index {
+                    return Some(target);
+                }
+                let child = child - usize::from(child > index);
+                Some(child + usize::from(child >= target))
+            }
+        };
+        // Parameter locks follow their effects to where they now sit
+        synth.sequencer.remap_effects(|target| target.renumber(edited, renumber));
     }
+    drop(removed_slot);
+    drop(removed_branch);
 }
 
+// Simplified effect stack
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+struct EffectStack {
+    slots: Vec<EffectSlot>,
+}
 
-#[derive(Clone, Copy, PartialEq)]
-enum Waveform {
+impl EffectStack {
+    fn new() -> Self {
+        Self { slots: Vec::new() }
+    }
+
+    fn add_effect(&mut self, effect: Effect) {
+        self.slots.push(EffectSlot::new(effect));
+    }
+
+    // Moves or removes a slot, handing a removed one back so it can be
+    // freed outside the audio lock. Duplicates are made by `edit_slot` and
+    // put in with `insert`.
+    fn apply(&mut self, index: usize, action: SlotAction) -> Option<EffectSlot> {
+        if index >= self.slots.len() {
+            return None;
+        }
+        match action {
+            SlotAction::Remove => return Some(self.slots.remove(index)),
+            SlotAction::Duplicate | SlotAction::RemoveBranch(_) => {}
+            SlotAction::MoveTo(target) => {
+                let slot = self.slots.remove(index);
+                self.slots.insert(target.min(self.slots.len()), slot);
+            }
+        }
+        None
+    }
+
+    fn insert(&mut self, index: usize, slot: EffectSlot) {
+        self.slots.insert(index.min(self.slots.len()), slot);
+    }
+
+    fn process(&mut self, frame: [f32; 2], context: &mut ProcessContext) -> [f32; 2] {
+        let mut processed = frame;
+        for slot in self.slots.iter_mut() {
+            processed = slot.process(processed, context);
+        }
+        processed
+    }
+
+    // Silences delay lines and reverb tails but keeps every parameter
+    fn reset(&mut self) {
+        for slot in self.slots.iter_mut() {
+            slot.effect.reset();
+        }
+    }
+
+    fn prepare(&mut self, sample_rate: f32) {
+        for slot in self.slots.iter_mut() {
+            slot.prepare(sample_rate);
+        }
+    }
+
+    // Calls `visit` with every slot and its address, split branches included
+    fn for_each_slot(&mut self, address: &EffectAddress, visit: &mut dyn FnMut(&EffectAddress, &mut EffectSlot)) {
+        for (index, slot) in self.slots.iter_mut().enumerate() {
+            let slot_address = address.child(index);
+            visit(&slot_address, slot);
+            if let Effect::Split(params) = &mut slot.effect {
+                for (branch_index, branch) in params.branches.iter_mut().enumerate() {
+                    branch.effects.for_each_slot(&slot_address.child(branch_index), visit);
+                }
+            }
+        }
+    }
+
+    // Finds a nested stack from alternating slot and branch indices; the
+    // empty path is this stack
+    fn stack_mut(&mut self, path: &[usize]) -> Option<&mut EffectStack> {
+        match path {
+            [] => Some(self),
+            [slot, branch, rest @ ..] => match &mut self.slots.get_mut(*slot)?.effect {
+                Effect::Split(params) => params.branches.get_mut(*branch)?.effects.stack_mut(rest),
+                _ => None,
+            },
+            [_] => None,
+        }
+    }
+
+    // Finds a slot by index, descending into split branches as alternating
+    // branch and slot indices
+    fn slot_mut(&mut self, path: &[usize]) -> Option<&mut EffectSlot> {
+        let (index, parent) = path.split_last()?;
+        self.stack_mut(parent)?.slots.get_mut(*index)
+    }
+
+    fn effect_mut(&mut self, path: &[usize]) -> Option<&mut Effect> {
+        self.slot_mut(path).map(|slot| &mut slot.effect)
+    }
+}
+
+// A shared effect chain fed from the voice sum and from send effects, mixed
+// back into the output after the main chain
+#[derive(Clone, Serialize, Deserialize)]
+struct AuxBus {
+    name: String,
+    voice_send: SmoothedParam,
+    effects: EffectStack,
+    return_level: SmoothedParam,
+}
+
+// Where an effect lives: the main chain or an aux bus, then a path as
+// taken by `EffectStack::effect_mut`
+#[derive(Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
+struct EffectAddress {
+    bus: Option<usize>,
+    path: Vec<usize>,
+}
+
+impl EffectAddress {
+    fn child(&self, index: usize) -> Self {
+        let mut path = self.path.clone();
+        path.push(index);
+        Self {
+            bus: self.bus,
+            path,
+        }
+    }
+
+    // Follows the effect here when the children of `parent`, a stack or a
+    // split, are renumbered by `renumber`. False once it has been removed.
+    fn renumber(&mut self, parent: &EffectAddress, renumber: impl Fn(usize) -> Option<usize>) -> bool {
+        if self.bus != parent.bus || !self.path.starts_with(&parent.path) {
+            return true;
+        }
+        let Some(child) = self.path.get_mut(parent.path.len()) else {
+            return true;
+        };
+        match renumber(*child) {
+            Some(renumbered) => {
+                *child = renumbered;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl AuxBus {
+    fn new(name: String) -> Self {
+        Self {
+            name,
+            voice_send: SmoothedParam::new(0.0),
+            effects: EffectStack::new(),
+            return_level: SmoothedParam::new(1.0),
+        }
+    }
+}
+
+
+#[derive(Clone, Copy, PartialEq)]
+enum Waveform {
     Sine,
     Square,
     Sawtooth,
@@ -347,10 +1479,137 @@ enum Waveform {
     },
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct VoiceKey {
+    channel: u8,
+    note: u8,
+}
+
+// Per-note expression as received on an MPE member channel
+#[derive(Clone, Copy)]
+struct NoteExpression {
+    bend: f32,
+    pressure: f32,
+    slide: f32,
+}
+
+impl Default for NoteExpression {
+    // Full pressure until the controller sends some, so the amplitude
+    // route doesn't mute notes from keyboards without aftertouch
+    fn default() -> Self {
+        Self {
+            bend: 0.0,
+            pressure: 1.0,
+            slide: 0.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ExpressionTarget {
+    Off,
+    Pitch,
+    Amplitude,
+    Filter,
+}
+
+// Amount is in semitones for pitch, octaves for the filter and a 0-1 depth for amplitude
+#[derive(Clone, Copy)]
+struct ExpressionRoute {
+    target: ExpressionTarget,
+    amount: f32,
+}
+
+struct MpeSettings {
+    // Member channel counts; zero disables the zone
+    lower_zone: u8,
+    upper_zone: u8,
+    note_bend_range: f32,
+    pressure: ExpressionRoute,
+    slide: ExpressionRoute,
+}
+
+impl MpeSettings {
+    fn new() -> Self {
+        Self {
+            lower_zone: 0,
+            upper_zone: 0,
+            note_bend_range: 48.0,
+            pressure: ExpressionRoute {
+                target: ExpressionTarget::Amplitude,
+                amount: 1.0,
+            },
+            slide: ExpressionRoute {
+                target: ExpressionTarget::Filter,
+                amount: 4.0,
+            },
+        }
+    }
+
+    fn is_member(&self, channel: u8) -> bool {
+        let lower = channel >= 1 && channel <= self.lower_zone;
+        let upper = channel < 15 && channel + self.upper_zone >= 15;
+        lower || upper
+    }
+
+    // MPE configuration message: the zone size is set from its master channel,
+    // and the other zone shrinks if the two would overlap
+    fn configure_zone(&mut self, master: u8, members: u8) {
+        let members = members.min(15);
+        match master {
+            0 => {
+                self.lower_zone = members;
+                self.upper_zone = self.upper_zone.min(14 - members.min(14));
+            }
+            15 => {
+                self.upper_zone = members;
+                self.lower_zone = self.lower_zone.min(14 - members.min(14));
+            }
+            _ => {}
+        }
+    }
+
+    // Returns the pitch offset in semitones, the gain and the filter offset in octaves
+    fn modulation(&self, expression: &NoteExpression) -> (f32, f32, f32) {
+        let mut semitones = expression.bend * self.note_bend_range;
+        let mut gain = 1.0;
+        let mut octaves = 0.0;
+        for (route, value) in [
+            (self.pressure, expression.pressure),
+            (self.slide, expression.slide),
+        ] {
+            match route.target {
+                ExpressionTarget::Off => {}
+                ExpressionTarget::Pitch => semitones += route.amount * value,
+                ExpressionTarget::Amplitude => {
+                    gain *= (1.0 + route.amount * (value - 1.0)).max(0.0)
+                }
+                ExpressionTarget::Filter => octaves += route.amount * value,
+            }
+        }
+        (semitones, gain, octaves)
+    }
+}
+
+// An automatable parameter as listed for editors
+struct ParameterInfo {
+    target: sequencer::ParameterTarget,
+    label: String,
+    value: f32,
+    range: RangeInclusive<f32>,
+}
+
 struct Synth {
-    voices: HashMap<u8, Voice>,
+    voices: HashMap<VoiceKey, Voice>,
     sample_rate: f32,
+    // Wheel position in -1.0..=1.0, scaled by the bend ranges in semitones
     pitch_bend: f32,
+    bend_range_up: f32,
+    bend_range_down: f32,
+    bend_multiplier: SmoothedParam,
+    // Glide time in seconds applied to every continuously variable parameter
+    smoothing_time: f32,
+    transport: transport::Transport,
     waveform: Waveform,
     attack: f32,
     decay: f32,
@@ -365,6 +1624,29 @@ struct Synth {
     num_harmonics: usize,
     harmonic_weights: [f32; 16],
     effects:EffectStack,
+    buses: Vec<AuxBus>,
+    sustain_pedal: bool,
+    sostenuto_pedal: bool,
+    filter_cutoff: SmoothedParam,
+    mpe: MpeSettings,
+    channel_expression: [NoteExpression; 16],
+    chords: chords::ChordMemory,
+    arpeggiator: arpeggiator::Arpeggiator,
+    // Reused buffer for the arpeggiator's note events
+    note_events: Vec<arpeggiator::NoteEvent>,
+    sequencer: sequencer::Sequencer,
+    // Peak output level per channel, falling back slowly, for meters
+    output_level: [f32; 2],
+    // Seconds of audio produced, which envelopes are timed against so
+    // rendering faster than real time sounds the same
+    clock: f64,
+    // Voice sum of the last frame before any effects, for recording
+    voice_sum: f32,
+    recorder: recorder::Recorder,
+    // Performance being recorded as MIDI, and a MIDI file playing back
+    midi_capture: Option<midi::Capture>,
+    midi_player: Option<midi::Player>,
+    looper: looper::Looper,
 }
 
 struct Voice {
@@ -373,8 +1655,16 @@ struct Voice {
     envelope: Envelope,
     frequency_envelope: FrequencyEnvelope,
     phase: f32,
-    pitch_bend: f32,
     harmonic_phases: [f32; 16],
+    key_held: bool,
+    sostenuto: bool,
+    expression: NoteExpression,
+    // Smoothed outputs of the expression routing
+    pitch_mod: SmoothedParam,
+    gain_mod: SmoothedParam,
+    filter_mod: SmoothedParam,
+    filter_state: f32,
+    velocity: f32,
 }
 
 struct Envelope {
@@ -382,8 +1672,12 @@ struct Envelope {
     decay: f32,
     sustain: f32,
     release: f32,
-    start_time: Option<Instant>,
-    release_time: Option<Instant>,
+    // Level the attack rises from, above zero when a sounding voice is
+    // struck again
+    start_level: f32,
+    // Synth clock times, in seconds
+    start_time: Option<f64>,
+    release_time: Option<f64>,
     is_released: bool,
 }
 
@@ -391,8 +1685,9 @@ struct FrequencyEnvelope {
     attack: f32,
     decay: f32,
     release: f32,
-    start_time: Option<Instant>,
-    release_time: Option<Instant>,
+    // Synth clock times, in seconds
+    start_time: Option<f64>,
+    release_time: Option<f64>,
     is_released: bool,
     start_freq: f32,
     peak_freq: f32,
@@ -421,13 +1716,13 @@ impl FrequencyEnvelope {
         }
     }
 
-    fn get_frequency_multiplier(&self) -> f32 {
+    fn get_frequency_multiplier(&self, now: f64) -> f32 {
         if let Some(start_time) = self.start_time {
-            let elapsed = start_time.elapsed().as_secs_f32();
+            let elapsed = (now - start_time) as f32;
 
             if self.is_released {
                 if let Some(release_time) = self.release_time {
-                    let release_elapsed = release_time.elapsed().as_secs_f32();
+                    let release_elapsed = (now - release_time) as f32;
                     return if release_elapsed >= self.release {
                         1.0 // Return to base frequency
                     } else {
@@ -468,19 +1763,20 @@ impl Envelope {
             decay,
             sustain,
             release,
+            start_level: 0.0,
             start_time: None,
             release_time: None,
             is_released: false,
         }
     }
 
-    fn get_amplitude(&self) -> f32 {
+    fn get_amplitude(&self, now: f64) -> f32 {
         if let Some(start_time) = self.start_time {
-            let elapsed = start_time.elapsed().as_secs_f32();
+            let elapsed = (now - start_time) as f32;
 
             if self.is_released {
                 if let Some(release_time) = self.release_time {
-                    let release_elapsed = release_time.elapsed().as_secs_f32();
+                    let release_elapsed = (now - release_time) as f32;
                     return if release_elapsed >= self.release {
                         0.0
                     } else {
@@ -490,7 +1786,7 @@ impl Envelope {
             }
 
             if elapsed < self.attack {
-                elapsed / self.attack
+                self.start_level + (1.0 - self.start_level) * elapsed / self.attack
             } else if elapsed < self.attack + self.decay {
                 1.0 - (1.0 - self.sustain) * (elapsed - self.attack) / self.decay
             } else {
@@ -503,13 +1799,23 @@ impl Envelope {
 }
 
 impl Voice {
-    fn get_sample(&mut self, sample_rate: f32) -> f32 {
-        let base_frequency = self.frequency * self.pitch_bend;
-        let freq_multiplier = self.frequency_envelope.get_frequency_multiplier();
+    fn release(&mut self, now: f64) {
+        if self.envelope.is_released {
+            return;
+        }
+        self.envelope.is_released = true;
+        self.envelope.release_time = Some(now);
+        self.frequency_envelope.is_released = true;
+        self.frequency_envelope.release_time = Some(now);
+    }
+
+    fn get_sample(&mut self, sample_rate: f32, pitch_bend: f32, cutoff: f32, now: f64) -> f32 {
+        let base_frequency = self.frequency * pitch_bend;
+        let freq_multiplier = self.frequency_envelope.get_frequency_multiplier(now);
         let current_frequency = base_frequency * freq_multiplier;
 
         let phase_step = current_frequency * 2.0 * PI / sample_rate;
-        let amplitude = self.envelope.get_amplitude();
+        let amplitude = self.envelope.get_amplitude(now);
 
         let sample = match self.waveform {
             Waveform::Sine => self.phase.sin(),
@@ -553,7 +1859,16 @@ impl Voice {
         };
 
         self.phase = (self.phase + phase_step) % (2.0 * PI);
-        sample * amplitude
+
+        // The voice filter is fully open at the top of its range
+        let filtered = if cutoff >= MAX_FILTER_CUTOFF {
+            sample
+        } else {
+            let alpha = one_pole_coefficient(cutoff, sample_rate);
+            self.filter_state += alpha * (sample - self.filter_state);
+            self.filter_state
+        };
+        filtered * amplitude
     }
 }
 
@@ -562,7 +1877,12 @@ impl Synth {
         Self {
             voices: HashMap::new(),
             sample_rate,
-            pitch_bend: 1.0,
+            pitch_bend: 0.0,
+            bend_range_up: 2.0,
+            bend_range_down: 2.0,
+            bend_multiplier: SmoothedParam::new(1.0),
+            smoothing_time: 0.01,
+            transport: transport::Transport::new(),
             waveform: Waveform::Sine,
             attack: 0.1,
             decay: 0.1,
@@ -580,14 +1900,124 @@ impl Synth {
             ],
             num_harmonics: 8,
             effects:EffectStack::new(),
+            buses: Vec::new(),
+            sustain_pedal: false,
+            sostenuto_pedal: false,
+            filter_cutoff: SmoothedParam::new(MAX_FILTER_CUTOFF),
+            mpe: MpeSettings::new(),
+            channel_expression: [NoteExpression::default(); 16],
+            chords: chords::ChordMemory::new(chords::builtin()),
+            arpeggiator: arpeggiator::Arpeggiator::new(),
+            note_events: Vec::with_capacity(arpeggiator::MAX_PATTERN_NOTES),
+            sequencer: sequencer::Sequencer::new(),
+            output_level: [0.0; 2],
+            clock: 0.0,
+            voice_sum: 0.0,
+            recorder: recorder::Recorder::default(),
+            midi_capture: None,
+            midi_player: None,
+            looper: looper::Looper::new(),
         }
     }
 
+    // Notes and the sustain pedal played on the computer keyboard or the
+    // screen, recorded as if they came in on channel 1
     fn note_on(&mut self, note: u8) {
-        if self.voices.contains_key(&note) && !self.voices[&note].envelope.is_released {
+        self.capture(&[0x90, note, 127]);
+        self.channel_note_on(0, note);
+    }
+
+    fn note_off(&mut self, note: u8) {
+        self.capture(&[0x80, note, 0]);
+        self.channel_note_off(0, note);
+    }
+
+    fn sustain(&mut self, down: bool) {
+        self.capture(&[0xB0, 64, if down { 127 } else { 0 }]);
+        self.set_sustain_pedal(down);
+    }
+
+    fn capture(&mut self, message: &[u8]) {
+        if let Some(capture) = &mut self.midi_capture {
+            capture.push(self.clock, message);
+        }
+    }
+
+    // The player is handed back so the caller can drop it outside the lock
+    fn stop_midi_player(&mut self) -> Option<midi::Player> {
+        let mut player = self.midi_player.take()?;
+        player.stop(self);
+        Some(player)
+    }
+
+    // Keys pass through chord memory, then go to the arpeggiator when it
+    // is on, otherwise straight to a voice
+    fn channel_note_on(&mut self, channel: u8, note: u8) {
+        for note in self.chords.press(channel, note) {
+            if self.arpeggiator.enabled {
+                self.arpeggiator.key_down(note);
+            } else {
+                self.start_voice(channel, note, 1.0);
+            }
+        }
+    }
+
+    // A key goes up the way it went down, so one held while the
+    // arpeggiator was switched on or off still lets go of its voice
+    fn channel_note_off(&mut self, channel: u8, note: u8) {
+        for note in self.chords.release(channel, note) {
+            if self.arpeggiator.holds(note) {
+                self.arpeggiator.key_up(note);
+            } else {
+                self.stop_voice(channel, note);
+            }
+        }
+    }
+
+    fn set_arpeggiator_enabled(&mut self, enabled: bool) {
+        if self.arpeggiator.enabled == enabled {
             return;
         }
-        let frequency = 440.0 * 2.0f32.powf((note as f32) / 12.0);
+        self.arpeggiator.enabled = enabled;
+        let mut events = std::mem::take(&mut self.note_events);
+        self.arpeggiator.clear(&mut events);
+        self.play_events(&mut events);
+        self.note_events = events;
+    }
+
+    fn play_events(&mut self, events: &mut Vec<arpeggiator::NoteEvent>) {
+        for event in events.drain(..) {
+            match event {
+                arpeggiator::NoteEvent::On { note, velocity } => {
+                    self.start_voice(arpeggiator::ARPEGGIATOR_CHANNEL, note, velocity)
+                }
+                arpeggiator::NoteEvent::Off { note } => self.stop_voice(arpeggiator::ARPEGGIATOR_CHANNEL, note),
+            }
+        }
+    }
+
+    fn start_voice(&mut self, channel: u8, note: u8, velocity: f32) {
+        let key = VoiceKey { channel, note };
+        // A voice that is only ringing because of a pedal is retriggered,
+        // keeping its sostenuto latch so the pedal still holds the new strike.
+        // The new strike carries on the old one's phase and rises from its
+        // level, so the swap doesn't click.
+        let mut sostenuto = false;
+        let mut carried = None;
+        if let Some(voice) = self.voices.get(&key) {
+            if voice.key_held {
+                return;
+            }
+            sostenuto = voice.sostenuto && !voice.envelope.is_released;
+            // In terms of the new strike's velocity, so the output level
+            // stays where it is
+            let level = voice.envelope.get_amplitude(self.clock) * voice.velocity / velocity.max(1e-3);
+            carried = Some((level, voice.phase, voice.harmonic_phases, voice.filter_state));
+        }
+        let frequency = note_to_frequency(note);
+        // Channels past the 16 MIDI ones carry no expression of their own
+        let expression = self.channel_expression.get(channel as usize).copied().unwrap_or_default();
+        let (semitones, gain, octaves) = self.mpe.modulation(&expression);
         let waveform = match self.waveform {
             Waveform::Additive { .. } => Waveform::Additive {
                 num_harmonics: self.num_harmonics,
@@ -608,29 +2038,291 @@ impl Synth {
                 frequency * self.freq_sustain_mult,
             ),
             phase: 0.0,
-            pitch_bend: self.pitch_bend,
             harmonic_phases: [0.0; 16],
+            key_held: true,
+            sostenuto,
+            // MPE controllers send the initial expression before the note on
+            expression,
+            pitch_mod: SmoothedParam::new(semitones),
+            gain_mod: SmoothedParam::new(gain),
+            filter_mod: SmoothedParam::new(octaves),
+            filter_state: 0.0,
+            velocity,
         };
-        voice.envelope.start_time = Some(Instant::now());
-        voice.frequency_envelope.start_time = Some(Instant::now());
+        voice.envelope.start_time = Some(self.clock);
+        voice.frequency_envelope.start_time = Some(self.clock);
+        if let Some((level, phase, harmonic_phases, filter_state)) = carried {
+            voice.envelope.start_level = level;
+            voice.phase = phase;
+            voice.harmonic_phases = harmonic_phases;
+            voice.filter_state = filter_state;
+        }
 
-        self.voices.insert(note, voice);
+        self.voices.insert(key, voice);
     }
 
-    fn note_off(&mut self, note: u8) {
-        if let Some(voice) = self.voices.get_mut(&note) {
-            voice.envelope.is_released = true;
-            voice.envelope.release_time = Some(Instant::now());
-            voice.frequency_envelope.is_released = true;
-            voice.frequency_envelope.release_time = Some(Instant::now());
+    fn stop_voice(&mut self, channel: u8, note: u8) {
+        if let Some(voice) = self.voices.get_mut(&VoiceKey { channel, note }) {
+            voice.key_held = false;
+            if !self.sustain_pedal && !voice.sostenuto {
+                voice.release(self.clock);
+            }
+        }
+    }
+
+    fn set_sustain_pedal(&mut self, down: bool) {
+        if self.sustain_pedal == down {
+            return;
+        }
+        self.sustain_pedal = down;
+        if !down {
+            for voice in self.voices.values_mut() {
+                if !voice.key_held && !voice.sostenuto {
+                    voice.release(self.clock);
+                }
+            }
+        }
+    }
+
+    fn set_sostenuto_pedal(&mut self, down: bool) {
+        if self.sostenuto_pedal == down {
+            return;
+        }
+        self.sostenuto_pedal = down;
+        for voice in self.voices.values_mut() {
+            if down {
+                // Only notes held at the moment the pedal goes down are latched
+                voice.sostenuto = voice.key_held && !voice.envelope.is_released;
+            } else if voice.sostenuto {
+                voice.sostenuto = false;
+                if !voice.key_held && !self.sustain_pedal {
+                    voice.release(self.clock);
+                }
+            }
+        }
+    }
+
+    fn pitch_bend_semitones(&self) -> f32 {
+        if self.pitch_bend >= 0.0 {
+            self.pitch_bend * self.bend_range_up
+        } else {
+            self.pitch_bend * self.bend_range_down
+        }
+    }
+
+    // 14-bit MIDI pitch wheel value, centred on 8192. On an MPE member
+    // channel it bends only the notes on that channel.
+    fn pitch_wheel(&mut self, channel: u8, value: u16) {
+        let offset = value as f32 - 8192.0;
+        let bend = if offset >= 0.0 {
+            offset / 8191.0
+        } else {
+            offset / 8192.0
+        };
+        if self.mpe.is_member(channel) {
+            self.update_expression(channel, |expression| expression.bend = bend);
+        } else {
+            self.pitch_bend = bend;
+        }
+    }
+
+    // Pressure and slide only shape notes on MPE member channels; elsewhere
+    // a keyboard resting at zero aftertouch would mute its notes
+    fn channel_pressure(&mut self, channel: u8, value: u8) {
+        if self.mpe.is_member(channel) {
+            let pressure = value as f32 / 127.0;
+            self.update_expression(channel, |expression| expression.pressure = pressure);
+        }
+    }
+
+    fn poly_pressure(&mut self, channel: u8, note: u8, value: u8) {
+        if !self.mpe.is_member(channel) {
+            return;
+        }
+        if let Some(voice) = self.voices.get_mut(&VoiceKey { channel, note }) {
+            voice.expression.pressure = value as f32 / 127.0;
+        }
+    }
+
+    fn update_expression(&mut self, channel: u8, update: impl Fn(&mut NoteExpression)) {
+        update(&mut self.channel_expression[channel as usize]);
+        for (key, voice) in self.voices.iter_mut() {
+            if key.channel == channel && !voice.envelope.is_released {
+                update(&mut voice.expression);
+            }
+        }
+    }
+
+    // RPN 0 on an MPE member channel sets the per-note range, otherwise the wheel range
+    fn set_bend_sensitivity(&mut self, channel: u8, semitones: f32) {
+        if self.mpe.is_member(channel) {
+            self.mpe.note_bend_range = semitones;
+        } else {
+            self.bend_range_up = semitones;
+            self.bend_range_down = semitones;
+        }
+    }
+
+    fn control_change(&mut self, channel: u8, controller: u8, value: u8) {
+        match controller {
+            64 => self.set_sustain_pedal(value >= 64),
+            66 => self.set_sostenuto_pedal(value >= 64),
+            74 if self.mpe.is_member(channel) => {
+                let slide = value as f32 / 127.0;
+                self.update_expression(channel, |expression| expression.slide = slide);
+            }
+            _ => {}
+        }
+    }
+
+    // Swaps in a loaded patch, keeping whether the sequencer is on
+    fn load_patch(&mut self, mut patch: patch::Patch) {
+        // Locks are handed back before the effects they point at go
+        let mut sequencer = std::mem::take(&mut self.sequencer);
+        sequencer.stop(self);
+        patch.sequencer.enabled = sequencer.enabled;
+        self.sequencer = patch.sequencer;
+        self.effects = patch.effects;
+        self.buses = patch.buses;
+    }
+
+    // Takes out a bus and moves sends to the buses after it down by one,
+    // leaving sends to the removed bus without one. The bus is handed back
+    // so its effects can be freed outside the audio lock.
+    fn remove_bus(&mut self, index: usize) -> Option<AuxBus> {
+        if index >= self.buses.len() {
+            return None;
+        }
+        let removed = self.buses.remove(index);
+        let stacks = std::iter::once((None, &mut self.effects))
+            .chain(self.buses.iter_mut().enumerate().map(|(bus, aux)| (Some(bus), &mut aux.effects)));
+        for (bus, stack) in stacks {
+            let root = EffectAddress { bus, path: Vec::new() };
+            stack.for_each_slot(&root, &mut |_, slot| {
+                if let Effect::Send(params) = &mut slot.effect {
+                    if params.bus == index {
+                        params.bus = MAX_AUX_BUSES;
+                    } else if params.bus > index && params.bus < MAX_AUX_BUSES {
+                        params.bus -= 1;
+                    }
+                }
+            });
+        }
+        self.sequencer.remap_effects(|address| match address.bus {
+            Some(bus) if bus == index => false,
+            Some(bus) if bus > index => {
+                address.bus = Some(bus - 1);
+                true
+            }
+            _ => true,
+        });
+        Some(removed)
+    }
+
+    // The main chain, or an aux bus's chain
+    fn stack_mut(&mut self, bus: Option<usize>) -> Option<&mut EffectStack> {
+        match bus {
+            Some(bus) => Some(&mut self.buses.get_mut(bus)?.effects),
+            None => Some(&mut self.effects),
+        }
+    }
+
+    fn effect_mut(&mut self, address: &EffectAddress) -> Option<&mut Effect> {
+        self.stack_mut(address.bus)?.effect_mut(&address.path)
+    }
+
+    fn parameter_mut(&mut self, target: &sequencer::ParameterTarget) -> Option<&mut f32> {
+        use sequencer::{ParameterTarget, SynthParameter};
+        match target {
+            ParameterTarget::Synth(parameter) => Some(match parameter {
+                SynthParameter::FilterCutoff => &mut self.filter_cutoff.target,
+                SynthParameter::Attack => &mut self.attack,
+                SynthParameter::Decay => &mut self.decay,
+                SynthParameter::Sustain => &mut self.sustain,
+                SynthParameter::Release => &mut self.release,
+                SynthParameter::PitchBend => &mut self.pitch_bend,
+            }),
+            ParameterTarget::Effect { address, name } => self
+                .effect_mut(address)?
+                .parameters_mut()
+                .into_iter()
+                .find(|parameter| parameter.name == *name)
+                .map(|parameter| &mut parameter.param.target),
+        }
+    }
+
+    // Every parameter that can be automated
+    fn parameter_targets(&mut self) -> Vec<ParameterInfo> {
+        use sequencer::{ParameterTarget, SynthParameter};
+        let mut targets: Vec<_> = SynthParameter::ALL
+            .into_iter()
+            .map(|parameter| {
+                let target = ParameterTarget::Synth(parameter);
+                let value = self.parameter_mut(&target).map_or(0.0, |value| *value);
+                ParameterInfo {
+                    target,
+                    label: parameter.label().to_string(),
+                    value,
+                    range: parameter.range(),
+                }
+            })
+            .collect();
+        let bus_names: Vec<String> = self.buses.iter().map(|bus| bus.name.clone()).collect();
+        let stacks = std::iter::once((None, &mut self.effects))
+            .chain(self.buses.iter_mut().enumerate().map(|(index, bus)| (Some(index), &mut bus.effects)));
+        for (bus, stack) in stacks {
+            let root = EffectAddress { bus, path: Vec::new() };
+            stack.for_each_slot(&root, &mut |address, slot| {
+                let effect = &mut slot.effect;
+                let location = match address.bus {
+                    Some(bus) => format!("{} ", bus_names[bus]),
+                    None => String::new(),
+                };
+                let slots: Vec<String> = address.path.iter().map(|index| (index + 1).to_string()).collect();
+                let effect_label = format!("{}{} {}", location, slots.join("."), effect.name());
+                for parameter in effect.parameters_mut() {
+                    targets.push(ParameterInfo {
+                        label: format!("{}: {}", effect_label, parameter.name),
+                        value: parameter.param.target,
+                        range: parameter.range,
+                        target: ParameterTarget::Effect {
+                            address: address.clone(),
+                            name: parameter.name,
+                        },
+                    });
+                }
+            });
         }
+        targets
     }
 
-    fn get_next_sample(&mut self) -> f32 {
+    fn get_next_frame(&mut self) -> [f32; 2] {
+        let smoothing = smoothing_coefficient(self.smoothing_time, self.sample_rate);
+        self.bend_multiplier.target = 2.0f32.powf(self.pitch_bend_semitones() / 12.0);
+        let pitch_bend = self.bend_multiplier.next(smoothing);
+        let filter_cutoff = self.filter_cutoff.next(smoothing);
+
+        // Taken out for the call so it can play voices and set parameters
+        let mut sequencer = std::mem::take(&mut self.sequencer);
+        sequencer.process(self);
+        self.sequencer = sequencer;
+        if let Some(mut player) = self.midi_player.take() {
+            if player.process(self) {
+                self.midi_player = Some(player);
+            }
+        }
+
+        let mut events = std::mem::take(&mut self.note_events);
+        let position = self.transport.playing.then_some(self.transport.position);
+        self.arpeggiator
+            .process(position, self.transport.tempo, self.sample_rate, &mut events);
+        self.play_events(&mut events);
+        self.note_events = events;
+
+        let now = self.clock;
         self.voices.retain(|_, voice| {
             !voice.envelope.is_released
-                || voice.envelope.release_time.unwrap().elapsed().as_secs_f32()
-                    < voice.envelope.release
+                || ((now - voice.envelope.release_time.unwrap()) as f32) < voice.envelope.release
         });
 
         let ret = if self.voices.is_empty() {
@@ -638,68 +2330,214 @@ impl Synth {
         } else {
             self.voices
                 .values_mut()
-                .map(|voice| voice.get_sample(self.sample_rate))
+                .map(|voice| {
+                    let (semitones, gain, octaves) = self.mpe.modulation(&voice.expression);
+                    voice.pitch_mod.target = semitones;
+                    voice.gain_mod.target = gain;
+                    voice.filter_mod.target = octaves;
+                    let bend = pitch_bend * 2.0f32.powf(voice.pitch_mod.next(smoothing) / 12.0);
+                    let cutoff = (filter_cutoff * 2.0f32.powf(voice.filter_mod.next(smoothing)))
+                        .min(MAX_FILTER_CUTOFF);
+                    voice.get_sample(self.sample_rate, bend, cutoff, now)
+                        * voice.gain_mod.next(smoothing)
+                        * voice.velocity
+                })
                 .sum::<f32>()
                 / self.voices.len() as f32
         };
 
-            self.effects.process(ret,self.sample_rate)
+        self.voice_sum = ret;
+        self.clock += 1.0 / self.sample_rate as f64;
+        self.transport.advance(self.sample_rate);
+        let mut context = ProcessContext {
+            sample_rate: self.sample_rate,
+            smoothing,
+            tempo: self.transport.tempo,
+            position: self.transport.playing.then_some(self.transport.position),
+            sends: [[0.0; 2]; MAX_AUX_BUSES],
+            sidechain: [ret, ret],
+        };
+        for (bus, send) in self.buses.iter_mut().zip(context.sends.iter_mut()) {
+            let level = bus.voice_send.next(smoothing);
+            *send = [ret * level; 2];
+        }
+        let mut output = self.effects.process([ret, ret], &mut context);
+
+        // Buses run in order, so a bus can only send on to the ones after it
+        for (index, bus) in self.buses.iter_mut().enumerate() {
+            let Some(&input) = context.sends.get(index) else {
+                break;
+            };
+            let returned = bus.effects.process(input, &mut context);
+            let level = bus.return_level.next(smoothing);
+            output[0] += returned[0] * level;
+            output[1] += returned[1] * level;
+        }
+        let output = self.looper.process(output, context.position, smoothing);
+
+        let fall = (-1.0 / (METER_FALL_TIME * self.sample_rate)).exp();
+        for (level, sample) in self.output_level.iter_mut().zip(output) {
+            *level = sample.abs().max(*level * fall);
+        }
+        output
     }
 }
 
-struct SynthApp {
-    synth: Arc<Mutex<Synth>>,
-    _stream: Stream,
-    key_map: HashMap<egui::Key, u8>,
+const MAX_FILTER_CUTOFF: f32 = 20000.0;
+const MAX_DELAY_TIME: f32 = 4.0;
+const MAX_AUX_BUSES: usize = 4;
+// Seconds for the output meter to fall by a factor of e
+const METER_FALL_TIME: f32 = 0.3;
+
+fn note_to_frequency(note: u8) -> f32 {
+    440.0 * 2.0f32.powf((note as f32 - 69.0) / 12.0)
 }
 
-impl SynthApp {
-    fn new(_cc: &eframe::CreationContext<'_>) -> Self {
-        let host = cpal::default_host();
-        let device = host.default_output_device().expect("no output device");
-        let config = device.default_output_config().unwrap();
-        let sample_rate = config.sample_rate().0 as f32;
-
-        let synth = Arc::new(Mutex::new(Synth::new(sample_rate)));
-        let synth_clone = synth.clone();
-
-        let stream = match config.sample_format() {
-            SampleFormat::F32 => create_stream(&device, &config.into(), synth_clone.clone()),
-            //SampleFormat::I16 => create_stream::<i16>(&device, &config.into(), synth_clone.clone()),
-            //SampleFormat::U16 => create_stream::<u16>(&device, &config.into(), synth_clone.clone()),
-            _ => panic!("Unsupported format"),
-        }
-        .unwrap();
-
-        stream.play().unwrap();
-
-        let keyboard = [
-            "zxcvbnm,./",
-            "asdfghjkl;'\\",
-            "qwertyuiop[]",
-            "`1234567890-=",
-        ];
-        let map: HashMap<egui::Key, u8> = keyboard.into_iter()
-            .enumerate()
-            .flat_map(move |(cnt, s)| {
-                s.chars()
-                    .map( move |x| egui::Key::from_name(&format!("{x}")).unwrap())
-                    .enumerate()
-                    .map(move |(i, d)| (d, (i + cnt * 5) as u8))
-            })
-            .collect();
-        Self {
-            synth: synth_clone,
-            _stream: stream,
-            key_map: map,
+// Lowest note of the fourths keyboard layout and the rectangular grid (A4)
+const BASE_NOTE: u8 = 69;
+
+// Note keys by the egui key at their position on a US keyboard, which is
+// what egui reports as the physical key whatever the system layout
+fn egui_key_map(map: &keyboard::KeyboardMap) -> HashMap<egui::Key, u8> {
+    map.notes()
+        .into_iter()
+        .filter_map(|(key, note)| Some((egui::Key::from_name(&key.to_string())?, note)))
+        .collect()
+}
+
+// Scale table read at startup, in the format described in scale.rs
+const SCALE_FILE: &str = "gamlar";
+
+// Where a note played from the GUI came from, so releasing it stops the
+// note it started even if the scale changed in between
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum NoteSource {
+    Key(egui::Key),
+    Tile(u8),
+}
+
+// Computer keyboard and grid notes, passed through the scale settings
+struct NoteInput {
+    scale: scale::ScaleSettings,
+    sounding: HashMap<NoteSource, u8>,
+}
+
+impl NoteInput {
+    fn press(&mut self, synth: &mut Synth, source: NoteSource, note: u8) {
+        if self.sounding.contains_key(&source) {
+            return;
+        }
+        if let Some(played) = self.scale.apply(note, BASE_NOTE) {
+            synth.note_on(played);
+            self.sounding.insert(source, played);
+        }
+    }
+
+    fn release(&mut self, synth: &mut Synth, source: NoteSource) {
+        if let Some(played) = self.sounding.remove(&source) {
+            synth.note_off(played);
         }
     }
 }
 
-impl eframe::App for SynthApp {
-    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
-        egui::CentralPanel::default().show(ctx, |ui| {
-            let mut synth = self.synth.lock().unwrap();
+// Scales from the scale file, with a status line
+fn load_scales() -> (Vec<scale::Scale>, String) {
+    match scale::load(SCALE_FILE) {
+        Ok(loaded) => {
+            let status = format!("Loaded {} scales from {}", loaded.len(), SCALE_FILE);
+            (loaded, status)
+        }
+        Err(message) => (Vec::new(), message),
+    }
+}
+
+// Chord presets: the built-in chords, then the scale file's interval sets
+fn chord_presets(scales: &[scale::Scale]) -> Vec<chords::Chord> {
+    let mut presets = chords::builtin();
+    presets.extend(chords::from_scales(scales));
+    presets
+}
+
+struct SynthApp {
+    synth: Arc<Mutex<Synth>>,
+    _stream: Stream,
+    midi: Option<midi::MidiConnection>,
+    keyboard: keyboard::KeyboardMap,
+    key_map: HashMap<egui::Key, u8>,
+    keyboard_status: String,
+    patch_path: String,
+    patch_status: String,
+    tap_tempo: transport::TapTempo,
+    note_input: NoteInput,
+    scale_status: String,
+    sequencer_view: SequencerView,
+    recording: RecordingView,
+    // Spectra of the EQs on screen, transformed without the audio lock
+    spectra: HashMap<EffectAddress, eq::SpectrumView>,
+    // Impulse responses still being read and partitioned
+    impulse_loads: Vec<convolution::ImpulseLoad>,
+}
+
+impl SynthApp {
+    fn new(_cc: &eframe::CreationContext<'_>) -> Result<Self, String> {
+        let (synth_clone, stream) = start_audio()?;
+
+        let (scales, scale_status) = load_scales();
+        synth_clone.lock().unwrap().chords.chords = chord_presets(&scales);
+
+        let midi = midi::connect(synth_clone.clone());
+
+        let (keyboard, keyboard_status) = keyboard::load_or_default();
+        Ok(Self {
+            synth: synth_clone,
+            _stream: stream,
+            midi,
+            key_map: egui_key_map(&keyboard),
+            keyboard,
+            keyboard_status,
+            patch_path: "patch.toml".to_string(),
+            patch_status: String::new(),
+            tap_tempo: transport::TapTempo::default(),
+            note_input: NoteInput {
+                scale: scale::ScaleSettings::new([scale::builtin(), scales].concat()),
+                sounding: HashMap::new(),
+            },
+            scale_status,
+            sequencer_view: SequencerView {
+                step: 0,
+                low_note: 60,
+            },
+            recording: RecordingView {
+                directory: "recordings".to_string(),
+                dry: false,
+                max_minutes: recorder::DEFAULT_MAX_MINUTES,
+                punch: false,
+                punch_in: 1,
+                punch_out: 5,
+                take: None,
+                status: String::new(),
+                midi_path: String::new(),
+                midi_status: String::new(),
+            },
+            spectra: HashMap::new(),
+            impulse_loads: Vec::new(),
+        })
+    }
+}
+
+impl eframe::App for SynthApp {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        let mut impulse_requests = Vec::new();
+        let mut loop_requests = Vec::new();
+        let mut recording_requests = Vec::new();
+        let mut slot_actions = Vec::new();
+        // A removed bus, freed once the audio lock is released
+        let mut freed_bus = None;
+        // Some(true) loads the patch file, Some(false) saves it
+        let mut patch_action = None;
+        egui::CentralPanel::default().show(ctx, |ui| {
+            let mut synth = self.synth.lock().unwrap();
+            self.recording.poll(&synth);
 
             ui.heading("Synthesizer");
 
@@ -763,140 +2601,390 @@ impl eframe::App for SynthApp {
                 });
             });
 
-            ui.add(egui::Slider::new(&mut synth.pitch_bend, 0.5..=2.0).text("Pitch Bend"));
-            ui.heading("Effects");
             ui.horizontal(|ui| {
-                //let synth = synth.lock().unwrap();
-                if ui.button("Add Delay").clicked() {
-                    let sr = synth.sample_rate;
-                    synth.effects.add_effect(Effect::new_delay(
-                        sr,
-                        0.3, // delay time
-                        0.4, // feedback
-                        0.5, // mix
-                    ));
-                }
-                
-                if ui.button("Add Distortion").clicked() {
-                    synth.effects.add_effect(Effect::new_distortion(2.0, 0.5));
-                }
-                
-                if ui.button("Add Filter").clicked() {
-                    synth.effects.add_effect(Effect::new_filter(1000.0, 0.7, 0.5));
-                }
-                
-                if ui.button("Add Tremolo").clicked() {
-                    synth.effects.add_effect(Effect::new_tremolo(5.0, 0.5, 0.5));
-                }
-                if ui.button("Add Chorus").clicked() {
-                    let sample_rate = synth.sample_rate;
-                    synth.effects.add_effect(Effect::new_chorus(
-                    sample_rate,
-                    3, // number of voices
-                    0.5, // mix
-                ));
-                }
-
-                if ui.button("Add Reverb").clicked() {
-                    let sample_rate = synth.sample_rate;
-                    synth.effects.add_effect(Effect::new_reverb(
-                    sample_rate,
-                    1.0, // room size
-                    0.5, // mix
-                    ));
-                    }
-
-                if ui.button("Add Ring Modulator").clicked() {
-                    synth.effects.add_effect(Effect::new_ring_mod(440.0, 0.5));
+                let semitones = synth.pitch_bend_semitones();
+                ui.add(
+                    egui::Slider::new(&mut synth.pitch_bend, -1.0..=1.0)
+                        .custom_formatter(move |_, _| format!("{semitones:+.2} st"))
+                        .text("Pitch Bend"),
+                );
+                if ui.button("Center").clicked() {
+                    synth.pitch_bend = 0.0;
+                }
+                ui.add(
+                    egui::Slider::new(&mut synth.bend_range_down, 0.0..=24.0)
+                        .step_by(1.0)
+                        .text("Range Down"),
+                );
+                ui.add(
+                    egui::Slider::new(&mut synth.bend_range_up, 0.0..=24.0)
+                        .step_by(1.0)
+                        .text("Range Up"),
+                );
+            });
+
+            ui.horizontal(|ui| {
+                let mut sustain = synth.sustain_pedal;
+                if ui.checkbox(&mut sustain, "Sustain Pedal (Space)").changed() {
+                    synth.sustain(sustain);
+                }
+                let mut sostenuto = synth.sostenuto_pedal;
+                if ui.checkbox(&mut sostenuto, "Sostenuto Pedal").changed() {
+                    synth.set_sostenuto_pedal(sostenuto);
+                }
+                match &self.midi {
+                    Some(connection) => ui.label(format!("MIDI: {}", connection.port_name)),
+                    None => ui.label("MIDI: no input"),
+                };
+            });
+
+            ui.add(
+                egui::Slider::new(&mut synth.filter_cutoff.target, 20.0..=MAX_FILTER_CUTOFF)
+                    .logarithmic(true)
+                    .text("Voice Filter Cutoff"),
+            );
+            let mut smoothing_ms = synth.smoothing_time * 1000.0;
+            if ui
+                .add(egui::Slider::new(&mut smoothing_ms, 0.0..=200.0).text("Parameter Smoothing (ms)"))
+                .changed()
+            {
+                synth.smoothing_time = smoothing_ms / 1000.0;
+            }
+
+            ui.collapsing("Transport", |ui| {
+                let transport = &mut synth.transport;
+                ui.horizontal(|ui| {
+                    if transport.playing {
+                        if ui.button("Stop").clicked() {
+                            transport.stop();
+                        }
+                    } else if ui.button("Play").clicked() {
+                        transport.play();
+                    }
+                    if ui.button("Rewind").clicked() {
+                        transport.rewind();
+                    }
+                    let (bar, beat) = transport.bar_and_beat();
+                    ui.label(format!("Bar {bar} Beat {beat}"));
+                });
+                ui.horizontal(|ui| {
+                    ui.add_enabled(
+                        !transport.follow_midi_clock,
+                        egui::Slider::new(&mut transport.tempo, transport::MIN_TEMPO..=transport::MAX_TEMPO)
+                            .text("Tempo (BPM)"),
+                    );
+                    if ui.add_enabled(!transport.follow_midi_clock, egui::Button::new("Tap")).clicked() {
+                        if let Some(tempo) = self.tap_tempo.tap() {
+                            transport.tempo = tempo;
                         }
+                    }
+                    ui.checkbox(&mut transport.follow_midi_clock, "Follow MIDI Clock");
                 });
+                ui.horizontal(|ui| {
+                    ui.label("Time Signature");
+                    ui.add(egui::DragValue::new(&mut transport.time_signature.beats).range(1..=32));
+                    ui.label("/");
+                    egui::ComboBox::from_id_salt("time_signature_unit")
+                        .selected_text(transport.time_signature.unit.to_string())
+                        .width(40.0)
+                        .show_ui(ui, |ui| {
+                            for unit in [2, 4, 8, 16] {
+                                ui.selectable_value(&mut transport.time_signature.unit, unit, unit.to_string());
+                            }
+                        });
+                });
+            });
 
-            if ui.button("Reset Effects").clicked() {
-                synth.effects = EffectStack::new();
-            }
+            ui.collapsing("Recording", |ui| {
+                recording_ui(ui, &mut synth, &mut self.recording, &mut recording_requests)
+            });
 
-            let sample_rate = synth.sample_rate;
-            for (index, effect) in synth.effects.effects.iter_mut().enumerate() {
-                ui.group(|ui| {
-                    match effect {
-                        Effect::Delay(params) => {
-                            ui.label(format!("Delay {}", index + 1));
-                            ui.add(egui::Slider::new(&mut params.delay_time, 0.0..=2.0).text("Delay Time"));
-                            ui.add(egui::Slider::new(&mut params.feedback, 0.0..=0.95).text("Feedback"));
-                            ui.add(egui::Slider::new(&mut params.mix, 0.0..=1.0).text("Mix"));
-
-                            // Update buffer size if delay time changes
-                            let new_size = (sample_rate * params.delay_time) as usize;
-                            if params.buffer.len() != new_size {
-                                params.buffer = vec![0.0; new_size.max(1)];
-                                params.position = 0;
+            ui.collapsing("Looper", |ui| looper_ui(ui, &mut synth, &mut loop_requests));
+
+            ui.collapsing("Arpeggiator", |ui| {
+                ui.horizontal(|ui| {
+                    let mut enabled = synth.arpeggiator.enabled;
+                    if ui.checkbox(&mut enabled, "Enabled").changed() {
+                        synth.set_arpeggiator_enabled(enabled);
+                    }
+                    let arpeggiator = &mut synth.arpeggiator;
+                    let mut latch = arpeggiator.latch;
+                    if ui.checkbox(&mut latch, "Latch").changed() {
+                        arpeggiator.set_latch(latch);
+                    }
+                    ui.label("Rate:");
+                    arpeggiator.rate.ui(ui, "arpeggiator_rate");
+                });
+                let arpeggiator = &mut synth.arpeggiator;
+                ui.horizontal(|ui| {
+                    for mode in arpeggiator::ArpMode::ALL {
+                        ui.radio_value(&mut arpeggiator.mode, mode, mode.label());
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.add(egui::Slider::new(&mut arpeggiator.octaves, 1..=arpeggiator::MAX_ARP_OCTAVES).text("Octaves"));
+                    ui.add(egui::Slider::new(&mut arpeggiator.gate, 0.05..=1.0).text("Gate"));
+                    ui.add(egui::Slider::new(&mut arpeggiator.swing, 0.0..=0.75).text("Swing"));
+                });
+                ui.add(egui::Slider::new(&mut arpeggiator.steps, 1..=arpeggiator::MAX_ARP_STEPS).text("Accent Steps"));
+                ui.horizontal(|ui| {
+                    let steps = arpeggiator.steps;
+                    for velocity in arpeggiator.velocities.iter_mut().take(steps) {
+                        ui.add(egui::Slider::new(velocity, 0.0..=1.0).vertical().show_value(false));
+                    }
+                });
+            });
+
+            ui.collapsing("Chord Memory", |ui| {
+                let chords = &mut synth.chords;
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut chords.enabled, "Play Chords");
+                    let selected_name = chords
+                        .chords
+                        .get(chords.selected)
+                        .map_or(String::new(), |chord| chord.name.clone());
+                    egui::ComboBox::from_id_salt("chord")
+                        .selected_text(selected_name)
+                        .show_ui(ui, |ui| {
+                            for index in 0..chords.chords.len() {
+                                let name = chords.chords[index].name.clone();
+                                ui.selectable_value(&mut chords.selected, index, name);
                             }
-                        },
-                        Effect::Distortion {  ref mut drive,ref mut  mix } => {
-                            ui.label(format!("Distortion {}", index + 1));
-                            ui.add(egui::Slider::new(drive, 1.0..=10.0).text("Drive"));
-                            ui.add(egui::Slider::new( mix, 0.0..=1.0).text("Mix"));
-                        },
-                        Effect::Filter(params) => {
-                            ui.label(format!("Filter {}", index + 1));
-                            ui.add(egui::Slider::new(&mut params.cutoff, 20.0..=20000.0).logarithmic(true).text("Cutoff"));
-                            ui.add(egui::Slider::new(&mut params.resonance, 0.0..=0.99).text("Resonance"));
-                            ui.add(egui::Slider::new(&mut params.mix, 0.0..=1.0).text("Mix"));
-                        },
-                        Effect::Tremolo(params) => {
-                            ui.label(format!("Tremolo {}", index + 1));
-                            ui.add(egui::Slider::new(&mut params.rate, 0.1..=20.0).text("Rate"));
-                            ui.add(egui::Slider::new(&mut params.depth, 0.0..=1.0).text("Depth"));
-                            ui.add(egui::Slider::new(&mut params.mix, 0.0..=1.0).text("Mix"));
-                        },
-                        Effect::Chorus(params) => {
-        ui.label(format!("Chorus {}", index + 1));
-        for i in 0..params.rates.len() {
-            ui.add(egui::Slider::new(&mut params.rates[i], 0.1..=5.0)
-                .text(format!("Voice {} Rate", i + 1)));
-            ui.add(egui::Slider::new(&mut params.depths[i], 0.0..=1.0)
-                .text(format!("Voice {} Depth", i + 1)));
-        }
-        ui.add(egui::Slider::new(&mut params.mix, 0.0..=1.0).text("Mix"));
-    },
-    Effect::Reverb(params) => {
-        ui.label(format!("Reverb {}", index + 1));
-        ui.add(egui::Slider::new(&mut params.feedback, 0.0..=0.95).text("Feedback"));
-        ui.add(egui::Slider::new(&mut params.mix, 0.0..=1.0).text("Mix"));
-    },
-    Effect::RingMod(params) => {
-        ui.label(format!("Ring Modulator {}", index + 1));
-        ui.add(egui::Slider::new(&mut params.frequency, 1.0..=2000.0)
-            .logarithmic(true)
-            .text("Frequency"));
-        ui.add(egui::Slider::new(&mut params.mix, 0.0..=1.0).text("Mix"));
-    },
+                        });
+                    let held = chords.held();
+                    if ui
+                        .add_enabled(held.len() >= 2, egui::Button::new("Learn from Held Notes"))
+                        .clicked()
+                    {
+                        chords.learn();
                     }
+                    let held_names: Vec<String> = held.into_iter().map(scale::note_name).collect();
+                    ui.label(format!("Held: {}", held_names.join(" ")));
                 });
-            }
+                ui.horizontal(|ui| {
+                    let size = chords.chords.get(chords.selected).map_or(1, |chord| chord.intervals.len());
+                    ui.add(egui::Slider::new(&mut chords.inversion, 0..=size.saturating_sub(1)).text("Inversion"));
+                    ui.checkbox(&mut chords.spread, "Spread");
+                    ui.checkbox(&mut chords.drop2, "Drop 2");
+                    let voiced: Vec<String> = chords.voice(60).into_iter().map(scale::note_name).collect();
+                    ui.label(format!("On C4: {}", voiced.join(" ")));
+                });
+            });
+
+            ui.collapsing("Step Sequencer", |ui| {
+                sequencer_ui(ui, &mut synth, &mut self.sequencer_view);
+            });
 
-           ui.heading("Keyboard-to-Note Mapping");
-            // Render keyboard rows with drag value for note adjustment
-            let rows = ["`1234567890-=".chars().collect::<Vec<_>>(),
-                "qwertyuiop[]\\".chars().collect::<Vec<_>>(),
-                "asdfghjkl;'".chars().collect::<Vec<_>>(),
-                "zxcvbnm,./".chars().collect::<Vec<_>>()];
-            for row in rows.iter() {
+            ui.collapsing("MPE", |ui| {
+                let mut lower_zone = synth.mpe.lower_zone;
+                let mut upper_zone = synth.mpe.upper_zone;
                 ui.horizontal(|ui| {
-                    for &key_char in row {
-                        let key = egui::Key::from_name(&key_char.to_string()).unwrap();
-                        let note = self.key_map.entry(key).or_insert(0);
+                    if ui
+                        .add(egui::Slider::new(&mut lower_zone, 0..=15).text("Lower Zone Channels"))
+                        .changed()
+                    {
+                        synth.mpe.configure_zone(0, lower_zone);
+                    }
+                    if ui
+                        .add(egui::Slider::new(&mut upper_zone, 0..=15).text("Upper Zone Channels"))
+                        .changed()
+                    {
+                        synth.mpe.configure_zone(15, upper_zone);
+                    }
+                });
+                ui.add(
+                    egui::Slider::new(&mut synth.mpe.note_bend_range, 0.0..=96.0)
+                        .step_by(1.0)
+                        .text("Per-Note Bend Range"),
+                );
+                let mpe = &mut synth.mpe;
+                for (name, route) in [("Pressure", &mut mpe.pressure), ("Slide", &mut mpe.slide)] {
+                    ui.horizontal(|ui| {
+                        ui.label(format!("{name}:"));
+                        ui.radio_value(&mut route.target, ExpressionTarget::Off, "Off");
+                        ui.radio_value(&mut route.target, ExpressionTarget::Pitch, "Pitch");
+                        ui.radio_value(&mut route.target, ExpressionTarget::Amplitude, "Amplitude");
+                        ui.radio_value(&mut route.target, ExpressionTarget::Filter, "Filter");
+                        let range = match route.target {
+                            ExpressionTarget::Pitch => -24.0..=24.0,
+                            ExpressionTarget::Filter => -8.0..=8.0,
+                            _ => 0.0..=1.0,
+                        };
+                        ui.add(egui::Slider::new(&mut route.amount, range).text("Amount"));
+                    });
+                }
+            });
+            ui.heading("Effects");
+            let sample_rate = synth.sample_rate;
+            let bus_names: Vec<String> = synth.buses.iter().map(|bus| bus.name.clone()).collect();
+            let mut editor = EffectEditor {
+                sample_rate,
+                bus_names,
+                impulse_requests: &mut impulse_requests,
+                slot_actions: &mut slot_actions,
+                spectra: &mut self.spectra,
+            };
+            ui.horizontal_wrapped(|ui| add_effect_buttons(ui, &mut synth.effects, &editor));
+
+            ui.horizontal(|ui| {
+                if ui.button("Reset Effects").clicked() {
+                    synth.effects = EffectStack::new();
+                }
+                if ui.button("Clear Tails").clicked() {
+                    synth.effects.reset();
+                    for bus in synth.buses.iter_mut() {
+                        bus.effects.reset();
+                    }
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label("Patch file:");
+                ui.text_edit_singleline(&mut self.patch_path);
+                if ui.button("Save").clicked() {
+                    patch_action = Some(false);
+                }
+                if ui.button("Load").clicked() {
+                    patch_action = Some(true);
+                }
+                ui.label(&self.patch_status);
+            });
 
-                        // Display key and text input for note
+            let address = EffectAddress {
+                bus: None,
+                path: Vec::new(),
+            };
+            effect_stack_ui(ui, &mut synth.effects, &address, &mut editor);
+
+            ui.heading("Aux Buses");
+            let mut removed_bus = None;
+            for (index, bus) in synth.buses.iter_mut().enumerate() {
+                ui.group(|ui| {
+                    ui.horizontal(|ui| {
+                        ui.text_edit_singleline(&mut bus.name);
+                        if ui.button("Remove").clicked() {
+                            removed_bus = Some(index);
+                        }
+                    });
+                    ui.add(egui::Slider::new(&mut bus.voice_send.target, 0.0..=1.0).text("Voice Send"));
+                    ui.add(egui::Slider::new(&mut bus.return_level.target, 0.0..=1.0).text("Return Level"));
+                    ui.horizontal_wrapped(|ui| add_effect_buttons(ui, &mut bus.effects, &editor));
+                    let address = EffectAddress {
+                        bus: Some(index),
+                        path: Vec::new(),
+                    };
+                    effect_stack_ui(ui, &mut bus.effects, &address, &mut editor);
+                });
+            }
+            if let Some(index) = removed_bus {
+                freed_bus = synth.remove_bus(index);
+            }
+            if synth.buses.len() < MAX_AUX_BUSES && ui.button("Add Aux Bus").clicked() {
+                let name = format!("Bus {}", synth.buses.len() + 1);
+                synth.buses.push(AuxBus::new(name));
+            }
+
+            ui.heading("Scale");
+            let scale_settings = &mut self.note_input.scale;
+            ui.horizontal(|ui| {
+                let fixed_root = matches!(
+                    scale_settings.scale().map(|scale| scale.pitches),
+                    Some(scale::PitchSet::Absolute { root: Some(_), .. })
+                );
+                ui.add_enabled_ui(!fixed_root, |ui| {
+                    egui::ComboBox::from_id_salt("scale_root")
+                        .selected_text(scale::NOTE_NAMES[scale_settings.effective_root() as usize])
+                        .width(50.0)
+                        .show_ui(ui, |ui| {
+                            for (pitch, name) in scale::NOTE_NAMES.iter().enumerate() {
+                                ui.selectable_value(&mut scale_settings.root, pitch as u8, *name);
+                            }
+                        });
+                });
+                let selected_name = scale_settings.scale().map_or("", |scale| scale.name.as_str()).to_string();
+                egui::ComboBox::from_id_salt("scale")
+                    .selected_text(selected_name)
+                    .show_ui(ui, |ui| {
+                        for index in 0..scale_settings.scales.len() {
+                            let name = scale_settings.scales[index].name.clone();
+                            ui.selectable_value(&mut scale_settings.selected, index, name);
+                        }
+                    });
+                if ui.button("Reload Scale File").clicked() {
+                    let (scales, status) = load_scales();
+                    // Learned chords are kept, the scale file's sets are replaced
+                    let learned: Vec<chords::Chord> = synth
+                        .chords
+                        .chords
+                        .iter()
+                        .filter(|chord| chord.learned)
+                        .cloned()
+                        .collect();
+                    synth.chords.chords = [chord_presets(&scales), learned].concat();
+                    synth.chords.selected = synth.chords.selected.min(synth.chords.chords.len() - 1);
+                    let scales = [scale::builtin(), scales].concat();
+                    scale_settings.selected = scale_settings.selected.min(scales.len() - 1);
+                    scale_settings.scales = scales;
+                    self.scale_status = status;
+                }
+            });
+            ui.horizontal(|ui| {
+                for mode in scale::ScaleMode::ALL {
+                    ui.radio_value(&mut scale_settings.mode, mode, mode.label());
+                }
+            });
+            ui.label(&self.scale_status);
+
+            ui.heading("Keyboard-to-Note Mapping");
+            let mut keyboard_changed = false;
+            ui.horizontal(|ui| {
+                ui.label("Layout:");
+                egui::ComboBox::from_id_salt("keyboard_layout")
+                    .selected_text(self.keyboard.layout.label())
+                    .show_ui(ui, |ui| {
+                        for layout in keyboard::Layout::ALL {
+                            keyboard_changed |= ui
+                                .selectable_value(&mut self.keyboard.layout, layout, layout.label())
+                                .changed();
+                        }
+                    });
+                if ui.button("Octave Down").clicked() {
+                    self.keyboard.shift_octave(-1);
+                    keyboard_changed = true;
+                }
+                ui.label(format!("Octave {:+} (Up/Down arrows)", self.keyboard.octave));
+                if ui.button("Octave Up").clicked() {
+                    self.keyboard.shift_octave(1);
+                    keyboard_changed = true;
+                }
+                if ui.button("Save").clicked() {
+                    self.keyboard_status = match self.keyboard.save(keyboard::KEYBOARD_FILE) {
+                        Ok(()) => format!("Saved {}", keyboard::KEYBOARD_FILE),
+                        Err(message) => message,
+                    };
+                }
+                ui.label(&self.keyboard_status);
+            });
+            // Rows top down, each shifted like the keys on the keyboard;
+            // editing a note turns the layout into a custom one
+            let notes: HashMap<char, u8> = self.keyboard.notes().into_iter().collect();
+            for (row, (keys, first)) in keyboard::KEY_ROWS.iter().enumerate().rev() {
+                ui.horizontal(|ui| {
+                    // 53 points a key: the note field and the spacing after it
+                    ui.add_space((*first as f32 - row as f32 * 0.5 + 2.5) * 53.0);
+                    for key_char in keys.chars() {
                         ui.vertical(|ui| {
                             ui.label(key_char.to_string());
-                            let mut note_string = note.to_string();
+                            let mut note_string = notes.get(&key_char).map_or(String::new(), |note| note.to_string());
                             let text_edit =
                                 egui::TextEdit::singleline(&mut note_string).desired_width(45.0);
                             if ui.add(text_edit).changed() {
-                                if let Ok(parsed_note) = note_string.parse::<u8>() {
+                                if note_string.is_empty() {
+                                    self.keyboard.set_note(key_char, None);
+                                    keyboard_changed = true;
+                                } else if let Ok(parsed_note) = note_string.parse::<u8>() {
                                     if parsed_note <= 127 {
-                                        *note = parsed_note;
+                                        self.keyboard.set_note(key_char, Some(parsed_note));
+                                        keyboard_changed = true;
                                     }
                                 }
                             }
@@ -904,6 +2992,9 @@ impl eframe::App for SynthApp {
                     }
                 });
             }
+            if keyboard_changed {
+                self.key_map = egui_key_map(&self.keyboard);
+            }
 
             ui.heading("Rectangular Keyboard");
             let tile_size = egui::vec2(50.0, 50.0); // Size of each tile
@@ -914,12 +3005,15 @@ impl eframe::App for SynthApp {
                 ui.horizontal(|ui| {
                     for col in 0..cols {
                         // Calculate MIDI note
-                        let note = col + row * cols;
+                        let index = col + row * cols;
+                        let note = BASE_NOTE + index;
+                        let played = self.note_input.scale.apply(note, BASE_NOTE);
+                        let degree = played.and_then(|played| self.note_input.scale.degree(played));
 
                         let response =
                             ui.allocate_response(tile_size, egui::Sense::click_and_drag());
 
-                        // Draw tile
+                        // Draw tile, marking the root and the other scale degrees
                         let painter = ui.painter();
                         let rect = response.rect;
                         painter.rect_filled(
@@ -928,7 +3022,11 @@ impl eframe::App for SynthApp {
                             if response.hovered() || response.clicked() {
                                 egui::Color32::LIGHT_BLUE
                             } else {
-                                egui::Color32::GRAY
+                                match degree {
+                                    Some(0) => egui::Color32::from_rgb(230, 160, 60),
+                                    Some(_) => egui::Color32::GRAY,
+                                    None => egui::Color32::DARK_GRAY,
+                                }
                             },
                         );
                         painter.rect_stroke(
@@ -936,43 +3034,1299 @@ impl eframe::App for SynthApp {
                             5.0, // Corner radius
                             egui::Stroke::new(1.0, egui::Color32::BLACK),
                         );
+                        if let Some(played) = played {
+                            let label = match degree {
+                                Some(degree) => format!("{}\n{}", scale::note_name(played), degree + 1),
+                                None => scale::note_name(played),
+                            };
+                            painter.text(
+                                rect.center(),
+                                egui::Align2::CENTER_CENTER,
+                                label,
+                                egui::FontId::proportional(11.0),
+                                egui::Color32::BLACK,
+                            );
+                        }
                         if response.drag_started() {
-                            synth.note_on(note);
+                            self.note_input.press(&mut synth, NoteSource::Tile(index), note);
                         }
 
                         if response.drag_stopped() {
-                            synth.note_off(note);
+                            self.note_input.release(&mut synth, NoteSource::Tile(index));
                         }
                     }
                 });
             }
         });
 
+        // Impulse responses are read and partitioned on their own threads;
+        // only the finished engine is swapped in, under a short lock
+        if !impulse_requests.is_empty() {
+            let mut synth = self.synth.lock().unwrap();
+            for request in &impulse_requests {
+                if let Some(Effect::Convolution(params)) = synth.effect_mut(&request.address) {
+                    params.status = format!("Loading {}...", request.path);
+                }
+            }
+        }
+        self.impulse_loads
+            .extend(impulse_requests.into_iter().map(convolution::ImpulseRequest::spawn));
+        let (finished, pending) = std::mem::take(&mut self.impulse_loads)
+            .into_iter()
+            .partition(|load| load.finished());
+        self.impulse_loads = pending;
+        for load in finished {
+            let address = load.address.clone();
+            let result = load.join();
+            let mut replaced = None;
+            let mut synth = self.synth.lock().unwrap();
+            if let Some(Effect::Convolution(params)) = synth.effect_mut(&address) {
+                match result {
+                    Ok((impulse, engine)) => replaced = Some(params.install(impulse, engine)),
+                    Err(message) => params.status = message,
+                }
+            }
+            // The old engine is freed with the audio running
+            drop(synth);
+            drop(replaced);
+        }
+        if !self.impulse_loads.is_empty() {
+            ctx.request_repaint_after(std::time::Duration::from_millis(50));
+        }
+
+        drop(freed_bus);
+        for (address, action) in slot_actions {
+            edit_slot(&self.synth, &address, action);
+        }
+
+        for request in recording_requests {
+            self.recording.apply(request, &self.synth);
+        }
+        // A recording that ended is dropped here, letting its writer finish
+        let ended = self.synth.lock().unwrap().recorder.take_ended();
+        drop(ended);
+
+        for request in loop_requests {
+            let frames = {
+                let synth = self.synth.lock().unwrap();
+                let (bar, tempo) = (synth.transport.time_signature.bar_length(), synth.transport.tempo);
+                synth.looper.buffer_frames(&request, bar, tempo, synth.sample_rate)
+            };
+            let buffer = frames.map(|frames| vec![[0.0; 2]; frames]);
+            let freed = {
+                let mut synth = self.synth.lock().unwrap();
+                let (bar, tempo, sample_rate) =
+                    (synth.transport.time_signature.bar_length(), synth.transport.tempo, synth.sample_rate);
+                synth.looper.apply(&request, buffer, bar, tempo, sample_rate)
+            };
+            // Old layers are freed here, with the audio running
+            drop(freed);
+        }
+
+        // Spectra copied this frame are drawn on the next; EQs that weren't
+        // shown are forgotten
+        self.spectra.retain(|_, spectrum| spectrum.transform());
+
+        match patch_action {
+            Some(true) => {
+                let sample_rate = self.synth.lock().unwrap().sample_rate;
+                self.patch_status = match patch::load(&self.patch_path, sample_rate) {
+                    Ok(loaded) => {
+                        self.synth.lock().unwrap().load_patch(loaded);
+                        format!("Loaded {}", self.patch_path)
+                    }
+                    Err(message) => message,
+                };
+            }
+            Some(false) => {
+                let text = {
+                    let synth = self.synth.lock().unwrap();
+                    patch::to_string(&synth.effects, &synth.buses, &synth.sequencer)
+                };
+                self.patch_status = match text.and_then(|text| patch::write(&self.patch_path, &text)) {
+                    Ok(()) => format!("Saved {}", self.patch_path),
+                    Err(message) => message,
+                };
+            }
+            None => {}
+        }
+
+        // Arrow keys belong to a focused slider or text field
+        let widget_focused = ctx.wants_keyboard_input() || ctx.memory(|memory| memory.focused().is_some());
         ctx.input(|i| {
-            let mut notes = Vec::new();
+            let mut synth = self.synth.lock().unwrap();
             for event in &i.events {
-                if let egui::Event::Key { key, pressed, .. } = event {
-                    //println!("{:?} {:?} {} ", &key, pressed, self.key_map[ &]  );
-                    {
-                        if self.key_map.contains_key(key) {
-                            let freq = self.key_map[key];
-                            notes.push((freq, pressed));
+                if let egui::Event::Key { key, physical_key, pressed, repeat, .. } = event {
+                    // Keys are matched by position so other keyboard layouts
+                    // play the same shape
+                    let key = physical_key.unwrap_or(*key);
+                    if key == egui::Key::Space {
+                        if !repeat {
+                            synth.sustain(*pressed);
+                        }
+                    } else if matches!(key, egui::Key::ArrowUp | egui::Key::ArrowDown) && !widget_focused {
+                        if *pressed && !repeat {
+                            self.keyboard.shift_octave(if key == egui::Key::ArrowUp { 1 } else { -1 });
+                            self.key_map = egui_key_map(&self.keyboard);
+                        }
+                    } else if let Some(&note) = self.key_map.get(&key) {
+                        let source = NoteSource::Key(key);
+                        match pressed {
+                            true => self.note_input.press(&mut synth, source, note),
+                            false => self.note_input.release(&mut synth, source),
                         }
                     }
                 }
             }
-            let mut synth = self.synth.lock().unwrap();
+        });
+
+        ctx.request_repaint();
+    }
+}
+
+// Everything the effect editor needs besides the effects themselves
+struct EffectEditor<'a> {
+    sample_rate: f32,
+    bus_names: Vec<String>,
+    impulse_requests: &'a mut Vec<convolution::ImpulseRequest>,
+    // Slot edits by address, done once the audio lock is released
+    slot_actions: &'a mut Vec<(EffectAddress, SlotAction)>,
+    spectra: &'a mut HashMap<EffectAddress, eq::SpectrumView>,
+}
+
+// Drag payload for reordering slots within one stack
+struct SlotDrag {
+    stack: egui::Id,
+    index: usize,
+}
+
+// Builds an effect for a sample rate
+type EffectBuilder = fn(f32) -> Effect;
+
+// Effects offered by the editors
+const EFFECT_PRESETS: [(&str, EffectBuilder); 19] = [
+    ("Delay", |sample_rate| Effect::new_delay(
+        sample_rate,
+        0.3, // delay time
+        0.4, // feedback
+        0.5, // mix
+    )),
+    ("Distortion", |sample_rate| Effect::new_distortion(sample_rate, 2.0, 0.5)),
+    ("Bit Crusher", |_| Effect::BitCrusher(distortion::BitCrusherParameters::new(8.0, 11025.0, 1.0))),
+    ("Filter", |_| Effect::new_filter(1000.0, 0.7, 0.5)),
+    ("Tremolo", |_| Effect::new_tremolo(5.0, 0.5, 0.5)),
+    ("Chorus", |sample_rate| Effect::new_chorus(
+        sample_rate,
+        3,   // number of voices
+        0.5, // mix
+    )),
+    ("Flanger", |sample_rate| Effect::new_flanger(sample_rate, 0.5)),
+    ("Phaser", |_| Effect::Phaser(modulation::PhaserParameters::new(4, 0.5))),
+    ("Reverb", |sample_rate| Effect::new_reverb(
+        sample_rate,
+        1.0, // room size
+        0.5, // mix
+    )),
+    ("EQ", Effect::new_eq),
+    ("Ring Modulator", |_| Effect::new_ring_mod(440.0, 0.5)),
+    ("Convolution", |_| Effect::Convolution(convolution::ConvolutionParameters::new(0.5))),
+    ("Parallel Split", |_| Effect::new_split(SplitMode::Parallel)),
+    ("Band Split", |_| Effect::new_split(SplitMode::Bands)),
+    ("Compressor", |_| Effect::Compressor(dynamics::CompressorParameters::new(-18.0, 4.0))),
+    ("Expander", |_| Effect::Expander(dynamics::ExpanderParameters::new(-40.0, 2.0, 24.0))),
+    ("Gate", |_| Effect::Expander(dynamics::ExpanderParameters::new(-50.0, 50.0, 80.0))),
+    ("Transient Shaper", |_| Effect::TransientShaper(dynamics::TransientShaperParameters::new(0.5, 0.0))),
+    ("Send", |_| Effect::new_send(0, 0.5)),
+];
+
+fn add_effect_buttons(ui: &mut egui::Ui, stack: &mut EffectStack, editor: &EffectEditor) {
+    for (name, build) in EFFECT_PRESETS {
+        // Sends need a bus to send to
+        if name == "Send" && editor.bus_names.is_empty() {
+            continue;
+        }
+        if ui.button(format!("Add {name}")).clicked() {
+            stack.add_effect(build(editor.sample_rate));
+        }
+    }
+}
+
+// Settings of the recording panel and the file being written
+struct RecordingView {
+    directory: String,
+    dry: bool,
+    max_minutes: f32,
+    punch: bool,
+    // Bars counted from one; recording runs up to the start of `punch_out`
+    punch_in: u32,
+    punch_out: u32,
+    take: Option<recorder::Take>,
+    status: String,
+    // MIDI file to play back, the last one recorded by default
+    midi_path: String,
+    midi_status: String,
+}
+
+impl RecordingView {
+    // Reports on the take once its file is finished
+    fn poll(&mut self, synth: &Synth) {
+        if synth.recorder.recording() || !self.take.as_ref().is_some_and(|take| take.finished()) {
+            return;
+        }
+        let take = self.take.take().unwrap();
+        let reason = match synth.recorder.stopped {
+            Some(recorder::StopReason::MaxLength) => "Reached the length limit. ",
+            Some(recorder::StopReason::PunchOut) => "Punched out. ",
+            Some(recorder::StopReason::WriterFailed) | None => "",
+        };
+        let path = match &take.dry_path {
+            Some(dry_path) => format!("{} and {}", take.path, dry_path),
+            None => take.path.clone(),
+        };
+        self.status = match take.join() {
+            Ok(frames) => format!(
+                "{}Saved {} ({:.1}s)",
+                reason,
+                path,
+                frames as f32 / synth.sample_rate
+            ),
+            Err(message) => message,
+        };
+    }
+}
+
+// Recording panel work that opens files or allocates, done once the audio
+// lock is released
+enum RecordingRequest {
+    Record(recorder::RecordSettings),
+    RecordMidi { tempo: f32 },
+    StopMidi,
+    PlayMidi,
+    StopPlayback,
+}
+
+impl RecordingView {
+    // Takes the audio lock only to swap things in and out
+    fn apply(&mut self, request: RecordingRequest, synth: &Mutex<Synth>) {
+        match request {
+            RecordingRequest::Record(settings) => {
+                let sample_rate = synth.lock().unwrap().sample_rate;
+                match recorder::start(&settings, sample_rate) {
+                    Ok((session, take)) => {
+                        synth.lock().unwrap().recorder.begin(session);
+                        self.take = Some(take);
+                        self.status.clear();
+                    }
+                    Err(message) => self.status = message,
+                }
+            }
+            RecordingRequest::RecordMidi { tempo } => {
+                let mut capture = midi::Capture::new(tempo);
+                let mut synth = synth.lock().unwrap();
+                capture.begin(synth.clock);
+                synth.midi_capture = Some(capture);
+                self.midi_status.clear();
+            }
+            RecordingRequest::StopMidi => {
+                let (capture, now) = {
+                    let mut synth = synth.lock().unwrap();
+                    (synth.midi_capture.take(), synth.clock)
+                };
+                let Some(capture) = capture else {
+                    return;
+                };
+                let tempo = capture.tempo;
+                let messages = capture.finish(now);
+                let path = recorder::next_path(&self.directory, "take", "mid");
+                let written = recorder::create_parent(&path).and_then(|()| smf::write(&path, &messages, tempo));
+                self.midi_status = match written {
+                    Ok(()) => {
+                        self.midi_path = path.clone();
+                        format!("Saved {} events to {}", messages.len(), path)
+                    }
+                    Err(message) => message,
+                };
+            }
+            RecordingRequest::PlayMidi => match smf::read(&self.midi_path) {
+                Ok(file) => {
+                    let mut player = midi::Player::new(file);
+                    let mut synth = synth.lock().unwrap();
+                    player.begin(synth.clock);
+                    synth.midi_player = Some(player);
+                }
+                Err(message) => self.midi_status = message,
+            },
+            RecordingRequest::StopPlayback => {
+                let player = synth.lock().unwrap().stop_midi_player();
+                drop(player);
+            }
+        }
+    }
+}
+
+fn recording_ui(ui: &mut egui::Ui, synth: &mut Synth, view: &mut RecordingView, requests: &mut Vec<RecordingRequest>) {
+    ui.horizontal(|ui| {
+        if synth.recorder.recording() {
+            if ui.button("Stop Recording").clicked() {
+                synth.recorder.stop();
+            }
+            let (frames, dropped) = synth.recorder.progress();
+            let path = view.take.as_ref().map_or("", |take| take.path.as_str());
+            let seconds = frames as f32 / synth.sample_rate;
+            if frames == 0 && view.punch {
+                ui.label(format!("Waiting for bar {} to record {}", view.punch_in, path));
+            } else {
+                ui.label(format!("Recording {}:{:04.1} to {}", (seconds / 60.0) as u32, seconds % 60.0, path));
+            }
+            if dropped > 0 {
+                ui.colored_label(egui::Color32::RED, format!("{} frames lost", dropped));
+            }
+        } else {
+            // The previous take has to finish writing first
+            if ui.add_enabled(view.take.is_none(), egui::Button::new("Record")).clicked() {
+                let bar = synth.transport.time_signature.bar_length();
+                requests.push(RecordingRequest::Record(recorder::RecordSettings {
+                    path: recorder::next_path(&view.directory, "take", "wav"),
+                    dry: view.dry,
+                    max_seconds: view.max_minutes * 60.0,
+                    punch: view.punch.then(|| {
+                        ((view.punch_in - 1) as f64 * bar, (view.punch_out - 1) as f64 * bar)
+                    }),
+                }));
+            }
+            ui.label(&view.status);
+        }
+    });
+    ui.horizontal(|ui| {
+        match synth.midi_capture.as_ref().map(|capture| capture.len()) {
+            Some(count) => {
+                if ui.button("Stop MIDI").clicked() {
+                    requests.push(RecordingRequest::StopMidi);
+                }
+                ui.label(format!("Recording MIDI, {} events", count));
+            }
+            None => {
+                if ui.button("Record MIDI").clicked() {
+                    requests.push(RecordingRequest::RecordMidi {
+                        tempo: synth.transport.tempo,
+                    });
+                }
+                ui.label(&view.midi_status);
+            }
+        }
+    });
+    ui.horizontal(|ui| {
+        ui.label("MIDI file:");
+        ui.text_edit_singleline(&mut view.midi_path);
+        match &synth.midi_player {
+            Some(player) => {
+                let (played, length) = player.progress(synth.clock);
+                if ui.button("Stop Playback").clicked() {
+                    requests.push(RecordingRequest::StopPlayback);
+                }
+                ui.label(format!("{:.1}s of {:.1}s", played, length));
+            }
+            // Plays through whatever patch is loaded now
+            None => {
+                if ui.button("Play").clicked() {
+                    requests.push(RecordingRequest::PlayMidi);
+                }
+            }
+        }
+    });
+    ui.add_enabled_ui(!synth.recorder.recording(), |ui| {
+        ui.horizontal(|ui| {
+            ui.label("Folder:");
+            ui.text_edit_singleline(&mut view.directory);
+        });
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut view.dry, "Also Record Dry Voices");
+            ui.add(egui::Slider::new(&mut view.max_minutes, 1.0..=120.0).text("Max Length (min)"));
+        });
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut view.punch, "Punch In/Out");
+            ui.add_enabled_ui(view.punch, |ui| {
+                ui.label("From bar");
+                ui.add(egui::DragValue::new(&mut view.punch_in).range(1..=999));
+                ui.label("to bar");
+                view.punch_out = view.punch_out.max(view.punch_in + 1);
+                ui.add(egui::DragValue::new(&mut view.punch_out).range(view.punch_in + 1..=1000));
+            });
+        });
+    });
+}
 
-            for note in notes {
-                match note {
-                    (freq, true) => synth.note_on(freq),
-                    (freq, false) => synth.note_off(freq),
+fn looper_ui(ui: &mut egui::Ui, synth: &mut Synth, requests: &mut Vec<looper::LoopRequest>) {
+    let transport = &synth.transport;
+    let bar = transport.time_signature.bar_length();
+    let beat = transport.time_signature.beat_length();
+    if !transport.playing {
+        ui.label("Loops record and play while the transport runs.");
+    }
+    let position = transport.position;
+    for (index, track) in synth.looper.tracks.iter_mut().enumerate() {
+        let mut request = |action| requests.push(looper::LoopRequest { track: index, action });
+        ui.horizontal(|ui| {
+            ui.label(format!("Loop {}", index + 1));
+            let state = track.state();
+            match state {
+                looper::LoopState::Empty => {
+                    ui.add(egui::DragValue::new(&mut track.bars).range(1..=looper::MAX_LOOP_BARS).suffix(" bars"));
+                    if ui.button("Record").clicked() {
+                        request(looper::LoopAction::Record);
+                    }
+                }
+                looper::LoopState::Armed { .. } => {
+                    ui.label("Waiting for the next bar");
+                }
+                looper::LoopState::Recording { start } if position < start => {
+                    ui.label("Waiting for the next bar");
+                }
+                looper::LoopState::Recording { start } => {
+                    ui.label(format!("Recording {:.0}%", (position - start) / track.length() * 100.0));
+                }
+                looper::LoopState::Playing | looper::LoopState::Overdubbing => {
+                    let overdubbing = state == looper::LoopState::Overdubbing;
+                    if ui.selectable_label(overdubbing, "Overdub").clicked() {
+                        request(looper::LoopAction::Overdub);
+                    }
+                    if ui.add_enabled(!overdubbing, egui::Button::new("x2")).clicked() {
+                        track.multiply(bar);
+                    }
+                    if ui.add_enabled(!overdubbing, egui::Button::new("/2")).clicked() {
+                        track.halve(beat);
+                    }
+                    ui.label(format!("{:.2} bars, {} layers", track.length() / bar, track.layer_count()));
+                }
+            }
+            if state != looper::LoopState::Empty {
+                if ui.button("Undo").clicked() {
+                    request(looper::LoopAction::Undo);
+                }
+                if ui.button("Clear").clicked() {
+                    request(looper::LoopAction::Clear);
                 }
             }
+            ui.checkbox(&mut track.muted, "Mute");
+            ui.add(egui::Slider::new(&mut track.volume.target, 0.0..=1.5).text("Volume"));
         });
+    }
+}
 
-        ctx.request_repaint();
+// Editor state of the step sequencer panel
+struct SequencerView {
+    // Step shown in the step editor
+    step: usize,
+    // Bottom row of the note grid
+    low_note: u8,
+}
+
+// Rows of the step sequencer's note grid
+const SEQUENCER_ROWS: u8 = 24;
+
+fn sequencer_ui(ui: &mut egui::Ui, synth: &mut Synth, view: &mut SequencerView) {
+    let targets = synth.parameter_targets();
+    let sequencer = &mut synth.sequencer;
+    ui.horizontal(|ui| {
+        ui.checkbox(&mut sequencer.enabled, "Play with Transport");
+        let selected_name = sequencer
+            .patterns
+            .get(sequencer.selected)
+            .map_or(String::new(), |pattern| pattern.name.clone());
+        egui::ComboBox::from_id_salt("sequencer_pattern")
+            .selected_text(selected_name)
+            .show_ui(ui, |ui| {
+                for index in 0..sequencer.patterns.len() {
+                    let name = sequencer.patterns[index].name.clone();
+                    ui.selectable_value(&mut sequencer.selected, index, name);
+                }
+            });
+        if ui.button("New").clicked() {
+            let name = format!("Pattern {}", sequencer.patterns.len() + 1);
+            sequencer.patterns.push(sequencer::Pattern::new(name));
+            sequencer.selected = sequencer.patterns.len() - 1;
+        }
+        if let Some(pattern) = sequencer.patterns.get(sequencer.selected) {
+            if ui.button("Duplicate").clicked() {
+                let mut copy = pattern.clone();
+                copy.name = format!("{} Copy", copy.name);
+                sequencer.patterns.push(copy);
+                sequencer.selected = sequencer.patterns.len() - 1;
+            }
+            if ui.button("Delete").clicked() {
+                sequencer.remove_pattern(sequencer.selected);
+            }
+        }
+    });
+
+    ui.horizontal(|ui| {
+        ui.label("Chain:");
+        let mut removed = None;
+        for (index, &entry) in sequencer.chain.iter().enumerate() {
+            let name = sequencer.patterns.get(entry).map_or("?", |pattern| pattern.name.as_str());
+            if ui.button(name).on_hover_text("Remove from the chain").clicked() {
+                removed = Some(index);
+            }
+        }
+        if let Some(index) = removed {
+            sequencer.chain.remove(index);
+        }
+        if sequencer.chain.is_empty() {
+            ui.label("(loops the selected pattern)");
+        }
+        if sequencer.selected < sequencer.patterns.len() && ui.button("Add Selected").clicked() {
+            sequencer.chain.push(sequencer.selected);
+        }
+    });
+
+    let playhead = sequencer
+        .playhead
+        .and_then(|(pattern, step)| (pattern == sequencer.selected).then_some(step));
+    let Some(pattern) = sequencer.patterns.get_mut(sequencer.selected) else {
+        return;
+    };
+    ui.horizontal(|ui| {
+        ui.text_edit_singleline(&mut pattern.name);
+        for length in sequencer::PATTERN_LENGTHS {
+            ui.radio_value(&mut pattern.length, length, format!("{length} Steps"));
+        }
+        ui.checkbox(&mut pattern.polyphonic, "Polyphonic");
+        ui.label("Rate:");
+        pattern.rate.ui(ui, "sequencer_rate");
+    });
+    ui.horizontal(|ui| {
+        if ui.button("Octave Down").clicked() {
+            view.low_note = view.low_note.saturating_sub(12);
+        }
+        if ui.button("Octave Up").clicked() {
+            view.low_note = (view.low_note + 12).min(128 - SEQUENCER_ROWS);
+        }
+        ui.label(format!(
+            "{} to {}",
+            scale::note_name(view.low_note),
+            scale::note_name(view.low_note + SEQUENCER_ROWS - 1)
+        ));
+    });
+    view.step = view.step.min(pattern.length - 1);
+    step_grid_ui(ui, pattern, playhead, view);
+
+    let step = &mut pattern.steps[view.step];
+    ui.horizontal(|ui| {
+        ui.label(format!("Step {}", view.step + 1));
+        ui.add(egui::Slider::new(&mut step.velocity, 0.0..=1.0).text("Velocity"));
+        ui.add(egui::Slider::new(&mut step.gate, 0.05..=1.0).text("Gate"));
+        ui.add(egui::Slider::new(&mut step.probability, 0.0..=1.0).text("Probability"));
+        ui.checkbox(&mut step.tie, "Tie");
+        if ui.button("Clear Step").clicked() {
+            *step = sequencer::Step::default();
+        }
+    });
+    let mut removed = None;
+    for (index, lock) in step.locks.iter_mut().enumerate() {
+        ui.horizontal(|ui| {
+            match targets.iter().find(|info| info.target == lock.target) {
+                Some(info) => {
+                    ui.label(&info.label);
+                    ui.add(egui::Slider::new(&mut lock.value, info.range.clone()));
+                }
+                None => {
+                    ui.label("(missing parameter)");
+                }
+            }
+            if ui.button("Remove").clicked() {
+                removed = Some(index);
+            }
+        });
+    }
+    if let Some(index) = removed {
+        step.locks.remove(index);
+    }
+    egui::ComboBox::from_id_salt("sequencer_lock")
+        .selected_text("Add Parameter Lock")
+        .show_ui(ui, |ui| {
+            for info in &targets {
+                let locked = step.locks.iter().any(|lock| lock.target == info.target);
+                if ui.add_enabled(!locked, egui::Button::new(info.label.as_str())).clicked() {
+                    step.locks.push(sequencer::ParameterLock {
+                        target: info.target.clone(),
+                        value: info.value,
+                    });
+                }
+            }
+        });
+}
+
+// Piano-roll grid of the pattern's steps. Clicking a cell toggles the note
+// and selects the step for the step editor.
+fn step_grid_ui(ui: &mut egui::Ui, pattern: &mut sequencer::Pattern, playhead: Option<usize>, view: &mut SequencerView) {
+    let columns = pattern.length;
+    let cell_width = (ui.available_width() / columns as f32).clamp(6.0, 24.0);
+    let cell_height = 10.0;
+    let size = egui::vec2(cell_width * columns as f32, cell_height * SEQUENCER_ROWS as f32);
+    let (response, painter) = ui.allocate_painter(size, egui::Sense::click());
+    let rect = response.rect;
+
+    let cell_rect = |column: usize, row: u8| {
+        let min = rect.min
+            + egui::vec2(
+                column as f32 * cell_width,
+                (SEQUENCER_ROWS - 1 - row) as f32 * cell_height,
+            );
+        egui::Rect::from_min_size(min, egui::vec2(cell_width, cell_height)).shrink(0.5)
+    };
+    for row in 0..SEQUENCER_ROWS {
+        let note = view.low_note + row;
+        let black_key = matches!(note % 12, 1 | 3 | 6 | 8 | 10);
+        for column in 0..columns {
+            // Beats are shaded alternately to make groups of four easy to count
+            let mut shade = if (column / 4) % 2 == 0 { 70 } else { 55 };
+            if black_key {
+                shade -= 20;
+            }
+            if Some(column) == playhead {
+                shade += 40;
+            }
+            let step = &pattern.steps[column];
+            let color = if step.notes.contains(&note) {
+                let level = (100.0 + 155.0 * step.velocity) as u8;
+                egui::Color32::from_rgb(level, level / 2, 40)
+            } else {
+                egui::Color32::from_gray(shade)
+            };
+            painter.rect_filled(cell_rect(column, row), 1.0, color);
+        }
+        if note.is_multiple_of(12) {
+            painter.text(
+                cell_rect(0, row).left_center(),
+                egui::Align2::LEFT_CENTER,
+                scale::note_name(note),
+                egui::FontId::proportional(8.0),
+                egui::Color32::LIGHT_GRAY,
+            );
+        }
+    }
+    let selected = cell_rect(view.step, SEQUENCER_ROWS - 1)
+        .union(cell_rect(view.step, 0))
+        .expand(0.5);
+    painter.rect_stroke(selected, 1.0, egui::Stroke::new(1.0, egui::Color32::WHITE));
+
+    if let Some(pointer) = response.interact_pointer_pos().filter(|_| response.clicked()) {
+        let offset = pointer - rect.min;
+        let column = ((offset.x / cell_width) as usize).min(columns - 1);
+        let row = SEQUENCER_ROWS - 1 - ((offset.y / cell_height) as u8).min(SEQUENCER_ROWS - 1);
+        let note = view.low_note + row;
+        let step = &mut pattern.steps[column];
+        if step.notes.contains(&note) {
+            step.notes.retain(|&existing| existing != note);
+        } else if pattern.polyphonic {
+            step.notes.push(note);
+        } else {
+            step.notes = vec![note];
+        }
+        view.step = column;
+    }
+}
+
+fn effect_stack_ui(
+    ui: &mut egui::Ui,
+    stack: &mut EffectStack,
+    address: &EffectAddress,
+    editor: &mut EffectEditor,
+) {
+    let stack_id = egui::Id::new(address);
+    let slot_count = stack.slots.len();
+    let mut slot_actions = Vec::new();
+    for (index, slot) in stack.slots.iter_mut().enumerate() {
+        let response = ui.group(|ui| {
+            ui.horizontal(|ui| {
+                let payload = SlotDrag {
+                    stack: stack_id,
+                    index,
+                };
+                ui.dnd_drag_source(stack_id.with(index), payload, |ui| {
+                    ui.label("☰");
+                });
+                ui.checkbox(&mut slot.bypass, "Bypass");
+                if ui.add_enabled(index > 0, egui::Button::new("⬆")).clicked() {
+                    slot_actions.push((index, SlotAction::MoveTo(index - 1)));
+                }
+                if ui.add_enabled(index + 1 < slot_count, egui::Button::new("⬇")).clicked() {
+                    slot_actions.push((index, SlotAction::MoveTo(index + 1)));
+                }
+                if ui.button("Duplicate").clicked() {
+                    slot_actions.push((index, SlotAction::Duplicate));
+                }
+                if ui.button("Remove").clicked() {
+                    slot_actions.push((index, SlotAction::Remove));
+                }
+            });
+            effect_ui(ui, &mut slot.effect, &address.child(index), editor);
+            ui.horizontal(|ui| {
+                ui.add(egui::Slider::new(&mut slot.mix.target, 0.0..=1.0).text("Slot Dry/Wet"));
+                let mut gain_db = 20.0 * slot.gain.target.max(1e-4).log10();
+                if ui
+                    .add(egui::Slider::new(&mut gain_db, -48.0..=12.0).text("Output Gain (dB)"))
+                    .changed()
+                {
+                    slot.gain.target = 10.0f32.powf(gain_db / 20.0);
+                }
+            });
+        });
+        // Dropping a slot's handle onto another slot of the same stack moves it there
+        if let Some(drag) = response.response.dnd_release_payload::<SlotDrag>() {
+            if drag.stack == stack_id && drag.index != index {
+                slot_actions.push((drag.index, SlotAction::MoveTo(index)));
+            }
+        }
+    }
+    editor
+        .slot_actions
+        .extend(slot_actions.into_iter().map(|(index, action)| (address.child(index), action)));
+}
+
+fn effect_ui(ui: &mut egui::Ui, effect: &mut Effect, address: &EffectAddress, editor: &mut EffectEditor) {
+    let index = address.path.last().copied().unwrap_or(0);
+    let id = egui::Id::new(address);
+    match effect {
+        Effect::Delay(params) => {
+            ui.label(format!("Delay {}", index + 1));
+            ui.horizontal(|ui| {
+                let mut synced = params.sync.is_some();
+                if ui.checkbox(&mut synced, "Tempo Sync").changed() {
+                    params.sync = synced.then(|| {
+                        NoteDivision::new(NoteValue::Eighth, NoteModifier::Dotted)
+                    });
+                }
+                match &mut params.sync {
+                    Some(division) => {
+                        division.ui(ui, id.with("delay_division"));
+                    }
+                    None => {
+                        ui.add(egui::Slider::new(&mut params.delay_time.target, 0.001..=MAX_DELAY_TIME).text("Delay Time"));
+                    }
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.radio_value(&mut params.mode, DelayMode::Mono, "Mono");
+                ui.radio_value(&mut params.mode, DelayMode::Stereo, "Stereo");
+                ui.radio_value(&mut params.mode, DelayMode::PingPong, "Ping-Pong");
+            });
+            ui.add(egui::Slider::new(&mut params.glide, 0.0..=2.0).text("Time Glide"));
+            ui.add(egui::Slider::new(&mut params.spread.target, -0.5..=0.5).text("Stereo Offset"));
+            ui.add(egui::Slider::new(&mut params.feedback.target, 0.0..=0.95).text("Feedback"));
+            ui.add(egui::Slider::new(&mut params.low_cut.target, 20.0..=2000.0).logarithmic(true).text("Feedback Low Cut"));
+            ui.add(egui::Slider::new(&mut params.high_cut.target, 500.0..=20000.0).logarithmic(true).text("Feedback High Cut"));
+            ui.add(egui::Slider::new(&mut params.mix.target, 0.0..=1.0).text("Mix"));
+        },
+        Effect::Distortion(params) => {
+            ui.horizontal(|ui| {
+                ui.label(format!("Distortion {}", index + 1));
+                egui::ComboBox::from_id_salt(id.with("distortion_curve"))
+                    .selected_text(params.curve.label())
+                    .show_ui(ui, |ui| {
+                        for curve in distortion::ShaperCurve::ALL {
+                            ui.selectable_value(&mut params.curve, curve, curve.label());
+                        }
+                    });
+                egui::ComboBox::from_id_salt(id.with("oversampling"))
+                    .selected_text(format!("Oversampling {}", params.oversampling.label()))
+                    .show_ui(ui, |ui| {
+                        for oversampling in distortion::Oversampling::ALL {
+                            ui.selectable_value(&mut params.oversampling, oversampling, oversampling.label());
+                        }
+                    });
+            });
+            ui.horizontal(|ui| {
+                transfer_curve_ui(ui, params, id.with("transfer_curve"));
+                ui.vertical(|ui| {
+                    if params.curve != distortion::ShaperCurve::Custom && ui.button("Edit as Custom").clicked() {
+                        let count = params.custom_curve.len().max(2);
+                        let curve = params.curve;
+                        params.custom_curve = (0..count)
+                            .map(|i| {
+                                let input = i as f32 / (count - 1) as f32 * 2.0 - 1.0;
+                                distortion::shape(curve, input, &[])
+                            })
+                            .collect();
+                        params.curve = distortion::ShaperCurve::Custom;
+                    }
+                    if params.curve == distortion::ShaperCurve::Custom {
+                        ui.label("Drag on the curve to draw");
+                    }
+                });
+            });
+            ui.add(egui::Slider::new(&mut params.drive.target, 1.0..=50.0).logarithmic(true).text("Drive"));
+            ui.add(egui::Slider::new(&mut params.pre_cut.target, 20.0..=2000.0).logarithmic(true).text("Pre High-Pass"));
+            ui.add(egui::Slider::new(&mut params.post_cut.target, 500.0..=20000.0).logarithmic(true).text("Post Low-Pass"));
+            ui.add(egui::Slider::new(&mut params.mix.target, 0.0..=1.0).text("Mix"));
+        },
+        Effect::BitCrusher(params) => {
+            ui.label(format!("Bit Crusher {}", index + 1));
+            ui.add(egui::Slider::new(&mut params.bits.target, 1.0..=16.0).text("Bits"));
+            ui.add(egui::Slider::new(&mut params.rate.target, 100.0..=editor.sample_rate).logarithmic(true).text("Sample Rate"));
+            ui.add(egui::Slider::new(&mut params.mix.target, 0.0..=1.0).text("Mix"));
+        },
+        Effect::Filter(params) => {
+            ui.label(format!("Filter {}", index + 1));
+            ui.add(egui::Slider::new(&mut params.cutoff.target, 20.0..=20000.0).logarithmic(true).text("Cutoff"));
+            ui.add(egui::Slider::new(&mut params.resonance, 0.0..=0.99).text("Resonance"));
+            ui.add(egui::Slider::new(&mut params.mix.target, 0.0..=1.0).text("Mix"));
+        },
+        Effect::Tremolo(params) => {
+            ui.label(format!("Tremolo {}", index + 1));
+            lfo_ui(ui, &mut params.lfo, id.with("tremolo_lfo"));
+            ui.add(egui::Slider::new(&mut params.depth.target, 0.0..=1.0).text("Depth"));
+            ui.add(egui::Slider::new(&mut params.mix.target, 0.0..=1.0).text("Mix"));
+        },
+        Effect::Chorus(params) => {
+            ui.horizontal(|ui| {
+                ui.label(format!("Chorus {}", index + 1));
+                egui::ComboBox::from_id_salt(id.with("chorus_interpolation"))
+                    .selected_text(params.interpolation.label())
+                    .show_ui(ui, |ui| {
+                        for interpolation in modulation::Interpolation::ALL {
+                            ui.selectable_value(&mut params.interpolation, interpolation, interpolation.label());
+                        }
+                    });
+                for mode in modulation::JunoMode::ALL {
+                    if ui.button(mode.label()).clicked() {
+                        params.apply_juno(mode);
+                    }
+                }
+            });
+            lfo_ui(ui, &mut params.lfo, id.with("chorus_lfo"));
+            ui.add(egui::Slider::new(&mut params.voices, 1..=modulation::MAX_CHORUS_VOICES).text("Voices"));
+            let max_time = modulation::MAX_CHORUS_DELAY * 1000.0 * 0.6;
+            let mut delay_ms = params.delay.target * 1000.0;
+            if ui.add(egui::Slider::new(&mut delay_ms, 0.5..=max_time).text("Base Delay (ms)")).changed() {
+                params.delay.target = delay_ms / 1000.0;
+            }
+            let mut depth_ms = params.depth.target * 1000.0;
+            if ui.add(egui::Slider::new(&mut depth_ms, 0.0..=modulation::MAX_CHORUS_DELAY * 1000.0 * 0.4).text("Depth (ms)")).changed() {
+                params.depth.target = depth_ms / 1000.0;
+            }
+            ui.add(egui::Slider::new(&mut params.feedback.target, -0.9..=0.9).text("Feedback"));
+            ui.add(egui::Slider::new(&mut params.spread.target, 0.0..=0.5).text("Stereo Spread"));
+            ui.add(egui::Slider::new(&mut params.mix.target, 0.0..=1.0).text("Mix"));
+        },
+        Effect::Flanger(params) => {
+            ui.horizontal(|ui| {
+                ui.label(format!("Flanger {}", index + 1));
+                ui.checkbox(&mut params.through_zero, "Through Zero");
+            });
+            lfo_ui(ui, &mut params.lfo, id.with("flanger_lfo"));
+            let max_time = modulation::MAX_FLANGER_DELAY * 1000.0 / 2.0;
+            let mut delay_ms = params.delay.target * 1000.0;
+            if ui.add(egui::Slider::new(&mut delay_ms, 0.1..=max_time).text("Delay (ms)")).changed() {
+                params.delay.target = delay_ms / 1000.0;
+            }
+            let mut depth_ms = params.depth.target * 1000.0;
+            if ui.add(egui::Slider::new(&mut depth_ms, 0.0..=max_time).text("Depth (ms)")).changed() {
+                params.depth.target = depth_ms / 1000.0;
+            }
+            ui.add(egui::Slider::new(&mut params.feedback.target, -0.95..=0.95).text("Feedback"));
+            ui.add(egui::Slider::new(&mut params.stereo_phase.target, 0.0..=0.5).text("Stereo Phase"));
+            ui.add(egui::Slider::new(&mut params.mix.target, 0.0..=1.0).text("Mix"));
+        },
+        Effect::Phaser(params) => {
+            ui.horizontal(|ui| {
+                ui.label(format!("Phaser {}", index + 1));
+                for stages in [4, 8, 12] {
+                    ui.radio_value(&mut params.stages, stages, format!("{stages} Stages"));
+                }
+            });
+            lfo_ui(ui, &mut params.lfo, id.with("phaser_lfo"));
+            ui.add(egui::Slider::new(&mut params.min_frequency.target, 20.0..=5000.0).logarithmic(true).text("Sweep Low"));
+            ui.add(egui::Slider::new(&mut params.max_frequency.target, 100.0..=15000.0).logarithmic(true).text("Sweep High"));
+            ui.add(egui::Slider::new(&mut params.feedback.target, -0.95..=0.95).text("Feedback"));
+            ui.add(egui::Slider::new(&mut params.stereo_phase.target, 0.0..=0.5).text("Stereo Phase"));
+            ui.add(egui::Slider::new(&mut params.mix.target, 0.0..=1.0).text("Mix"));
+        },
+        Effect::Reverb(params) => {
+            ui.label(format!("Reverb {}", index + 1));
+            ui.add(egui::Slider::new(&mut params.size.target, 0.1..=MAX_REVERB_SIZE).text("Size"));
+            ui.add(egui::Slider::new(&mut params.decay.target, 0.1..=20.0).logarithmic(true).text("Decay Time"));
+            ui.add(egui::Slider::new(&mut params.damping.target, 0.0..=0.95).text("Damping"));
+            ui.add(egui::Slider::new(&mut params.pre_delay.target, 0.0..=MAX_PRE_DELAY).text("Pre-Delay"));
+            ui.add(egui::Slider::new(&mut params.early_level.target, 0.0..=1.0).text("Early Reflections"));
+            ui.add(egui::Slider::new(&mut params.width.target, 0.0..=1.0).text("Width"));
+            ui.add(egui::Slider::new(&mut params.mix.target, 0.0..=1.0).text("Mix"));
+        },
+        Effect::RingMod(params) => {
+            ui.label(format!("Ring Modulator {}", index + 1));
+            ui.add(egui::Slider::new(&mut params.frequency.target, 1.0..=2000.0)
+                .logarithmic(true)
+                .text("Frequency"));
+            ui.add(egui::Slider::new(&mut params.mix.target, 0.0..=1.0).text("Mix"));
+        },
+        Effect::Convolution(params) => {
+            ui.label(format!("Convolution {}", index + 1));
+            ui.horizontal(|ui| {
+                ui.label("Impulse WAV:");
+                ui.text_edit_singleline(&mut params.path);
+                if ui.button("Load").clicked() {
+                    editor.impulse_requests.push(convolution::ImpulseRequest {
+                        address: address.clone(),
+                        path: params.path.clone(),
+                        impulse: None,
+                        settings: params.settings,
+                        sample_rate: editor.sample_rate,
+                    });
+                }
+            });
+            ui.label(&params.status);
+            let duration = params.impulse.as_ref().map_or(10.0, |impulse| impulse.duration());
+            ui.add(egui::Slider::new(&mut params.settings.trim_start, 0.0..=duration).text("Trim Start"));
+            ui.add(egui::Slider::new(&mut params.settings.length, 0.0..=duration).text("Length (0 = full)"));
+            ui.add(egui::Slider::new(&mut params.settings.stretch, 0.25..=4.0).logarithmic(true).text("Stretch"));
+            if let Some(impulse) = &params.impulse {
+                if ui.button("Apply Trim/Stretch").clicked() {
+                    editor.impulse_requests.push(convolution::ImpulseRequest {
+                        address: address.clone(),
+                        path: params.path.clone(),
+                        impulse: Some(impulse.clone()),
+                        settings: params.settings,
+                        sample_rate: editor.sample_rate,
+                    });
+                }
+            }
+            ui.add(egui::Slider::new(&mut params.level.target, 0.0..=4.0).text("Level"));
+            ui.add(egui::Slider::new(&mut params.mix.target, 0.0..=1.0).text("Mix"));
+        },
+        Effect::Split(params) => {
+            ui.horizontal(|ui| {
+                ui.label(format!("Split {}", index + 1));
+                ui.radio_value(&mut params.mode, SplitMode::Parallel, "Parallel");
+                ui.radio_value(&mut params.mode, SplitMode::Bands, "Frequency Bands");
+            });
+            let branch_count = params.branches.len();
+            let mut removed_branch = None;
+            for (branch_index, branch) in params.branches.iter_mut().enumerate() {
+                ui.group(|ui| {
+                    ui.horizontal(|ui| {
+                        ui.label(format!("Branch {}", branch_index + 1));
+                        ui.add(egui::Slider::new(&mut branch.level.target, 0.0..=2.0).text("Level"));
+                        if branch_count > 1 && ui.button("Remove Branch").clicked() {
+                            removed_branch = Some(branch_index);
+                        }
+                        ui.menu_button("Add Effect", |ui| {
+                            add_effect_buttons(ui, &mut branch.effects, editor);
+                        });
+                    });
+                    if params.mode == SplitMode::Bands && branch_index + 1 < branch_count {
+                        ui.add(
+                            egui::Slider::new(&mut params.crossovers[branch_index], 20.0..=20000.0)
+                                .logarithmic(true)
+                                .text("Crossover"),
+                        );
+                    }
+                    let branch_address = address.child(branch_index);
+                    effect_stack_ui(ui, &mut branch.effects, &branch_address, editor);
+                });
+            }
+            if let Some(branch_index) = removed_branch {
+                editor
+                    .slot_actions
+                    .push((address.clone(), SlotAction::RemoveBranch(branch_index)));
+            }
+            if ui.button("Add Branch").clicked() {
+                params.add_branch();
+            }
+            // Keep crossovers ascending so every band is non-empty
+            for i in 1..params.crossovers.len() {
+                params.crossovers[i] = params.crossovers[i].max(params.crossovers[i - 1]);
+            }
+        },
+        Effect::Send(params) => {
+            ui.horizontal(|ui| {
+                ui.label(format!("Send {}", index + 1));
+                let selected = editor
+                    .bus_names
+                    .get(params.bus)
+                    .cloned()
+                    .unwrap_or_else(|| "(missing bus)".to_string());
+                egui::ComboBox::from_id_salt(id.with("send_bus"))
+                    .selected_text(selected)
+                    .show_ui(ui, |ui| {
+                        for (bus, name) in editor.bus_names.iter().enumerate() {
+                            ui.selectable_value(&mut params.bus, bus, name);
+                        }
+                    });
+            });
+            ui.add(egui::Slider::new(&mut params.level.target, 0.0..=1.0).text("Send Level"));
+        },
+        Effect::Compressor(params) => {
+            ui.horizontal(|ui| {
+                ui.label(format!("Compressor {}", index + 1));
+                ui.checkbox(&mut params.sidechain, "Sidechain from Voices");
+            });
+            ui.add(egui::Slider::new(&mut params.threshold.target, -60.0..=0.0).text("Threshold (dB)"));
+            ui.add(egui::Slider::new(&mut params.ratio.target, 1.0..=20.0).logarithmic(true).text("Ratio"));
+            ui.add(egui::Slider::new(&mut params.knee, 0.0..=24.0).text("Knee (dB)"));
+            ui.add(egui::Slider::new(&mut params.attack, 0.0001..=0.2).logarithmic(true).text("Attack"));
+            ui.add(egui::Slider::new(&mut params.release, 0.01..=2.0).logarithmic(true).text("Release"));
+            ui.add(egui::Slider::new(&mut params.makeup.target, 0.0..=24.0).text("Makeup (dB)"));
+            gain_reduction_meter(ui, params.gain_reduction);
+        },
+        Effect::Expander(params) => {
+            ui.horizontal(|ui| {
+                ui.label(format!("Expander/Gate {}", index + 1));
+                ui.checkbox(&mut params.sidechain, "Sidechain from Voices");
+            });
+            ui.add(egui::Slider::new(&mut params.threshold.target, -80.0..=0.0).text("Threshold (dB)"));
+            ui.add(egui::Slider::new(&mut params.ratio.target, 1.0..=100.0).logarithmic(true).text("Ratio"));
+            ui.add(egui::Slider::new(&mut params.range.target, 0.0..=96.0).text("Range (dB)"));
+            ui.add(egui::Slider::new(&mut params.attack, 0.0001..=0.1).logarithmic(true).text("Attack"));
+            ui.add(egui::Slider::new(&mut params.hold, 0.0..=0.5).text("Hold"));
+            ui.add(egui::Slider::new(&mut params.release, 0.01..=2.0).logarithmic(true).text("Release"));
+            gain_reduction_meter(ui, params.gain_reduction);
+        },
+        Effect::Eq(params) => {
+            ui.label(format!("EQ {}", index + 1));
+            let spectrum = editor.spectra.entry(address.clone()).or_insert_with(eq::SpectrumView::new);
+            params.analyzer.copy_to(spectrum);
+            eq_curve_ui(ui, params, &spectrum.levels, id, editor.sample_rate);
+            let mut removed_band = None;
+            for (band_index, band) in params.bands.iter_mut().enumerate() {
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut band.enabled, format!("Band {}", band_index + 1));
+                    egui::ComboBox::from_id_salt(id.with(("eq_kind", band_index)))
+                        .selected_text(band.kind.label())
+                        .show_ui(ui, |ui| {
+                            for kind in BiquadKind::ALL {
+                                ui.selectable_value(&mut band.kind, kind, kind.label());
+                            }
+                        });
+                    ui.add(egui::Slider::new(&mut band.frequency.target, EQ_MIN_FREQUENCY..=EQ_MAX_FREQUENCY).logarithmic(true).text("Hz"));
+                    ui.add(egui::Slider::new(&mut band.q.target, 0.1..=18.0).logarithmic(true).text("Q"));
+                    ui.add_enabled(
+                        band.kind.has_gain(),
+                        egui::Slider::new(&mut band.gain.target, -EQ_MAX_GAIN..=EQ_MAX_GAIN).text("dB"),
+                    );
+                    if ui.button("Remove").clicked() {
+                        removed_band = Some(band_index);
+                    }
+                });
+            }
+            if let Some(band_index) = removed_band {
+                params.bands.remove(band_index);
+            }
+            if ui.button("Add Band").clicked() {
+                params.bands.push(eq::EqBand::new(BiquadKind::Peaking, 1000.0, 1.0, 0.0));
+            }
+        },
+        Effect::TransientShaper(params) => {
+            ui.label(format!("Transient Shaper {}", index + 1));
+            ui.add(egui::Slider::new(&mut params.attack.target, -1.0..=1.0).text("Attack"));
+            ui.add(egui::Slider::new(&mut params.sustain.target, -1.0..=1.0).text("Sustain"));
+            ui.add(egui::Slider::new(&mut params.output.target, -24.0..=24.0).text("Output (dB)"));
+            let change = params.gain_change;
+            ui.add(
+                egui::ProgressBar::new((change.abs() / 24.0).min(1.0))
+                    .text(format!("Gain {:+.1} dB", change)),
+            );
+        },
+    }
+}
+
+fn lfo_ui(ui: &mut egui::Ui, lfo: &mut modulation::Lfo, id: egui::Id) {
+    ui.horizontal(|ui| {
+        egui::ComboBox::from_id_salt(id.with("shape"))
+            .selected_text(lfo.shape.label())
+            .show_ui(ui, |ui| {
+                for shape in modulation::LfoShape::ALL {
+                    ui.selectable_value(&mut lfo.shape, shape, shape.label());
+                }
+            });
+        let mut synced = lfo.sync.is_some();
+        if ui.checkbox(&mut synced, "Tempo Sync").changed() {
+            lfo.sync = synced.then(|| NoteDivision::new(NoteValue::Whole, NoteModifier::Straight));
+        }
+        match &mut lfo.sync {
+            Some(division) => {
+                division.ui(ui, id.with("division"));
+            }
+            None => {
+                ui.add(egui::Slider::new(&mut lfo.rate.target, 0.01..=20.0).logarithmic(true).text("Rate (Hz)"));
+            }
+        }
+    });
+}
+
+// Transfer curve over -1..1 on both axes; for a custom curve dragging
+// paints every point passed since the previous frame
+fn transfer_curve_ui(ui: &mut egui::Ui, params: &mut distortion::DistortionParameters, id: egui::Id) {
+    let (response, painter) = ui.allocate_painter(egui::vec2(140.0, 140.0), egui::Sense::drag());
+    let rect = response.rect;
+    let to_screen = |x: f32, y: f32| egui::pos2(rect.center().x + x * rect.width() * 0.5, rect.center().y - y * rect.height() * 0.5);
+
+    painter.rect_filled(rect, 2.0, egui::Color32::from_gray(20));
+    let grid = egui::Stroke::new(1.0, egui::Color32::from_gray(50));
+    painter.line_segment([to_screen(-1.0, 0.0), to_screen(1.0, 0.0)], grid);
+    painter.line_segment([to_screen(0.0, -1.0), to_screen(0.0, 1.0)], grid);
+
+    let editable = params.curve == distortion::ShaperCurve::Custom && params.custom_curve.len() >= 2;
+    if editable && response.dragged() {
+        if let Some(pointer) = response.interact_pointer_pos() {
+            let last = params.custom_curve.len() - 1;
+            let x = ((pointer.x - rect.left()) / rect.width()).clamp(0.0, 1.0);
+            let y = ((rect.center().y - pointer.y) / (rect.height() * 0.5)).clamp(-1.0, 1.0);
+            let index = (x * last as f32).round() as usize;
+            let previous = ui.data(|data| data.get_temp::<(usize, f32)>(id)).unwrap_or((index, y));
+            let (from, to) = (previous.0.min(index), previous.0.max(index));
+            for point in from..=to {
+                let t = if to == from { 1.0 } else { (point - from) as f32 / (to - from) as f32 };
+                let t = if previous.0 <= index { t } else { 1.0 - t };
+                params.custom_curve[point] = previous.1 + (y - previous.1) * t;
+            }
+            ui.data_mut(|data| data.insert_temp(id, (index, y)));
+        }
+    } else {
+        ui.data_mut(|data| data.remove::<(usize, f32)>(id));
+    }
+
+    let points: Vec<egui::Pos2> = (0..=rect.width() as usize)
+        .map(|i| {
+            let x = i as f32 / rect.width() * 2.0 - 1.0;
+            let y = distortion::shape(params.curve, x, &params.custom_curve);
+            to_screen(x, y.clamp(-1.0, 1.0))
+        })
+        .collect();
+    painter.add(egui::Shape::line(points, egui::Stroke::new(2.0, egui::Color32::LIGHT_BLUE)));
+}
+
+const EQ_MIN_FREQUENCY: f32 = 20.0;
+const EQ_MAX_FREQUENCY: f32 = 20000.0;
+const EQ_MAX_GAIN: f32 = 24.0;
+// Level range of the spectrum drawn behind the curve
+const EQ_SPECTRUM_FLOOR: f32 = -96.0;
+
+// Frequency response of all enabled bands over the live output spectrum.
+// Band handles drag frequency and gain; scrolling over a handle changes Q.
+fn eq_curve_ui(ui: &mut egui::Ui, params: &mut eq::EqParameters, spectrum: &[f32], id: egui::Id, sample_rate: f32) {
+    let size = egui::vec2(ui.available_width().min(600.0), 180.0);
+    let (response, painter) = ui.allocate_painter(size, egui::Sense::hover());
+    let rect = response.rect;
+    let log_range = (EQ_MAX_FREQUENCY / EQ_MIN_FREQUENCY).ln();
+    let x_for = |frequency: f32| rect.left() + rect.width() * (frequency / EQ_MIN_FREQUENCY).ln() / log_range;
+    let frequency_for = |x: f32| EQ_MIN_FREQUENCY * (log_range * (x - rect.left()) / rect.width()).exp();
+    let y_for = |gain: f32| rect.center().y - gain / EQ_MAX_GAIN * rect.height() * 0.5;
+    let gain_for = |y: f32| (rect.center().y - y) / (rect.height() * 0.5) * EQ_MAX_GAIN;
+
+    painter.rect_filled(rect, 2.0, egui::Color32::from_gray(20));
+    let grid = egui::Stroke::new(1.0, egui::Color32::from_gray(50));
+    for frequency in [100.0, 1000.0, 10000.0] {
+        let x = x_for(frequency);
+        painter.line_segment([egui::pos2(x, rect.top()), egui::pos2(x, rect.bottom())], grid);
     }
+    for gain in [-12.0, 0.0, 12.0] {
+        let y = y_for(gain);
+        painter.line_segment([egui::pos2(rect.left(), y), egui::pos2(rect.right(), y)], grid);
+    }
+
+    if spectrum.len() >= 2 {
+        let bin_width = sample_rate / eq::ANALYZER_SIZE as f32;
+        let points: Vec<egui::Pos2> = (0..rect.width() as usize)
+            .map(|i| {
+                let x = rect.left() + i as f32;
+                let bin = (frequency_for(x) / bin_width).min(spectrum.len() as f32 - 2.0);
+                let index = bin as usize;
+                let fraction = bin - index as f32;
+                let level = spectrum[index] + (spectrum[index + 1] - spectrum[index]) * fraction;
+                let height = ((level - EQ_SPECTRUM_FLOOR) / -EQ_SPECTRUM_FLOOR).clamp(0.0, 1.0);
+                egui::pos2(x, rect.bottom() - height * rect.height())
+            })
+            .collect();
+        painter.add(egui::Shape::line(points, egui::Stroke::new(1.0, egui::Color32::from_gray(90))));
+    }
+
+    let filters: Vec<Biquad> = params
+        .bands
+        .iter()
+        .filter(|band| band.enabled)
+        .map(|band| band.target_filter(sample_rate))
+        .collect();
+    let curve: Vec<egui::Pos2> = (0..rect.width() as usize)
+        .map(|i| {
+            let x = rect.left() + i as f32;
+            let frequency = frequency_for(x);
+            let magnitude: f32 = filters.iter().map(|filter| filter.magnitude(frequency, sample_rate)).product();
+            let gain = 20.0 * magnitude.max(1e-6).log10();
+            egui::pos2(x, y_for(gain).clamp(rect.top(), rect.bottom()))
+        })
+        .collect();
+    painter.add(egui::Shape::line(curve, egui::Stroke::new(2.0, egui::Color32::LIGHT_BLUE)));
+
+    for (band_index, band) in params.bands.iter_mut().enumerate() {
+        let gain = if band.kind.has_gain() { band.gain.target } else { 0.0 };
+        let center = egui::pos2(x_for(band.frequency.target), y_for(gain));
+        let handle = ui.interact(
+            egui::Rect::from_center_size(center, egui::vec2(14.0, 14.0)),
+            id.with(("eq_handle", band_index)),
+            egui::Sense::drag(),
+        );
+        if handle.dragged() {
+            if let Some(pointer) = handle.interact_pointer_pos() {
+                band.frequency.target = frequency_for(pointer.x).clamp(EQ_MIN_FREQUENCY, EQ_MAX_FREQUENCY);
+                if band.kind.has_gain() {
+                    band.gain.target = gain_for(pointer.y).clamp(-EQ_MAX_GAIN, EQ_MAX_GAIN);
+                }
+            }
+        }
+        if handle.hovered() {
+            let scroll = ui.input(|i| i.raw_scroll_delta.y);
+            if scroll != 0.0 {
+                band.q.target = (band.q.target * (scroll * 0.005).exp()).clamp(0.1, 18.0);
+            }
+        }
+        let color = if !band.enabled {
+            egui::Color32::DARK_GRAY
+        } else if handle.hovered() || handle.dragged() {
+            egui::Color32::WHITE
+        } else {
+            egui::Color32::GOLD
+        };
+        painter.circle_filled(center, 5.0, color);
+        painter.text(
+            center + egui::vec2(7.0, -7.0),
+            egui::Align2::LEFT_BOTTOM,
+            (band_index + 1).to_string(),
+            egui::FontId::proportional(10.0),
+            color,
+        );
+    }
+}
+
+fn gain_reduction_meter(ui: &mut egui::Ui, reduction: f32) {
+    ui.add(
+        egui::ProgressBar::new((reduction / 24.0).clamp(0.0, 1.0))
+            .text(format!("GR {:.1} dB", reduction)),
+    );
+}
+
+// Opens the default output device and starts a synth playing through it
+fn start_audio() -> Result<(Arc<Mutex<Synth>>, Stream), String> {
+    let host = cpal::default_host();
+    let device = host.default_output_device().ok_or("No audio output device found")?;
+    let config = device
+        .default_output_config()
+        .map_err(|err| format!("Failed to query the audio output: {}", err))?;
+    let sample_rate = config.sample_rate().0 as f32;
+
+    let synth = Arc::new(Mutex::new(Synth::new(sample_rate)));
+
+    let stream = match config.sample_format() {
+        SampleFormat::F32 => create_stream(&device, &config.into(), synth.clone()),
+        //SampleFormat::I16 => create_stream::<i16>(&device, &config.into(), synth.clone()),
+        //SampleFormat::U16 => create_stream::<u16>(&device, &config.into(), synth.clone()),
+        format => return Err(format!("Unsupported audio sample format {}", format)),
+    }
+    .map_err(|err| format!("Failed to open the audio stream: {}", err))?;
+
+    stream
+        .play()
+        .map_err(|err| format!("Failed to start the audio stream: {}", err))?;
+    Ok((synth, stream))
 }
 
 fn create_stream(
@@ -980,12 +4334,25 @@ fn create_stream(
     config: &cpal::StreamConfig,
     synth: Arc<Mutex<Synth>>,
 ) -> Result<Stream, cpal::BuildStreamError> {
+    let channels = config.channels as usize;
     device.build_output_stream(
         config,
         move |data: &mut [_], _: &cpal::OutputCallbackInfo| {
             let mut synth = synth.lock().unwrap();
-            for sample in data.iter_mut() {
-                *sample = synth.get_next_sample();
+            for frame in data.chunks_mut(channels) {
+                let [left, right] = synth.get_next_frame();
+                let position = synth.transport.playing.then_some(synth.transport.position);
+                let dry = synth.voice_sum;
+                synth.recorder.capture([left, right], dry, position);
+                match frame {
+                    [mono] => *mono = (left + right) * 0.5,
+                    [first, second, rest @ ..] => {
+                        *first = left;
+                        *second = right;
+                        rest.fill(0.0);
+                    }
+                    [] => {}
+                }
             }
         },
         |err| eprintln!("Error in audio stream: {}", err),
@@ -993,11 +4360,16 @@ fn create_stream(
     )
 }
 
-fn main() -> Result<(), eframe::Error> {
+fn run_gui() -> Result<(), String> {
     let options = eframe::NativeOptions::default();
     eframe::run_native(
         "Synthesizer",
         options,
-        Box::new(|cc| Ok(Box::new(SynthApp::new(cc)))),
+        Box::new(|cc| Ok(Box::new(SynthApp::new(cc)?))),
     )
+    .map_err(|err| format!("Failed to open the window: {}", err))
+}
+
+fn main() -> std::process::ExitCode {
+    cli::main()
 }
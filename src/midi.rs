@@ -0,0 +1,235 @@
+use crate::smf::{MidiFile, TimedMessage};
+use crate::Synth;
+use midir::{Ignore, MidiInput, MidiInputConnection};
+use std::sync::{Arc, Mutex};
+
+pub struct MidiConnection {
+    pub port_name: String,
+    _connection: MidiInputConnection<RegisteredParameters>,
+}
+
+// Connects to the first available MIDI input port, if there is one
+pub fn connect(synth: Arc<Mutex<Synth>>) -> Option<MidiConnection> {
+    let mut input = MidiInput::new("synth").ok()?;
+    input.ignore(Ignore::SysexAndActiveSense);
+
+    let ports = input.ports();
+    let port = ports.first()?;
+    let port_name = input.port_name(port).unwrap_or_else(|_| "Unknown".to_string());
+
+    let connection = input
+        .connect(
+            port,
+            "synth-input",
+            move |timestamp, message, parameters| {
+                let mut synth = synth.lock().unwrap();
+                // Only live input is recorded, not files played back
+                if matches!(message.first(), Some(0x80..=0xEF)) {
+                    synth.capture(message);
+                }
+                handle_message(&mut synth, parameters, message, timestamp);
+            },
+            RegisteredParameters::default(),
+        )
+        .ok()?;
+
+    Some(MidiConnection {
+        port_name,
+        _connection: connection,
+    })
+}
+
+// Names of the MIDI input ports, in the order `connect` tries them
+pub fn input_ports() -> Result<Vec<String>, String> {
+    let input = MidiInput::new("synth").map_err(|err| format!("Failed to open MIDI input: {}", err))?;
+    Ok(input
+        .ports()
+        .iter()
+        .map(|port| input.port_name(port).unwrap_or_else(|_| "Unknown".to_string()))
+        .collect())
+}
+
+// Registered parameter number currently selected on each channel
+#[derive(Default)]
+pub struct RegisteredParameters {
+    selected: [(u8, u8); 16],
+}
+
+// Applies one message to the synth; `timestamp` is in microseconds
+pub fn handle_message(
+    synth: &mut Synth,
+    parameters: &mut RegisteredParameters,
+    message: &[u8],
+    timestamp: u64,
+) {
+    // Clock, start/stop and song position drive the transport
+    if matches!(message.first(), Some(0xF2 | 0xF8 | 0xFA | 0xFB | 0xFC)) {
+        synth.transport.midi_message(message, timestamp);
+        return;
+    }
+    let (status, data1, data2) = match *message {
+        [status, data1, data2] => (status, data1, data2),
+        [status, data1] => (status, data1, 0),
+        _ => return,
+    };
+    let channel = status & 0x0F;
+    match status & 0xF0 {
+        0x90 if data2 > 0 => synth.channel_note_on(channel, data1),
+        // Note on with zero velocity is a note off
+        0x80 | 0x90 => synth.channel_note_off(channel, data1),
+        0xA0 => synth.poly_pressure(channel, data1, data2),
+        0xB0 => match data1 {
+            101 => parameters.selected[channel as usize].0 = data2,
+            100 => parameters.selected[channel as usize].1 = data2,
+            6 => match parameters.selected[channel as usize] {
+                (0, 0) => synth.set_bend_sensitivity(channel, data2 as f32),
+                (0, 6) => synth.mpe.configure_zone(channel, data2),
+                _ => {}
+            },
+            _ => synth.control_change(channel, data1, data2),
+        },
+        0xD0 => synth.channel_pressure(channel, data1),
+        0xE0 => synth.pitch_wheel(channel, ((data2 as u16) << 7) | data1 as u16),
+        _ => {}
+    }
+}
+
+// Messages a capture has room for before it has to grow, about an hour of
+// busy playing
+const CAPTURE_CAPACITY: usize = 1 << 17;
+
+// Channel messages recorded as they arrive, timed in seconds of synth clock.
+// They are kept at a fixed size in a buffer made up front, so recording
+// doesn't allocate while the synth is locked.
+pub struct Capture {
+    start: f64,
+    // Tempo when recording began, which the file's bars follow
+    pub tempo: f32,
+    // Time, then the message padded to three bytes with its length
+    messages: Vec<(f64, [u8; 3], usize)>,
+    // Notes and sustain pedals still down, to let go of at the end
+    held: Vec<(u8, u8)>,
+    pedals: [bool; 16],
+}
+
+impl Capture {
+    // Makes the buffer; recording starts once `begin` sets the time
+    pub fn new(tempo: f32) -> Self {
+        Self {
+            start: 0.0,
+            tempo,
+            messages: Vec::with_capacity(CAPTURE_CAPACITY),
+            held: Vec::with_capacity(128),
+            pedals: [false; 16],
+        }
+    }
+
+    pub fn begin(&mut self, start: f64) {
+        self.start = start;
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn push(&mut self, now: f64, message: &[u8]) {
+        let (status, data1, data2) = match *message {
+            [status, data1, data2] => (status, data1, data2),
+            [status, data1] => (status, data1, 0),
+            _ => return,
+        };
+        let channel = status & 0x0F;
+        match status & 0xF0 {
+            0x90 if data2 > 0 => self.held.push((channel, data1)),
+            0x80 | 0x90 => self.held.retain(|&held| held != (channel, data1)),
+            0xB0 if data1 == 64 => self.pedals[channel as usize] = data2 >= 64,
+            _ => {}
+        }
+        let mut bytes = [0; 3];
+        bytes[..message.len()].copy_from_slice(message);
+        self.messages.push((now - self.start, bytes, message.len()));
+    }
+
+    // The messages with everything still down released at `now`
+    pub fn finish(self, now: f64) -> Vec<TimedMessage> {
+        let time = now - self.start;
+        let mut messages: Vec<TimedMessage> = self
+            .messages
+            .iter()
+            .map(|(time, bytes, length)| TimedMessage {
+                time: *time,
+                message: bytes[..*length].to_vec(),
+            })
+            .collect();
+        for (channel, note) in self.held {
+            messages.push(TimedMessage {
+                time,
+                message: vec![0x80 | channel, note, 0],
+            });
+        }
+        for (channel, _) in self.pedals.iter().enumerate().filter(|(_, down)| **down) {
+            messages.push(TimedMessage {
+                time,
+                message: vec![0xB0 | channel as u8, 64, 0],
+            });
+        }
+        messages
+    }
+}
+
+// Plays a MIDI file into the synth against its clock
+pub struct Player {
+    file: MidiFile,
+    start: f64,
+    next: usize,
+    parameters: RegisteredParameters,
+    held: Vec<(u8, u8)>,
+}
+
+impl Player {
+    // Ready to play from the file's start once `begin` sets the time
+    pub fn new(file: MidiFile) -> Self {
+        Self {
+            file,
+            start: 0.0,
+            next: 0,
+            parameters: RegisteredParameters::default(),
+            held: Vec::with_capacity(128),
+        }
+    }
+
+    pub fn begin(&mut self, start: f64) {
+        self.start = start;
+    }
+
+    // Seconds played and the length of the file
+    pub fn progress(&self, now: f64) -> (f64, f64) {
+        (now - self.start, self.file.duration())
+    }
+
+    // Sends the messages that are due; false once the file has ended
+    pub fn process(&mut self, synth: &mut Synth) -> bool {
+        let time = synth.clock - self.start;
+        while let Some(timed) = self.file.messages.get(self.next).filter(|timed| timed.time <= time) {
+            if let [status, note, velocity] = timed.message[..] {
+                let key = (status & 0x0F, note);
+                match status & 0xF0 {
+                    0x90 if velocity > 0 => self.held.push(key),
+                    0x80 | 0x90 => self.held.retain(|&held| held != key),
+                    _ => {}
+                }
+            }
+            let timestamp = (timed.time * 1_000_000.0) as u64;
+            handle_message(synth, &mut self.parameters, &timed.message, timestamp);
+            self.next += 1;
+        }
+        self.next < self.file.messages.len()
+    }
+
+    // Lets go of the notes the file left sounding
+    pub fn stop(&mut self, synth: &mut Synth) {
+        for (channel, note) in self.held.drain(..) {
+            synth.channel_note_off(channel, note);
+        }
+    }
+}
@@ -0,0 +1,387 @@
+use crate::{DelayLine, NoteDivision, ProcessContext, SmoothedParam};
+use serde::{Deserialize, Serialize};
+use std::f32::consts::PI;
+
+// Longest flanger delay including modulation, in seconds
+pub const MAX_FLANGER_DELAY: f32 = 0.02;
+pub const MAX_PHASER_STAGES: usize = 12;
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum LfoShape {
+    Sine,
+    Triangle,
+}
+
+impl LfoShape {
+    pub const ALL: [LfoShape; 2] = [LfoShape::Sine, LfoShape::Triangle];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            LfoShape::Sine => "Sine",
+            LfoShape::Triangle => "Triangle",
+        }
+    }
+}
+
+// Low-frequency oscillator for modulation effects, free running in Hz or
+// locked to a note division of the tempo
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Lfo {
+    pub shape: LfoShape,
+    pub rate: SmoothedParam,
+    pub sync: Option<NoteDivision>,
+    #[serde(skip)]
+    phase: f32,
+}
+
+impl Lfo {
+    pub fn new(rate: f32) -> Self {
+        Self {
+            shape: LfoShape::Sine,
+            rate: SmoothedParam::new(rate),
+            sync: None,
+            phase: 0.0,
+        }
+    }
+
+    // Moves the oscillator on by one sample. A synced LFO follows the
+    // transport position while it plays, so cycles line up with the beat.
+    pub fn advance(&mut self, context: &ProcessContext) {
+        if let Some(division) = self.sync {
+            self.rate.target = 1.0 / division.seconds(context.tempo);
+            if let Some(position) = context.position {
+                self.phase = (position / division.beats() as f64).fract() as f32;
+                return;
+            }
+        }
+        let rate = self.rate.next(context.smoothing);
+        self.phase = (self.phase + rate / context.sample_rate).fract();
+    }
+
+    // Output in -1..1, `offset` cycles ahead of the current phase
+    pub fn value(&self, offset: f32) -> f32 {
+        let phase = (self.phase + offset).rem_euclid(1.0);
+        match self.shape {
+            LfoShape::Sine => (2.0 * PI * phase).sin(),
+            // Shifted to rise through zero at phase 0 like the sine
+            LfoShape::Triangle => 4.0 * ((phase + 0.75).fract() - 0.5).abs() - 1.0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.phase = 0.0;
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FlangerParameters {
+    pub lfo: Lfo,
+    // Seconds
+    pub delay: SmoothedParam,
+    pub depth: SmoothedParam,
+    // Negative values invert the fed back signal for a hollower sound
+    pub feedback: SmoothedParam,
+    // Delays the dry signal so the sweep can pass through zero
+    pub through_zero: bool,
+    // LFO phase difference between the channels, in cycles
+    pub stereo_phase: SmoothedParam,
+    pub mix: SmoothedParam,
+    #[serde(skip)]
+    lines: [DelayLine; 2],
+    #[serde(skip)]
+    last_wet: [f32; 2],
+}
+
+impl FlangerParameters {
+    pub fn new(mix: f32) -> Self {
+        Self {
+            lfo: Lfo::new(0.25),
+            delay: SmoothedParam::new(0.002),
+            depth: SmoothedParam::new(0.0015),
+            feedback: SmoothedParam::new(0.5),
+            through_zero: false,
+            stereo_phase: SmoothedParam::new(0.25),
+            mix: SmoothedParam::new(mix),
+            lines: Default::default(),
+            last_wet: [0.0; 2],
+        }
+    }
+
+    pub fn process(&mut self, input: [f32; 2], context: &ProcessContext) -> [f32; 2] {
+        let delay = self.delay.next(context.smoothing);
+        let depth = self.depth.next(context.smoothing);
+        let feedback = self.feedback.next(context.smoothing);
+        let stereo_phase = self.stereo_phase.next(context.smoothing);
+        let mix = self.mix.next(context.smoothing);
+        self.lfo.advance(context);
+
+        let mut output = [0.0; 2];
+        for channel in 0..2 {
+            let modulation = self.lfo.value(stereo_phase * channel as f32);
+            let line = &mut self.lines[channel];
+            line.write(input[channel] + self.last_wet[channel] * feedback);
+
+            // Through-zero sweeps the wet tap around a dry signal delayed by the
+            // base time; otherwise the wet tap sweeps upwards from the base time
+            let (dry, wet_delay) = if self.through_zero {
+                (line.read(1.0 + delay * context.sample_rate), delay + depth * modulation)
+            } else {
+                (input[channel], delay + depth * (modulation + 1.0) * 0.5)
+            };
+            let wet = line.read(1.0 + wet_delay.max(0.0) * context.sample_rate);
+            self.last_wet[channel] = wet;
+            output[channel] = dry * (1.0 - mix) + wet * mix;
+        }
+        output
+    }
+
+    pub fn reset(&mut self) {
+        for line in self.lines.iter_mut() {
+            line.clear();
+        }
+        self.last_wet = [0.0; 2];
+        self.lfo.reset();
+    }
+
+    pub fn prepare(&mut self, sample_rate: f32) {
+        let length = (MAX_FLANGER_DELAY * sample_rate) as usize + 4;
+        self.lines = [DelayLine::new(length), DelayLine::new(length)];
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PhaserParameters {
+    pub lfo: Lfo,
+    // Number of first-order all-pass stages, each pair adding one notch
+    pub stages: usize,
+    // Hz, the sweep moves logarithmically between them
+    pub min_frequency: SmoothedParam,
+    pub max_frequency: SmoothedParam,
+    pub feedback: SmoothedParam,
+    pub stereo_phase: SmoothedParam,
+    pub mix: SmoothedParam,
+    #[serde(skip)]
+    states: [[f32; MAX_PHASER_STAGES]; 2],
+    #[serde(skip)]
+    last_output: [f32; 2],
+}
+
+impl PhaserParameters {
+    pub fn new(stages: usize, mix: f32) -> Self {
+        Self {
+            lfo: Lfo::new(0.5),
+            stages,
+            min_frequency: SmoothedParam::new(200.0),
+            max_frequency: SmoothedParam::new(3000.0),
+            feedback: SmoothedParam::new(0.5),
+            stereo_phase: SmoothedParam::new(0.25),
+            mix: SmoothedParam::new(mix),
+            states: [[0.0; MAX_PHASER_STAGES]; 2],
+            last_output: [0.0; 2],
+        }
+    }
+
+    pub fn process(&mut self, input: [f32; 2], context: &ProcessContext) -> [f32; 2] {
+        let min_frequency = self.min_frequency.next(context.smoothing);
+        let max_frequency = self.max_frequency.next(context.smoothing);
+        let feedback = self.feedback.next(context.smoothing);
+        let stereo_phase = self.stereo_phase.next(context.smoothing);
+        let mix = self.mix.next(context.smoothing);
+        self.lfo.advance(context);
+        let stages = self.stages.min(MAX_PHASER_STAGES);
+
+        let mut output = [0.0; 2];
+        for channel in 0..2 {
+            let position = (self.lfo.value(stereo_phase * channel as f32) + 1.0) * 0.5;
+            let frequency = (min_frequency * (max_frequency / min_frequency).powf(position))
+                .min(context.sample_rate * 0.45);
+            let tangent = (PI * frequency / context.sample_rate).tan();
+            let coefficient = (tangent - 1.0) / (tangent + 1.0);
+
+            let mut sample = input[channel] + self.last_output[channel] * feedback;
+            for state in self.states[channel][..stages].iter_mut() {
+                let filtered = coefficient * sample + *state;
+                *state = sample - coefficient * filtered;
+                sample = filtered;
+            }
+            self.last_output[channel] = sample;
+            output[channel] = input[channel] * (1.0 - mix) + sample * mix;
+        }
+        output
+    }
+
+    pub fn reset(&mut self) {
+        self.states = [[0.0; MAX_PHASER_STAGES]; 2];
+        self.last_output = [0.0; 2];
+        self.lfo.reset();
+    }
+}
+
+// Longest chorus tap, base delay plus full depth, in seconds
+pub const MAX_CHORUS_DELAY: f32 = 0.05;
+pub const MAX_CHORUS_VOICES: usize = 4;
+
+// How taps between two samples of the delay line are read
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Interpolation {
+    Linear,
+    Cubic,
+    // First-order all-pass, flat in level but keeps some state per tap
+    Allpass,
+}
+
+impl Interpolation {
+    pub const ALL: [Interpolation; 3] = [
+        Interpolation::Linear,
+        Interpolation::Cubic,
+        Interpolation::Allpass,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Interpolation::Linear => "Linear",
+            Interpolation::Cubic => "Cubic",
+            Interpolation::Allpass => "All-pass",
+        }
+    }
+}
+
+// Settings of the Roland Juno-60 and Juno-106 bucket-brigade choruses
+#[derive(Clone, Copy, PartialEq)]
+pub enum JunoMode {
+    One,
+    Two,
+    Both,
+}
+
+impl JunoMode {
+    pub const ALL: [JunoMode; 3] = [
+        JunoMode::One,
+        JunoMode::Two,
+        JunoMode::Both,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            JunoMode::One => "Juno I",
+            JunoMode::Two => "Juno II",
+            JunoMode::Both => "Juno I+II",
+        }
+    }
+}
+
+// Taps on one shared delay line, each voice offset in LFO phase, with the
+// right channel reading the LFO `spread` cycles apart from the left
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ChorusParameters {
+    pub lfo: Lfo,
+    pub voices: usize,
+    pub interpolation: Interpolation,
+    // Seconds, the taps sweep from `delay` up to `delay + depth`
+    pub delay: SmoothedParam,
+    pub depth: SmoothedParam,
+    pub feedback: SmoothedParam,
+    pub spread: SmoothedParam,
+    pub mix: SmoothedParam,
+    #[serde(skip)]
+    line: DelayLine,
+    // Previous output of each all-pass tap, per voice and channel
+    #[serde(skip)]
+    allpass_states: [[f32; 2]; MAX_CHORUS_VOICES],
+    #[serde(skip)]
+    last_wet: f32,
+}
+
+impl ChorusParameters {
+    pub fn new(voices: usize, mix: f32) -> Self {
+        Self {
+            lfo: Lfo::new(0.5),
+            voices: voices.clamp(1, MAX_CHORUS_VOICES),
+            interpolation: Interpolation::Cubic,
+            delay: SmoothedParam::new(0.007),
+            depth: SmoothedParam::new(0.004),
+            feedback: SmoothedParam::new(0.0),
+            spread: SmoothedParam::new(0.25),
+            mix: SmoothedParam::new(mix),
+            line: DelayLine::default(),
+            allpass_states: [[0.0; 2]; MAX_CHORUS_VOICES],
+            last_wet: 0.0,
+        }
+    }
+
+    pub fn apply_juno(&mut self, mode: JunoMode) {
+        // Both modes of the Juno-60 sweep the same range at different rates;
+        // the 106's combined mode is a fast, shallow vibrato
+        let (rate, delay, depth) = match mode {
+            JunoMode::One => (0.513, 0.00166, 0.00369),
+            JunoMode::Two => (0.863, 0.00166, 0.00369),
+            JunoMode::Both => (9.75, 0.00328, 0.0005),
+        };
+        self.lfo.shape = LfoShape::Triangle;
+        self.lfo.sync = None;
+        self.lfo.rate.target = rate;
+        self.voices = 1;
+        self.delay.target = delay;
+        self.depth.target = depth;
+        self.feedback.target = 0.0;
+        // The two channels take the LFO in opposite polarity
+        self.spread.target = 0.5;
+        self.mix.target = 0.5;
+    }
+
+    pub fn process(&mut self, input: [f32; 2], context: &ProcessContext) -> [f32; 2] {
+        let delay = self.delay.next(context.smoothing);
+        let depth = self.depth.next(context.smoothing);
+        let feedback = self.feedback.next(context.smoothing);
+        let spread = self.spread.next(context.smoothing);
+        let mix = self.mix.next(context.smoothing);
+        self.lfo.advance(context);
+
+        let mono = (input[0] + input[1]) * 0.5;
+        self.line.write(mono + self.last_wet * feedback);
+
+        let voices = self.voices.clamp(1, MAX_CHORUS_VOICES);
+        let mut wet = [0.0; 2];
+        for voice in 0..voices {
+            let voice_phase = voice as f32 / voices as f32;
+            for (channel, sample) in wet.iter_mut().enumerate() {
+                let modulation = self.lfo.value(voice_phase + spread * channel as f32);
+                let tap = 1.0 + (delay + depth * (modulation + 1.0) * 0.5) * context.sample_rate;
+                *sample += match self.interpolation {
+                    Interpolation::Linear => self.line.read_linear(tap),
+                    Interpolation::Cubic => self.line.read(tap),
+                    Interpolation::Allpass => {
+                        // Keeping the fractional part in 0.618..1.618 keeps the
+                        // coefficient well inside the unit circle
+                        let state = &mut self.allpass_states[voice][channel];
+                        let whole = (tap - 0.618).floor().max(1.0) as usize;
+                        let fraction = tap - whole as f32;
+                        let coefficient = (1.0 - fraction) / (1.0 + fraction);
+                        let output = coefficient * self.line.at(whole) + self.line.at(whole + 1)
+                            - coefficient * *state;
+                        *state = output;
+                        output
+                    }
+                };
+            }
+        }
+        let wet = wet.map(|sample| sample / voices as f32);
+        self.last_wet = (wet[0] + wet[1]) * 0.5;
+
+        [
+            input[0] * (1.0 - mix) + wet[0] * mix,
+            input[1] * (1.0 - mix) + wet[1] * mix,
+        ]
+    }
+
+    pub fn reset(&mut self) {
+        self.line.clear();
+        self.allpass_states = [[0.0; 2]; MAX_CHORUS_VOICES];
+        self.last_wet = 0.0;
+        self.lfo.reset();
+    }
+
+    pub fn prepare(&mut self, sample_rate: f32) {
+        self.line = DelayLine::new((MAX_CHORUS_DELAY * sample_rate) as usize + 4);
+    }
+}
@@ -0,0 +1,103 @@
+use crate::sequencer::{Sequencer, Step, MAX_PATTERN_LENGTH};
+use crate::{AuxBus, Effect, EffectAddress, EffectStack, MAX_AUX_BUSES};
+use serde::{Deserialize, Serialize};
+
+// The effect chain, aux buses and sequencer patterns as stored on disk
+#[derive(Deserialize)]
+pub struct Patch {
+    pub effects: EffectStack,
+    #[serde(default)]
+    pub buses: Vec<AuxBus>,
+    #[serde(default = "Sequencer::new")]
+    pub sequencer: Sequencer,
+}
+
+#[derive(Serialize)]
+struct PatchRef<'a> {
+    effects: &'a EffectStack,
+    buses: &'a [AuxBus],
+    sequencer: &'a Sequencer,
+}
+
+pub fn to_string(effects: &EffectStack, buses: &[AuxBus], sequencer: &Sequencer) -> Result<String, String> {
+    toml::to_string_pretty(&PatchRef {
+        effects,
+        buses,
+        sequencer,
+    })
+    .map_err(|err| format!("Failed to encode patch: {}", err))
+}
+
+pub fn write(path: &str, text: &str) -> Result<(), String> {
+    std::fs::write(path, text).map_err(|err| format!("Failed to write {}: {}", path, err))
+}
+
+// Reads and parses a saved patch without checking it can be played
+pub fn read(path: &str) -> Result<Patch, String> {
+    let text =
+        std::fs::read_to_string(path).map_err(|err| format!("Failed to read {}: {}", path, err))?;
+    let mut patch: Patch =
+        toml::from_str(&text).map_err(|err| format!("Failed to parse {}: {}", path, err))?;
+    // Patterns play up to their length, so they are filled out with rests
+    for pattern in patch.sequencer.patterns.iter_mut() {
+        pattern.steps.resize(MAX_PATTERN_LENGTH, Step::default());
+        pattern.length = pattern.length.clamp(1, MAX_PATTERN_LENGTH);
+    }
+    Ok(patch)
+}
+
+// Allocates the buffers of a checked patch, so it can be swapped into a
+// running synth in one step
+pub fn prepare(patch: &mut Patch, sample_rate: f32) {
+    patch.effects.prepare(sample_rate);
+    for bus in patch.buses.iter_mut() {
+        bus.effects.prepare(sample_rate);
+    }
+}
+
+// Reads a patch and gets it ready to play, failing on its first problem
+pub fn load(path: &str, sample_rate: f32) -> Result<Patch, String> {
+    let mut patch = read(path)?;
+    if let Some(problem) = problems(&mut patch).first() {
+        return Err(format!("Failed to load {}: {}", path, problem));
+    }
+    prepare(&mut patch, sample_rate);
+    Ok(patch)
+}
+
+// Anything that parses but can't be played: more buses than the synth
+// mixes, or a split without a crossover between each pair of branches
+pub fn problems(patch: &mut Patch) -> Vec<String> {
+    let mut problems = Vec::new();
+    if patch.buses.len() > MAX_AUX_BUSES {
+        problems.push(format!(
+            "{} aux buses, but at most {} are supported",
+            patch.buses.len(),
+            MAX_AUX_BUSES
+        ));
+    }
+    let stacks = std::iter::once((None, &mut patch.effects))
+        .chain(patch.buses.iter_mut().enumerate().map(|(index, bus)| (Some(index), &mut bus.effects)));
+    for (bus, stack) in stacks {
+        let root = EffectAddress { bus, path: Vec::new() };
+        stack.for_each_slot(&root, &mut |address, slot| {
+            let Effect::Split(params) = &slot.effect else {
+                return;
+            };
+            let expected = params.branches.len().saturating_sub(1);
+            if params.crossovers.len() != expected {
+                let slots: Vec<String> = address.path.iter().map(|index| (index + 1).to_string()).collect();
+                let location = bus.map_or("Effect".to_string(), |bus| format!("Bus {} effect", bus + 1));
+                problems.push(format!(
+                    "{} {} (Split) has {} branches but {} crossovers instead of {}",
+                    location,
+                    slots.join("."),
+                    params.branches.len(),
+                    params.crossovers.len(),
+                    expected
+                ));
+            }
+        });
+    }
+    problems
+}
@@ -0,0 +1,259 @@
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+
+// Frames per block handed to the writer thread
+const BLOCK_FRAMES: usize = 4096;
+// Blocks allocated up front; with the writer stalled this many blocks of
+// audio (about 2.7s at 48 kHz) can queue before frames are dropped
+const BLOCK_COUNT: usize = 32;
+pub const DEFAULT_MAX_MINUTES: f32 = 30.0;
+
+// Why a recording ended without being stopped
+#[derive(Clone, Copy, PartialEq)]
+pub enum StopReason {
+    MaxLength,
+    PunchOut,
+    WriterFailed,
+}
+
+// The audio side of a recording: frames are gathered into preallocated
+// blocks and passed to the writer thread without ever waiting on it
+pub struct Session {
+    blocks: SyncSender<Vec<f32>>,
+    free: Receiver<Vec<f32>>,
+    block: Vec<f32>,
+    // Output left and right, then the dry voice sum when it is kept
+    channels: usize,
+    // Start and end in quarter notes; frames are only taken in between
+    // while the transport plays
+    punch: Option<(f64, f64)>,
+    frames: u64,
+    max_frames: u64,
+    dropped: u64,
+}
+
+impl Session {
+    // Passes the current block on and takes an empty one. Returns false
+    // once the writer has gone.
+    fn flush(&mut self) -> bool {
+        if self.block.is_empty() {
+            return true;
+        }
+        let Ok(empty) = self.free.try_recv() else {
+            // No block to switch to, so this one is reused and its audio lost
+            self.dropped += (self.block.len() / self.channels) as u64;
+            self.block.clear();
+            return true;
+        };
+        let full = std::mem::replace(&mut self.block, empty);
+        match self.blocks.try_send(full) {
+            Ok(()) => true,
+            Err(TrySendError::Full(full)) => {
+                self.dropped += (full.len() / self.channels) as u64;
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Recorder {
+    session: Option<Session>,
+    // A session that has ended, kept until the UI takes it to drop outside
+    // the audio lock
+    ended: Option<Session>,
+    pub stopped: Option<StopReason>,
+}
+
+impl Recorder {
+    pub fn begin(&mut self, session: Session) {
+        self.session = Some(session);
+        self.stopped = None;
+    }
+
+    pub fn recording(&self) -> bool {
+        self.session.is_some()
+    }
+
+    // Frames captured and frames lost to a slow disk
+    pub fn progress(&self) -> (u64, u64) {
+        self.session
+            .as_ref()
+            .map_or((0, 0), |session| (session.frames, session.dropped))
+    }
+
+    // Hands over what is left and sets the session aside; the writer
+    // finishes the file once it is dropped
+    pub fn stop(&mut self) {
+        if let Some(mut session) = self.session.take() {
+            session.flush();
+            self.ended = Some(session);
+        }
+    }
+
+    pub fn take_ended(&mut self) -> Option<Session> {
+        self.ended.take()
+    }
+
+    fn end(&mut self, reason: StopReason) {
+        self.stop();
+        self.stopped = Some(reason);
+    }
+
+    // Called from the audio callback with the final output and the voice
+    // sum it was made from; `position` is the transport position while it
+    // plays
+    pub fn capture(&mut self, output: [f32; 2], dry: f32, position: Option<f64>) {
+        let Some(session) = &mut self.session else {
+            return;
+        };
+        if let Some((start, end)) = session.punch {
+            match position {
+                Some(position) if position >= end => return self.end(StopReason::PunchOut),
+                Some(position) if position >= start => {}
+                _ => return,
+            }
+        }
+        session.block.extend_from_slice(&output);
+        if session.channels == 3 {
+            session.block.push(dry);
+        }
+        session.frames += 1;
+        if session.frames >= session.max_frames {
+            return self.end(StopReason::MaxLength);
+        }
+        if session.block.len() >= BLOCK_FRAMES * session.channels && !session.flush() {
+            self.end(StopReason::WriterFailed);
+        }
+    }
+}
+
+pub struct RecordSettings {
+    pub path: String,
+    // Also write the voices before the effects to a mono file beside it
+    pub dry: bool,
+    pub max_seconds: f32,
+    pub punch: Option<(f64, f64)>,
+}
+
+// A file being written; the thread returns the frames written
+pub struct Take {
+    pub path: String,
+    pub dry_path: Option<String>,
+    thread: JoinHandle<Result<u64, String>>,
+}
+
+impl Take {
+    pub fn finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    pub fn join(self) -> Result<u64, String> {
+        self.thread
+            .join()
+            .unwrap_or_else(|_| Err("Recording thread panicked".to_string()))
+    }
+}
+
+// The first `name-NNN.extension` in `directory` that isn't taken
+pub fn next_path(directory: &str, name: &str, extension: &str) -> String {
+    (1..)
+        .map(|index| Path::new(directory).join(format!("{}-{:03}.{}", name, index, extension)))
+        .find(|path| !path.exists() && !dry_path(&path.to_string_lossy()).exists())
+        .map(|path| path.to_string_lossy().into_owned())
+        .unwrap()
+}
+
+// Makes the folder a recording goes in
+pub fn create_parent(path: &str) -> Result<(), String> {
+    match Path::new(path).parent().filter(|directory| !directory.as_os_str().is_empty()) {
+        Some(directory) => std::fs::create_dir_all(directory)
+            .map_err(|err| format!("Failed to create {}: {}", directory.display(), err)),
+        None => Ok(()),
+    }
+}
+
+fn dry_path(path: &str) -> std::path::PathBuf {
+    let path = Path::new(path);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}-dry.wav", stem))
+}
+
+// Opens the files and starts the writer thread. The session goes to the
+// synth's recorder and the take stays with the caller.
+pub fn start(settings: &RecordSettings, sample_rate: f32) -> Result<(Session, Take), String> {
+    create_parent(&settings.path)?;
+    let spec = |channels| hound::WavSpec {
+        channels,
+        sample_rate: sample_rate as u32,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    let open = |path: &str, channels| {
+        hound::WavWriter::create(path, spec(channels)).map_err(|err| format!("Failed to create {}: {}", path, err))
+    };
+    let output = open(&settings.path, 2)?;
+    let dry_path = settings.dry.then(|| dry_path(&settings.path).to_string_lossy().into_owned());
+    let dry = dry_path.as_deref().map(|path| open(path, 1)).transpose()?;
+
+    let channels = if settings.dry { 3 } else { 2 };
+    let (blocks, queued) = mpsc::sync_channel(BLOCK_COUNT);
+    let (returned, free) = mpsc::sync_channel(BLOCK_COUNT);
+    for _ in 0..BLOCK_COUNT - 1 {
+        let _ = returned.try_send(Vec::with_capacity(BLOCK_FRAMES * channels));
+    }
+    let path = settings.path.clone();
+    let thread = thread::spawn(move || write(output, dry, queued, returned, channels, &path));
+
+    let session = Session {
+        blocks,
+        free,
+        block: Vec::with_capacity(BLOCK_FRAMES * channels),
+        channels,
+        punch: settings.punch,
+        frames: 0,
+        max_frames: (settings.max_seconds.max(1.0) * sample_rate) as u64,
+        dropped: 0,
+    };
+    let take = Take {
+        path: settings.path.clone(),
+        dry_path,
+        thread,
+    };
+    Ok((session, take))
+}
+
+type WavFile = hound::WavWriter<std::io::BufWriter<std::fs::File>>;
+
+// Writes blocks until the session is dropped, handing each block back to
+// be filled again
+fn write(
+    mut output: WavFile,
+    mut dry: Option<WavFile>,
+    blocks: Receiver<Vec<f32>>,
+    returned: SyncSender<Vec<f32>>,
+    channels: usize,
+    path: &str,
+) -> Result<u64, String> {
+    let error = |err: hound::Error| format!("Failed to write {}: {}", path, err);
+    let mut frames = 0;
+    for mut block in blocks {
+        for frame in block.chunks_exact(channels) {
+            output.write_sample(frame[0]).map_err(error)?;
+            output.write_sample(frame[1]).map_err(error)?;
+            if let Some(dry) = &mut dry {
+                dry.write_sample(frame[2]).map_err(error)?;
+            }
+        }
+        frames += (block.len() / channels) as u64;
+        block.clear();
+        let _ = returned.try_send(block);
+    }
+    output.finalize().map_err(error)?;
+    if let Some(dry) = dry {
+        dry.finalize().map_err(error)?;
+    }
+    Ok(frames)
+}
@@ -0,0 +1,397 @@
+// Scale tables in the `gamlar` format. Each non-empty line is one of:
+//
+//   C : C D E F G A B         pitch classes starting from their root
+//   c - b : { B E }           an unordered set of pitch classes
+//   Dorian = 0 2 3 5 7 9 10   semitones above whatever root is chosen
+//
+// `#` starts a comment. Only the last form is transposed by the root
+// selector; the others name fixed pitch classes.
+
+pub const NOTE_NAMES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+
+const BUILTIN_SCALES: &str = "\
+Major = 0 2 4 5 7 9 11
+Natural Minor = 0 2 3 5 7 8 10
+Harmonic Minor = 0 2 3 5 7 8 11
+Melodic Minor = 0 2 3 5 7 9 11
+Dorian = 0 2 3 5 7 9 10
+Phrygian = 0 1 3 5 7 8 10
+Lydian = 0 2 4 6 7 9 11
+Mixolydian = 0 2 4 5 7 9 10
+Locrian = 0 1 3 5 6 8 10
+Major Pentatonic = 0 2 4 7 9
+Minor Pentatonic = 0 3 5 7 10
+Blues = 0 3 5 6 7 10
+Whole Tone = 0 2 4 6 8 10
+Chromatic = 0 1 2 3 4 5 6 7 8 9 10 11
+";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum PitchSet {
+    // Bit per semitone above the chosen root
+    Relative(u16),
+    // Bit per pitch class from C, with the root when the table gives one
+    Absolute { mask: u16, root: Option<u8> },
+}
+
+#[derive(Clone)]
+pub struct Scale {
+    pub name: String,
+    pub pitches: PitchSet,
+}
+
+impl Scale {
+    // Semitones above the root, or above the lowest pitch class for a set
+    // without one
+    pub fn intervals(&self) -> Vec<u8> {
+        let (mask, root) = match self.pitches {
+            PitchSet::Relative(mask) => (mask, 0),
+            PitchSet::Absolute { mask, root } => {
+                let lowest = (0..12).find(|pitch| mask & (1 << pitch) != 0).unwrap_or(0);
+                (mask, root.unwrap_or(lowest))
+            }
+        };
+        (0..12u8)
+            .filter(|interval| mask & (1 << ((root + interval) % 12)) != 0)
+            .collect()
+    }
+}
+
+pub fn note_name(note: u8) -> String {
+    format!("{}{}", NOTE_NAMES[note as usize % 12], note as i32 / 12 - 1)
+}
+
+// Letter name with any number of sharps or flats, e.g. `F#`, `bb` or `C`
+fn parse_pitch_class(token: &str) -> Option<u8> {
+    let mut chars = token.chars();
+    let natural: i32 = match chars.next()?.to_ascii_uppercase() {
+        'C' => 0,
+        'D' => 2,
+        'E' => 4,
+        'F' => 5,
+        'G' => 7,
+        'A' => 9,
+        'B' => 11,
+        _ => return None,
+    };
+    let mut pitch = natural;
+    for accidental in chars {
+        match accidental {
+            '#' => pitch += 1,
+            'b' => pitch -= 1,
+            _ => return None,
+        }
+    }
+    Some(pitch.rem_euclid(12) as u8)
+}
+
+pub fn parse(text: &str) -> Result<Vec<Scale>, String> {
+    let mut scales = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = strip_comment(line);
+        if line.is_empty() {
+            continue;
+        }
+        let error = |message: &str| format!("line {}: {}", number + 1, message);
+
+        let scale = if let Some((name, intervals)) = line.split_once('=') {
+            let mut mask = 0u16;
+            for token in intervals.split_whitespace() {
+                let interval: u8 = token
+                    .parse()
+                    .map_err(|_| error(&format!("`{token}` is not a semitone count")))?;
+                mask |= 1 << (interval % 12);
+            }
+            Scale {
+                name: name.trim().to_string(),
+                pitches: PitchSet::Relative(mask),
+            }
+        } else if let Some((name, notes)) = line.split_once(':') {
+            let notes = notes.trim();
+            let (notes, ordered) = match notes.strip_prefix('{') {
+                Some(set) => (
+                    set.strip_suffix('}').ok_or_else(|| error("missing `}`"))?,
+                    false,
+                ),
+                None => (notes, true),
+            };
+            let mut mask = 0u16;
+            let mut root = None;
+            for token in notes.split_whitespace() {
+                let pitch = parse_pitch_class(token)
+                    .ok_or_else(|| error(&format!("`{token}` is not a note name")))?;
+                mask |= 1 << pitch;
+                if ordered && root.is_none() {
+                    root = Some(pitch);
+                }
+            }
+            Scale {
+                name: name.trim().to_string(),
+                pitches: PitchSet::Absolute { mask, root },
+            }
+        } else {
+            return Err(error("expected `name : notes` or `name = intervals`"));
+        };
+
+        if scale.name.is_empty() {
+            return Err(error("missing scale name"));
+        }
+        scales.push(scale);
+    }
+    Ok(scales)
+}
+
+// Cuts a comment that starts with a `#` at the beginning of the line or
+// after whitespace, leaving sharps in note names alone
+fn strip_comment(line: &str) -> &str {
+    let mut previous = ' ';
+    for (index, character) in line.char_indices() {
+        if character == '#' && previous.is_whitespace() {
+            return line[..index].trim();
+        }
+        previous = character;
+    }
+    line.trim()
+}
+
+pub fn load(path: &str) -> Result<Vec<Scale>, String> {
+    let text = std::fs::read_to_string(path).map_err(|err| format!("Failed to read {}: {}", path, err))?;
+    parse(&text).map_err(|err| format!("{}: {}", path, err))
+}
+
+pub fn builtin() -> Vec<Scale> {
+    parse(BUILTIN_SCALES).expect("built-in scales are valid")
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ScaleMode {
+    // Every note plays as mapped
+    Off,
+    // Consecutive keys step through consecutive scale notes
+    Map,
+    // Out-of-scale notes move to the nearest scale note
+    Snap,
+    // Out-of-scale notes are ignored
+    Disable,
+}
+
+impl ScaleMode {
+    pub const ALL: [ScaleMode; 4] = [ScaleMode::Off, ScaleMode::Map, ScaleMode::Snap, ScaleMode::Disable];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            ScaleMode::Off => "Off",
+            ScaleMode::Map => "Map Keys to Scale",
+            ScaleMode::Snap => "Snap",
+            ScaleMode::Disable => "Disable Out-of-Scale",
+        }
+    }
+}
+
+pub struct ScaleSettings {
+    pub scales: Vec<Scale>,
+    pub selected: usize,
+    // Pitch class chosen by the user, used by relative scales and by sets
+    // that don't name a root
+    pub root: u8,
+    pub mode: ScaleMode,
+}
+
+impl ScaleSettings {
+    pub fn new(scales: Vec<Scale>) -> Self {
+        Self {
+            scales,
+            selected: 0,
+            root: 0,
+            mode: ScaleMode::Off,
+        }
+    }
+
+    pub fn scale(&self) -> Option<&Scale> {
+        self.scales.get(self.selected)
+    }
+
+    // Pitch class the degrees are counted from
+    pub fn effective_root(&self) -> u8 {
+        match self.scale().map(|scale| scale.pitches) {
+            Some(PitchSet::Absolute { root: Some(root), .. }) => root,
+            _ => self.root,
+        }
+    }
+
+    // Bit per pitch class from C
+    fn mask(&self) -> u16 {
+        match self.scale().map(|scale| scale.pitches) {
+            Some(PitchSet::Relative(mask)) => {
+                let rotated = ((mask as u32) << self.root) | ((mask as u32) >> (12 - self.root as u32));
+                (rotated & 0xFFF) as u16
+            }
+            Some(PitchSet::Absolute { mask, .. }) => mask,
+            None => 0xFFF,
+        }
+    }
+
+    pub fn contains(&self, note: u8) -> bool {
+        self.mask() & (1 << (note % 12)) != 0
+    }
+
+    // Zero-based position of the note within its octave of the scale,
+    // counted from the root
+    pub fn degree(&self, note: u8) -> Option<usize> {
+        if !self.contains(note) {
+            return None;
+        }
+        let root = self.effective_root();
+        let offset = (note % 12 + 12 - root) % 12;
+        Some(
+            (0..offset)
+                .filter(|step| self.contains(root + step))
+                .count(),
+        )
+    }
+
+    // Nearest scale note, preferring the lower one on a tie
+    pub fn snap(&self, note: u8) -> Option<u8> {
+        (0..12u8).find_map(|distance| {
+            [note.checked_sub(distance), note.checked_add(distance)]
+                .into_iter()
+                .flatten()
+                .find(|&candidate| candidate <= 127 && self.contains(candidate))
+        })
+    }
+
+    // Moves `steps` scale notes up or down from the lowest scale note at or
+    // above `base`
+    fn step(&self, base: u8, steps: i32) -> Option<u8> {
+        let next = |note: u8, direction: i32| {
+            let mut note = note as i32;
+            loop {
+                note += direction;
+                if !(0..=127).contains(&note) {
+                    return None;
+                }
+                if self.contains(note as u8) {
+                    return Some(note as u8);
+                }
+            }
+        };
+        let mut note = if self.contains(base) { base } else { next(base, 1)? };
+        for _ in 0..steps.unsigned_abs() {
+            note = next(note, steps.signum())?;
+        }
+        Some(note)
+    }
+
+    // Note to play for a key mapped to `note`, where `base` is the note of
+    // the first key so mapped keys count scale steps from there. None means
+    // the key is silent.
+    pub fn apply(&self, note: u8, base: u8) -> Option<u8> {
+        if self.mask() == 0 {
+            return Some(note);
+        }
+        match self.mode {
+            ScaleMode::Off => Some(note),
+            ScaleMode::Map => self.step(base, note as i32 - base as i32),
+            ScaleMode::Snap => self.snap(note),
+            ScaleMode::Disable => self.contains(note).then_some(note),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mask(pitch_classes: &[u8]) -> u16 {
+        pitch_classes.iter().fold(0, |mask, pitch| mask | 1 << pitch)
+    }
+
+    #[test]
+    fn parses_the_shipped_gamlar_file() {
+        let scales = parse(include_str!("../gamlar")).unwrap();
+        let names: Vec<&str> = scales.iter().map(|scale| scale.name.as_str()).collect();
+        assert_eq!(
+            names,
+            ["C", "D", "E", "F", "G", "A", "B", "c - b", "c - d", "c - e", "c - f", "c - g", "c - a"]
+        );
+
+        assert!(scales[1].pitches == PitchSet::Absolute { mask: mask(&[2, 4, 6, 7, 9, 11, 1]), root: Some(2) });
+        // Sharps are read as written, so F's A# is its fourth
+        assert!(scales[3].pitches == PitchSet::Absolute { mask: mask(&[5, 7, 9, 10, 0, 2, 4]), root: Some(5) });
+        assert_eq!(scales[3].intervals(), [0, 2, 4, 5, 7, 9, 11]);
+        // Sets in braces have no root and count from their lowest pitch
+        assert!(scales[7].pitches == PitchSet::Absolute { mask: mask(&[11, 4]), root: None });
+        assert_eq!(scales[7].intervals(), [0, 7]);
+        assert_eq!(scales[8].intervals(), [0, 2, 5, 7, 9]);
+    }
+
+    #[test]
+    fn comments_and_errors() {
+        let scales = parse("# heading\nPair = 0 7 # fifths\nSharp : F# # comment").unwrap();
+        assert!(scales[0].pitches == PitchSet::Relative(mask(&[0, 7])));
+        assert!(scales[1].pitches == PitchSet::Absolute { mask: mask(&[6]), root: Some(6) });
+
+        assert_eq!(parse("Bad = 0 x").err().unwrap(), "line 1: `x` is not a semitone count");
+        assert_eq!(parse("\nBad : C H").err().unwrap(), "line 2: `H` is not a note name");
+        assert!(parse("Open : { C E").is_err());
+        assert!(parse(" = 0 4 7").is_err());
+        assert!(parse("no separator").is_err());
+    }
+
+    // D major from the built-in relative scales
+    fn d_major(mode: ScaleMode) -> ScaleSettings {
+        let mut settings = ScaleSettings::new(builtin());
+        settings.root = 2;
+        settings.mode = mode;
+        settings
+    }
+
+    #[test]
+    fn off_plays_every_note() {
+        let settings = d_major(ScaleMode::Off);
+        assert!((0..=127).all(|note| settings.apply(note, 60) == Some(note)));
+    }
+
+    #[test]
+    fn map_steps_through_the_scale() {
+        let settings = d_major(ScaleMode::Map);
+        // Keys from C4 count scale steps from C#4, the first scale note
+        // at or above it
+        let played: Vec<Option<u8>> = (60..68).map(|note| settings.apply(note, 60)).collect();
+        let expected = [61, 62, 64, 66, 67, 69, 71, 73].map(Some);
+        assert_eq!(played, expected);
+        assert_eq!(settings.apply(59, 60), Some(59));
+        assert_eq!(settings.apply(127, 60), None);
+    }
+
+    #[test]
+    fn snap_moves_to_the_nearest_scale_note() {
+        let settings = d_major(ScaleMode::Snap);
+        assert_eq!(settings.apply(62, 60), Some(62));
+        assert_eq!(settings.apply(65, 60), Some(64));
+        // Ties go down
+        assert_eq!(settings.apply(63, 60), Some(62));
+        assert_eq!(settings.apply(60, 60), Some(59));
+    }
+
+    #[test]
+    fn disable_silences_out_of_scale_notes() {
+        let settings = d_major(ScaleMode::Disable);
+        assert_eq!(settings.apply(66, 60), Some(66));
+        assert_eq!(settings.apply(65, 60), None);
+        assert_eq!(settings.degree(66), Some(2));
+        assert_eq!(settings.degree(61), Some(6));
+    }
+
+    #[test]
+    fn absolute_scales_ignore_the_root_selector() {
+        let mut settings = ScaleSettings::new(parse("G : G A B C D E F#").unwrap());
+        settings.root = 5;
+        settings.mode = ScaleMode::Disable;
+        assert_eq!(settings.effective_root(), 7);
+        assert_eq!(settings.apply(66, 60), Some(66));
+        assert_eq!(settings.apply(65, 60), None);
+    }
+}
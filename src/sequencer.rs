@@ -0,0 +1,305 @@
+use crate::{EffectAddress, NoteDivision, NoteModifier, NoteValue, Synth, MAX_FILTER_CUTOFF};
+use serde::{Deserialize, Serialize};
+use std::ops::RangeInclusive;
+
+pub const PATTERN_LENGTHS: [usize; 3] = [16, 32, 64];
+pub const MAX_PATTERN_LENGTH: usize = 64;
+// Voices started by the sequencer get a channel past the 16 MIDI ones, so a
+// step never retriggers or lets go of a key the player is holding
+const SEQUENCER_CHANNEL: u8 = 16;
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SynthParameter {
+    FilterCutoff,
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    PitchBend,
+}
+
+impl SynthParameter {
+    pub const ALL: [SynthParameter; 6] = [
+        SynthParameter::FilterCutoff,
+        SynthParameter::Attack,
+        SynthParameter::Decay,
+        SynthParameter::Sustain,
+        SynthParameter::Release,
+        SynthParameter::PitchBend,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            SynthParameter::FilterCutoff => "Voice Filter Cutoff",
+            SynthParameter::Attack => "Attack",
+            SynthParameter::Decay => "Decay",
+            SynthParameter::Sustain => "Sustain",
+            SynthParameter::Release => "Release",
+            SynthParameter::PitchBend => "Pitch Bend",
+        }
+    }
+
+    // Matches the range of the parameter's control
+    pub fn range(self) -> RangeInclusive<f32> {
+        match self {
+            SynthParameter::FilterCutoff => 20.0..=MAX_FILTER_CUTOFF,
+            SynthParameter::Attack | SynthParameter::Decay => 0.01..=1.0,
+            SynthParameter::Sustain => 0.0..=1.0,
+            SynthParameter::Release => 0.01..=2.0,
+            SynthParameter::PitchBend => -1.0..=1.0,
+        }
+    }
+}
+
+// A synth setting, or a named setting of an effect as listed by
+// `Effect::parameters_mut`
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub enum ParameterTarget {
+    Synth(SynthParameter),
+    Effect { address: EffectAddress, name: String },
+}
+
+// Holds a parameter at `value` for as long as its step plays
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ParameterLock {
+    pub target: ParameterTarget,
+    pub value: f32,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Step {
+    // Empty for a rest; monophonic patterns only play the first note
+    pub notes: Vec<u8>,
+    pub velocity: f32,
+    // Fraction of the step the notes sound for
+    pub gate: f32,
+    // Hold the notes into the next step, which doesn't retrigger the ones
+    // it repeats
+    pub tie: bool,
+    // Chance of the step playing at all, locks included
+    pub probability: f32,
+    pub locks: Vec<ParameterLock>,
+}
+
+impl Default for Step {
+    fn default() -> Self {
+        Self {
+            notes: Vec::new(),
+            velocity: 1.0,
+            gate: 0.5,
+            tie: false,
+            probability: 1.0,
+            locks: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Pattern {
+    pub name: String,
+    // Steps played before moving on; the rest are kept so shortening a
+    // pattern doesn't lose them
+    pub length: usize,
+    pub polyphonic: bool,
+    pub rate: NoteDivision,
+    pub steps: Vec<Step>,
+}
+
+impl Pattern {
+    pub fn new(name: String) -> Self {
+        Self {
+            name,
+            length: 16,
+            polyphonic: false,
+            rate: NoteDivision::new(NoteValue::Sixteenth, NoteModifier::Straight),
+            steps: vec![Step::default(); MAX_PATTERN_LENGTH],
+        }
+    }
+}
+
+// Plays patterns in time with the transport. Only the patterns and the
+// chain are stored with a patch.
+#[derive(Default, Serialize, Deserialize)]
+pub struct Sequencer {
+    pub patterns: Vec<Pattern>,
+    // Order the patterns play in; when empty the selected pattern loops
+    pub chain: Vec<usize>,
+    // Pattern shown in the editor
+    pub selected: usize,
+    #[serde(skip)]
+    pub enabled: bool,
+    // Pattern and step that last played, for the editor
+    #[serde(skip)]
+    pub playhead: Option<(usize, usize)>,
+    // Next step to play: position in the chain, pattern, step and when
+    #[serde(skip)]
+    next: Option<(usize, usize, usize, f64)>,
+    #[serde(skip)]
+    last_position: f64,
+    #[serde(skip)]
+    sounding: Vec<u8>,
+    #[serde(skip)]
+    tied: bool,
+    #[serde(skip)]
+    gate_end: f64,
+    // Values the locked parameters had before the locks took them over
+    #[serde(skip)]
+    restore: Vec<(ParameterTarget, f32)>,
+}
+
+impl Sequencer {
+    pub fn new() -> Self {
+        Self {
+            patterns: vec![Pattern::new("Pattern 1".to_string())],
+            ..Default::default()
+        }
+    }
+
+    // Keeps locks on the effects they were made for when the stacks are
+    // edited. `remap` moves an address and returns false once its effect is
+    // gone, which drops the locks on it.
+    pub fn remap_effects(&mut self, remap: impl Fn(&mut EffectAddress) -> bool) {
+        let keep = |target: &mut ParameterTarget| match target {
+            ParameterTarget::Effect { address, .. } => remap(address),
+            ParameterTarget::Synth(_) => true,
+        };
+        for step in self.patterns.iter_mut().flat_map(|pattern| pattern.steps.iter_mut()) {
+            step.locks.retain_mut(|lock| keep(&mut lock.target));
+        }
+        self.restore.retain_mut(|(target, _)| keep(target));
+    }
+
+    fn first_pattern(&self) -> usize {
+        self.chain.first().copied().unwrap_or(self.selected)
+    }
+
+    // Runs for one sample. It plays while enabled and the transport is
+    // running, and starts from the top whenever the transport jumps back.
+    pub fn process(&mut self, synth: &mut Synth) {
+        let position = match synth.transport.playing && self.enabled {
+            true => synth.transport.position,
+            false => {
+                if self.next.is_some() {
+                    self.stop(synth);
+                }
+                return;
+            }
+        };
+        if self.next.is_none() || position < self.last_position {
+            self.stop(synth);
+            // Begin on the first pattern's step grid
+            let first = self.first_pattern();
+            let length = self.patterns.get(first).map_or(1.0, |pattern| pattern.rate.beats() as f64);
+            self.next = Some((0, first, 0, (position / length).ceil() * length));
+        }
+        self.last_position = position;
+
+        if !self.tied && position >= self.gate_end {
+            for note in self.sounding.drain(..) {
+                synth.stop_voice(SEQUENCER_CHANNEL, note);
+            }
+        }
+        if let Some((link, pattern, step, at)) = self.next {
+            if position >= at {
+                self.play_step(synth, link, pattern, step, at);
+            }
+        }
+    }
+
+    fn play_step(&mut self, synth: &mut Synth, link: usize, pattern_index: usize, step_index: usize, at: f64) {
+        let Some((pattern, step)) = self
+            .patterns
+            .get(pattern_index)
+            .and_then(|pattern| Some((pattern, pattern.steps.get(step_index)?)))
+        else {
+            self.stop(synth);
+            return;
+        };
+        let length = pattern.rate.beats() as f64;
+        let plays = !step.notes.is_empty() && rand::random::<f32>() < step.probability;
+        let notes: Vec<u8> = match (plays, pattern.polyphonic) {
+            (false, _) => Vec::new(),
+            (true, true) => step.notes.clone(),
+            (true, false) => step.notes[..1].to_vec(),
+        };
+
+        // Locks go first so they shape the notes of their own step. Put
+        // back what the previous step locked, unless this one locks it too
+        let locks: &[ParameterLock] = if plays { &step.locks } else { &[] };
+        self.restore.retain(|(target, value)| {
+            if locks.iter().any(|lock| lock.target == *target) {
+                return true;
+            }
+            if let Some(parameter) = synth.parameter_mut(target) {
+                *parameter = *value;
+            }
+            false
+        });
+        for lock in locks {
+            if let Some(parameter) = synth.parameter_mut(&lock.target) {
+                if !self.restore.iter().any(|(target, _)| *target == lock.target) {
+                    self.restore.push((lock.target.clone(), *parameter));
+                }
+                *parameter = lock.value;
+            }
+        }
+
+        // Tied notes that play again carry on without a new attack
+        let carried: Vec<u8> = match self.tied {
+            true => self.sounding.iter().copied().filter(|note| notes.contains(note)).collect(),
+            false => Vec::new(),
+        };
+        for &note in self.sounding.iter().filter(|note| !carried.contains(note)) {
+            synth.stop_voice(SEQUENCER_CHANNEL, note);
+        }
+        for &note in notes.iter().filter(|note| !carried.contains(note)) {
+            synth.start_voice(SEQUENCER_CHANNEL, note, step.velocity);
+        }
+        self.sounding = notes;
+        self.tied = plays && step.tie;
+        self.gate_end = at + length * step.gate as f64;
+
+        self.playhead = Some((pattern_index, step_index));
+        let mut next = (link, pattern_index, step_index + 1, at + length);
+        if next.2 >= pattern.length {
+            next.2 = 0;
+            if self.chain.is_empty() {
+                next.1 = self.selected;
+            } else {
+                next.0 = (link + 1) % self.chain.len();
+                next.1 = self.chain[next.0];
+            }
+        }
+        self.next = Some(next);
+    }
+
+    // Silences the sequencer's notes and hands locked parameters back
+    pub fn stop(&mut self, synth: &mut Synth) {
+        for note in self.sounding.drain(..) {
+            synth.stop_voice(SEQUENCER_CHANNEL, note);
+        }
+        for (target, value) in self.restore.drain(..) {
+            if let Some(parameter) = synth.parameter_mut(&target) {
+                *parameter = value;
+            }
+        }
+        self.tied = false;
+        self.next = None;
+        self.playhead = None;
+    }
+
+    // Chain entries pointing at a removed pattern are dropped and later
+    // ones renumbered
+    pub fn remove_pattern(&mut self, index: usize) {
+        if index >= self.patterns.len() {
+            return;
+        }
+        self.patterns.remove(index);
+        self.chain.retain(|&entry| entry != index);
+        for entry in self.chain.iter_mut().filter(|entry| **entry > index) {
+            *entry -= 1;
+        }
+        self.selected = self.selected.min(self.patterns.len().saturating_sub(1));
+        self.next = None;
+    }
+}
@@ -0,0 +1,365 @@
+// Standard MIDI File reading and writing, enough to play a song through the
+// synth and to save what was played on it
+
+// Tempo until the file sets one, in microseconds per quarter note
+const DEFAULT_TEMPO: u32 = 500_000;
+
+// A channel message and when it happens, in seconds from the start
+pub struct TimedMessage {
+    pub time: f64,
+    pub message: Vec<u8>,
+}
+
+pub struct MidiFile {
+    // Every track's channel messages merged in time order
+    pub messages: Vec<TimedMessage>,
+    // Tempo changes as seconds and beats per minute
+    pub tempos: Vec<(f64, f32)>,
+}
+
+impl MidiFile {
+    // When the last message happens
+    pub fn duration(&self) -> f64 {
+        self.messages.last().map_or(0.0, |message| message.time)
+    }
+}
+
+pub fn read(path: &str) -> Result<MidiFile, String> {
+    let bytes = std::fs::read(path).map_err(|err| format!("Failed to read {}: {}", path, err))?;
+    parse(&bytes).map_err(|err| format!("Failed to parse {}: {}", path, err))
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], String> {
+        let end = self.offset + count;
+        let taken = self.bytes.get(self.offset..end).ok_or("unexpected end of file")?;
+        self.offset = end;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    // Variable-length quantity: seven bits a byte, high bit set on all but
+    // the last
+    fn variable(&mut self) -> Result<u32, String> {
+        let mut value = 0u32;
+        for _ in 0..4 {
+            let byte = self.byte()?;
+            value = (value << 7) | (byte & 0x7F) as u32;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("variable-length value too long".to_string())
+    }
+
+    fn done(&self) -> bool {
+        self.offset >= self.bytes.len()
+    }
+}
+
+enum TrackEvent {
+    Message(Vec<u8>),
+    // Microseconds per quarter note
+    Tempo(u32),
+}
+
+fn parse_track(data: &[u8], events: &mut Vec<(u64, TrackEvent)>) -> Result<(), String> {
+    let mut reader = Reader { bytes: data, offset: 0 };
+    let mut tick = 0u64;
+    let mut running_status = None;
+    while !reader.done() {
+        tick += reader.variable()? as u64;
+        let mut status = reader.byte()?;
+        match status {
+            0xFF => {
+                let kind = reader.byte()?;
+                let length = reader.variable()? as usize;
+                let data = reader.take(length)?;
+                match (kind, data) {
+                    (0x2F, _) => break,
+                    (0x51, [a, b, c]) => {
+                        let tempo = u32::from_be_bytes([0, *a, *b, *c]);
+                        events.push((tick, TrackEvent::Tempo(tempo)));
+                    }
+                    _ => {}
+                }
+            }
+            0xF0 | 0xF7 => {
+                let length = reader.variable()? as usize;
+                reader.take(length)?;
+            }
+            // System common and real-time messages aren't played back, so
+            // they are skipped by their fixed lengths. System common ones
+            // end running status.
+            0xF1..=0xF6 | 0xF8..=0xFE => {
+                let length = match status {
+                    0xF1 | 0xF3 => 1,
+                    0xF2 => 2,
+                    _ => 0,
+                };
+                reader.take(length)?;
+                if status < 0xF8 {
+                    running_status = None;
+                }
+            }
+            _ => {
+                // Running status: the data byte belongs to the last status
+                let mut first = None;
+                if status < 0x80 {
+                    first = Some(status);
+                    status = running_status.ok_or("data byte without a status")?;
+                } else {
+                    running_status = Some(status);
+                }
+                let length = match status & 0xF0 {
+                    0xC0 | 0xD0 => 1,
+                    _ => 2,
+                };
+                let mut message = vec![status];
+                if let Some(first) = first {
+                    message.push(first);
+                }
+                while message.len() <= length {
+                    message.push(reader.byte()?);
+                }
+                events.push((tick, TrackEvent::Message(message)));
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn parse(bytes: &[u8]) -> Result<MidiFile, String> {
+    let mut reader = Reader { bytes, offset: 0 };
+    if reader.take(4)? != b"MThd" {
+        return Err("not a standard MIDI file".to_string());
+    }
+    let header_length = reader.u32()? as usize;
+    let mut header = Reader {
+        bytes: reader.take(header_length)?,
+        offset: 0,
+    };
+    let _format = header.u16()?;
+    let track_count = header.u16()?;
+    let division = header.u16()?;
+
+    let mut events = Vec::new();
+    for _ in 0..track_count {
+        if reader.done() {
+            break;
+        }
+        let kind = reader.take(4)?;
+        let length = reader.u32()? as usize;
+        let data = reader.take(length)?;
+        // Unknown chunks are skipped, as the format asks
+        if kind == b"MTrk" {
+            parse_track(data, &mut events)?;
+        }
+    }
+    // Stable, so events at the same tick keep their track order
+    events.sort_by_key(|(tick, _)| *tick);
+
+    // Ticks are a fraction of a quarter note, or of a second for SMPTE time
+    let smpte = division & 0x8000 != 0;
+    let ticks_per_unit = if smpte {
+        let frames = -((division >> 8) as u8 as i8) as f64;
+        frames * (division & 0xFF) as f64
+    } else {
+        division as f64
+    };
+    if ticks_per_unit <= 0.0 {
+        return Err("invalid time division".to_string());
+    }
+
+    let mut file = MidiFile {
+        messages: Vec::new(),
+        tempos: Vec::new(),
+    };
+    let mut tempo = DEFAULT_TEMPO;
+    let mut last_tick = 0;
+    let mut time = 0.0;
+    for (tick, event) in events {
+        let seconds_per_tick = match smpte {
+            true => 1.0 / ticks_per_unit,
+            false => tempo as f64 / 1_000_000.0 / ticks_per_unit,
+        };
+        time += (tick - last_tick) as f64 * seconds_per_tick;
+        last_tick = tick;
+        match event {
+            TrackEvent::Message(message) => file.messages.push(TimedMessage { time, message }),
+            TrackEvent::Tempo(0) => {}
+            TrackEvent::Tempo(microseconds) => {
+                tempo = microseconds;
+                file.tempos.push((time, 60_000_000.0 / microseconds as f32));
+            }
+        }
+    }
+    Ok(file)
+}
+
+// Ticks per quarter note in written files
+const WRITE_DIVISION: u16 = 480;
+
+fn push_variable(bytes: &mut Vec<u8>, value: u32) {
+    let mut groups = vec![(value & 0x7F) as u8];
+    let mut rest = value >> 7;
+    while rest > 0 {
+        groups.push((rest & 0x7F) as u8 | 0x80);
+        rest >>= 7;
+    }
+    bytes.extend(groups.iter().rev());
+}
+
+// A single-track file playing `messages` at their times, with ticks laid
+// out at `tempo` so the bars line up in a sequencer set to it
+pub fn to_bytes(messages: &[TimedMessage], tempo: f32) -> Vec<u8> {
+    let microseconds = (60_000_000.0 / tempo).round() as u32;
+    let mut track = Vec::new();
+    push_variable(&mut track, 0);
+    track.extend([0xFF, 0x51, 0x03]);
+    track.extend(&microseconds.to_be_bytes()[1..]);
+
+    let ticks_per_second = WRITE_DIVISION as f64 * tempo as f64 / 60.0;
+    let mut last_tick = 0;
+    for message in messages {
+        let tick = (message.time.max(0.0) * ticks_per_second).round() as u64;
+        let delta = tick.saturating_sub(last_tick).min(0x0FFF_FFFF);
+        push_variable(&mut track, delta as u32);
+        track.extend(&message.message);
+        last_tick = last_tick.max(tick);
+    }
+    push_variable(&mut track, 0);
+    track.extend([0xFF, 0x2F, 0x00]);
+
+    let mut bytes = Vec::new();
+    bytes.extend(b"MThd");
+    bytes.extend(6u32.to_be_bytes());
+    bytes.extend(0u16.to_be_bytes());
+    bytes.extend(1u16.to_be_bytes());
+    bytes.extend(WRITE_DIVISION.to_be_bytes());
+    bytes.extend(b"MTrk");
+    bytes.extend((track.len() as u32).to_be_bytes());
+    bytes.extend(track);
+    bytes
+}
+
+pub fn write(path: &str, messages: &[TimedMessage], tempo: f32) -> Result<(), String> {
+    std::fs::write(path, to_bytes(messages, tempo)).map_err(|err| format!("Failed to write {}: {}", path, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(time: f64, bytes: &[u8]) -> TimedMessage {
+        TimedMessage {
+            time,
+            message: bytes.to_vec(),
+        }
+    }
+
+    // A format 0 file at 96 ticks per quarter note holding one track
+    fn file_with_track(track: &[u8]) -> Vec<u8> {
+        let mut bytes = b"MThd".to_vec();
+        bytes.extend(6u32.to_be_bytes());
+        bytes.extend([0, 0, 0, 1, 0, 96]);
+        bytes.extend(b"MTrk");
+        bytes.extend((track.len() as u32).to_be_bytes());
+        bytes.extend(track);
+        bytes
+    }
+
+    #[test]
+    fn round_trip_keeps_times_and_tempo() {
+        let messages = vec![
+            message(0.0, &[0x90, 60, 100]),
+            message(0.5, &[0xB0, 64, 127]),
+            message(1.25, &[0x80, 60, 0]),
+            message(1.25, &[0xE1, 0, 64]),
+            message(3.0, &[0xD2, 90]),
+        ];
+        let file = parse(&to_bytes(&messages, 100.0)).unwrap();
+
+        assert_eq!(file.tempos.len(), 1);
+        assert_eq!(file.tempos[0].0, 0.0);
+        assert!((file.tempos[0].1 - 100.0).abs() < 0.01);
+        assert_eq!(file.messages.len(), messages.len());
+        // Times come back to within a tick
+        let tick = 60.0 / (100.0 * WRITE_DIVISION as f64);
+        for (read, written) in file.messages.iter().zip(&messages) {
+            assert_eq!(read.message, written.message);
+            assert!((read.time - written.time).abs() <= tick, "{} read as {}", written.time, read.time);
+        }
+    }
+
+    #[test]
+    fn long_deltas_use_several_bytes() {
+        let messages = vec![message(0.0, &[0x90, 60, 100]), message(600.0, &[0x80, 60, 0])];
+        let file = parse(&to_bytes(&messages, 120.0)).unwrap();
+        assert!((file.duration() - 600.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn running_status_repeats_the_last_status() {
+        let file = parse(&file_with_track(&[
+            0x00, 0x90, 60, 100, // note on
+            0x60, 64, 100, // a quarter note later, running status
+            0x00, 60, 0, // zero velocity as note off
+            0x00, 0xC3, 5, // program change takes one data byte
+            0x00, 7, // and so does its running status
+            0x00, 0xFF, 0x2F, 0x00,
+        ]))
+        .unwrap();
+
+        let read: Vec<(f64, Vec<u8>)> = file.messages.into_iter().map(|m| (m.time, m.message)).collect();
+        assert_eq!(
+            read,
+            vec![
+                (0.0, vec![0x90, 60, 100]),
+                (0.5, vec![0x90, 64, 100]),
+                (0.5, vec![0x90, 60, 0]),
+                (0.5, vec![0xC3, 5]),
+                (0.5, vec![0xC3, 7]),
+            ]
+        );
+    }
+
+    #[test]
+    fn tempo_changes_apply_from_their_tick() {
+        let file = parse(&file_with_track(&[
+            0x00, 0x90, 60, 100,
+            // Two quarter notes at the default 120 bpm, then 60 bpm
+            0x81, 0x40, 0xFF, 0x51, 0x03, 0x0F, 0x42, 0x40,
+            0x60, 0x80, 60, 0,
+            0x00, 0xFF, 0x2F, 0x00,
+        ]))
+        .unwrap();
+
+        assert_eq!(file.tempos, vec![(1.0, 60.0)]);
+        assert_eq!(file.messages[1].time, 2.0);
+    }
+
+    #[test]
+    fn data_without_a_status_is_an_error() {
+        assert!(parse(&file_with_track(&[0x00, 60, 100])).is_err());
+        assert!(parse(b"RIFF").is_err());
+    }
+}
@@ -0,0 +1,181 @@
+use std::time::{Duration, Instant};
+
+// MIDI clock resolution in pulses per quarter note
+const CLOCKS_PER_BEAT: f64 = 24.0;
+pub const MIN_TEMPO: f32 = 20.0;
+pub const MAX_TEMPO: f32 = 400.0;
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct TimeSignature {
+    pub beats: u32,
+    // Note value of one beat: 2, 4, 8 or 16
+    pub unit: u32,
+}
+
+impl TimeSignature {
+    // Length of one beat in quarter notes
+    pub fn beat_length(&self) -> f64 {
+        4.0 / self.unit as f64
+    }
+
+    pub fn bar_length(&self) -> f64 {
+        self.beats as f64 * self.beat_length()
+    }
+}
+
+// Global musical clock. Positions are in quarter notes so note divisions
+// can be compared against them regardless of the time signature.
+pub struct Transport {
+    pub tempo: f32,
+    pub time_signature: TimeSignature,
+    pub playing: bool,
+    pub position: f64,
+    // Take tempo, start/stop and position from incoming MIDI clock
+    pub follow_midi_clock: bool,
+    // Clock pulses counted since the last start, None until the first
+    // pulse after a start arrives
+    clock_ticks: Option<u64>,
+    // Timestamp of the previous pulse and the smoothed pulse spacing, in
+    // microseconds
+    last_clock: Option<u64>,
+    clock_interval: Option<f64>,
+}
+
+impl Transport {
+    pub fn new() -> Self {
+        Self {
+            tempo: 120.0,
+            time_signature: TimeSignature { beats: 4, unit: 4 },
+            playing: false,
+            position: 0.0,
+            follow_midi_clock: false,
+            clock_ticks: Some(0),
+            last_clock: None,
+            clock_interval: None,
+        }
+    }
+
+    // Moves the position on by one sample
+    pub fn advance(&mut self, sample_rate: f32) {
+        if !self.playing {
+            return;
+        }
+        let next = self.position + self.tempo as f64 / 60.0 / sample_rate as f64;
+        self.position = if self.follow_midi_clock {
+            // Never run ahead of the next expected pulse, so the position
+            // only moves forward and stays locked to the sender
+            match self.clock_ticks {
+                Some(ticks) => next.min((ticks + 1) as f64 / CLOCKS_PER_BEAT),
+                None => self.position,
+            }
+        } else {
+            next
+        };
+    }
+
+    pub fn play(&mut self) {
+        self.playing = true;
+    }
+
+    pub fn stop(&mut self) {
+        self.playing = false;
+    }
+
+    pub fn rewind(&mut self) {
+        self.position = 0.0;
+        self.clock_ticks = Some(0);
+    }
+
+    // Bar and beat, both counted from one
+    pub fn bar_and_beat(&self) -> (u64, u32) {
+        let bar_length = self.time_signature.bar_length();
+        let bar = (self.position / bar_length).floor();
+        let beat = ((self.position - bar * bar_length) / self.time_signature.beat_length()).floor();
+        (bar as u64 + 1, beat as u32 + 1)
+    }
+
+    // Handles a system real-time or song position message; `timestamp` is
+    // in microseconds
+    pub fn midi_message(&mut self, message: &[u8], timestamp: u64) {
+        if !self.follow_midi_clock {
+            return;
+        }
+        match *message {
+            [0xF8] => self.midi_clock(timestamp),
+            // Start plays from the top once the next pulse arrives
+            [0xFA] => {
+                self.position = 0.0;
+                self.clock_ticks = None;
+                self.playing = true;
+            }
+            [0xFB] => self.playing = true,
+            [0xFC] => self.playing = false,
+            // Song position pointer counts sixteenth notes, six pulses each
+            [0xF2, low, high] if !self.playing => {
+                let sixteenths = ((high as u64) << 7) | low as u64;
+                self.clock_ticks = Some(sixteenths * 6);
+                self.position = (sixteenths * 6) as f64 / CLOCKS_PER_BEAT;
+            }
+            _ => {}
+        }
+    }
+
+    fn midi_clock(&mut self, timestamp: u64) {
+        if let Some(last) = self.last_clock {
+            let interval = timestamp.saturating_sub(last) as f64;
+            // A long gap means the sender paused; start measuring afresh
+            if interval > 0.0 && interval < 250_000.0 {
+                let smoothed = match self.clock_interval {
+                    Some(previous) => previous + (interval - previous) * 0.1,
+                    None => interval,
+                };
+                self.clock_interval = Some(smoothed);
+                let tempo = 60_000_000.0 / (smoothed * CLOCKS_PER_BEAT);
+                self.tempo = (tempo as f32).clamp(MIN_TEMPO, MAX_TEMPO);
+            } else {
+                self.clock_interval = None;
+            }
+        }
+        self.last_clock = Some(timestamp);
+
+        if self.playing {
+            let ticks = self.clock_ticks.map_or(0, |ticks| ticks + 1);
+            self.clock_ticks = Some(ticks);
+            self.position = self.position.max(ticks as f64 / CLOCKS_PER_BEAT);
+        }
+    }
+}
+
+// Averages the spacing of recent button taps into a tempo
+#[derive(Default)]
+pub struct TapTempo {
+    taps: Vec<Instant>,
+}
+
+impl TapTempo {
+    const MAX_TAPS: usize = 5;
+    const TIMEOUT: Duration = Duration::from_secs(2);
+
+    pub fn tap(&mut self) -> Option<f32> {
+        let now = Instant::now();
+        if self
+            .taps
+            .last()
+            .is_some_and(|last| now.duration_since(*last) > Self::TIMEOUT)
+        {
+            self.taps.clear();
+        }
+        self.taps.push(now);
+        if self.taps.len() > Self::MAX_TAPS {
+            self.taps.remove(0);
+        }
+
+        let (first, last) = (self.taps.first()?, self.taps.last()?);
+        let intervals = self.taps.len() - 1;
+        if intervals == 0 {
+            return None;
+        }
+        let beat = last.duration_since(*first).as_secs_f32() / intervals as f32;
+        Some((60.0 / beat).clamp(MIN_TEMPO, MAX_TEMPO))
+    }
+}
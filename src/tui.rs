@@ -0,0 +1,542 @@
+// Terminal front end, for playing over SSH or without a display server.
+// Note keys follow the same layout as the window's computer keyboard.
+
+use crate::{keyboard, midi, EffectAddress, Synth, Waveform, EFFECT_PRESETS, MAX_FILTER_CUTOFF};
+use crossterm::event::{
+    self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, KeyboardEnhancementFlags,
+    PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+};
+use crossterm::style::Print;
+use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{cursor, execute, queue};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+use std::time::{Duration, Instant};
+
+// Without release events a note is held this long after its last press,
+// which key repeat keeps renewing while the key is down
+const HOLD_WITHOUT_RELEASE: Duration = Duration::from_millis(600);
+const FRAME_TIME: Duration = Duration::from_millis(33);
+const COLUMN_WIDTH: usize = 34;
+const METER_WIDTH: usize = 40;
+const METER_FLOOR_DB: f32 = -60.0;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Pane {
+    Synth,
+    Effects,
+    Parameters,
+}
+
+#[derive(Clone, Copy)]
+enum SynthRow {
+    Waveform,
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    FilterCutoff,
+    Tempo,
+    Transport,
+    SustainPedal,
+    Arpeggiator,
+    Chords,
+    Sequencer,
+}
+
+const SYNTH_ROWS: [SynthRow; 12] = [
+    SynthRow::Waveform,
+    SynthRow::Attack,
+    SynthRow::Decay,
+    SynthRow::Sustain,
+    SynthRow::Release,
+    SynthRow::FilterCutoff,
+    SynthRow::Tempo,
+    SynthRow::Transport,
+    SynthRow::SustainPedal,
+    SynthRow::Arpeggiator,
+    SynthRow::Chords,
+    SynthRow::Sequencer,
+];
+
+const WAVEFORMS: [(&str, Waveform); 5] = [
+    ("Sine", Waveform::Sine),
+    ("Square", Waveform::Square),
+    ("Saw", Waveform::Sawtooth),
+    ("Triangle", Waveform::Triangle),
+    ("Noise", Waveform::Noise),
+];
+
+fn on_off(value: bool) -> String {
+    if value { "On" } else { "Off" }.to_string()
+}
+
+impl SynthRow {
+    fn label(self) -> &'static str {
+        match self {
+            SynthRow::Waveform => "Waveform",
+            SynthRow::Attack => "Attack",
+            SynthRow::Decay => "Decay",
+            SynthRow::Sustain => "Sustain",
+            SynthRow::Release => "Release",
+            SynthRow::FilterCutoff => "Filter Cutoff",
+            SynthRow::Tempo => "Tempo",
+            SynthRow::Transport => "Transport",
+            SynthRow::SustainPedal => "Sustain Pedal",
+            SynthRow::Arpeggiator => "Arpeggiator",
+            SynthRow::Chords => "Chord Memory",
+            SynthRow::Sequencer => "Sequencer",
+        }
+    }
+
+    fn value(self, synth: &Synth) -> String {
+        match self {
+            SynthRow::Waveform => WAVEFORMS
+                .iter()
+                .find(|(_, waveform)| *waveform == synth.waveform)
+                .map_or("Additive", |(name, _)| name)
+                .to_string(),
+            SynthRow::Attack => format!("{:.3} s", synth.attack),
+            SynthRow::Decay => format!("{:.3} s", synth.decay),
+            SynthRow::Sustain => format!("{:.2}", synth.sustain),
+            SynthRow::Release => format!("{:.3} s", synth.release),
+            SynthRow::FilterCutoff => format!("{:.0} Hz", synth.filter_cutoff.target),
+            SynthRow::Tempo => format!("{:.1} BPM", synth.transport.tempo),
+            SynthRow::Transport => {
+                let (bar, beat) = synth.transport.bar_and_beat();
+                let state = if synth.transport.playing { "Playing" } else { "Stopped" };
+                format!("{state} {bar}.{beat}")
+            }
+            SynthRow::SustainPedal => on_off(synth.sustain_pedal),
+            SynthRow::Arpeggiator => on_off(synth.arpeggiator.enabled),
+            SynthRow::Chords => on_off(synth.chords.enabled),
+            SynthRow::Sequencer => on_off(synth.sequencer.enabled),
+        }
+    }
+
+    fn adjust(self, synth: &mut Synth, direction: f32, coarse: bool) {
+        let step = |value: &mut f32, range| *value = step_value(*value, &range, direction, coarse);
+        match self {
+            SynthRow::Waveform => {
+                let current = WAVEFORMS
+                    .iter()
+                    .position(|(_, waveform)| *waveform == synth.waveform)
+                    .unwrap_or(0);
+                let next = (current as i32 + direction as i32).rem_euclid(WAVEFORMS.len() as i32);
+                synth.waveform = WAVEFORMS[next as usize].1;
+            }
+            SynthRow::Attack => step(&mut synth.attack, 0.01..=1.0),
+            SynthRow::Decay => step(&mut synth.decay, 0.01..=1.0),
+            SynthRow::Sustain => step(&mut synth.sustain, 0.0..=1.0),
+            SynthRow::Release => step(&mut synth.release, 0.01..=2.0),
+            SynthRow::FilterCutoff => step(&mut synth.filter_cutoff.target, 20.0..=MAX_FILTER_CUTOFF),
+            SynthRow::Tempo => {
+                let change = if coarse { 10.0 } else { 1.0 };
+                synth.transport.tempo = (synth.transport.tempo + change * direction)
+                    .clamp(crate::transport::MIN_TEMPO, crate::transport::MAX_TEMPO);
+            }
+            // Right starts, left stops and a second left rewinds
+            SynthRow::Transport => {
+                if direction > 0.0 {
+                    synth.transport.play();
+                } else if synth.transport.playing {
+                    synth.transport.stop();
+                } else {
+                    synth.transport.rewind();
+                }
+            }
+            SynthRow::SustainPedal => synth.sustain(direction > 0.0),
+            SynthRow::Arpeggiator => synth.set_arpeggiator_enabled(direction > 0.0),
+            SynthRow::Chords => synth.chords.enabled = direction > 0.0,
+            SynthRow::Sequencer => synth.sequencer.enabled = direction > 0.0,
+        }
+    }
+}
+
+// Moves a value a hundredth of its range, or a tenth when coarse. Wide
+// ranges above zero, such as frequencies, step logarithmically.
+fn step_value(value: f32, range: &RangeInclusive<f32>, direction: f32, coarse: bool) -> f32 {
+    let (low, high) = (*range.start(), *range.end());
+    let steps = if coarse { 10.0 } else { 100.0 };
+    let next = if low > 0.0 && high / low >= 100.0 {
+        value.max(low) * (high / low).powf(direction / steps)
+    } else {
+        value + (high - low) * direction / steps
+    };
+    next.clamp(low, high)
+}
+
+fn meter(level: f32) -> String {
+    let db = 20.0 * level.max(1e-6).log10();
+    let filled = (((db - METER_FLOOR_DB) / -METER_FLOOR_DB).clamp(0.0, 1.0) * METER_WIDTH as f32) as usize;
+    let clip = if level >= 1.0 { " CLIP" } else { "" };
+    format!(
+        "[{}{}] {:>6.1} dB{}",
+        "#".repeat(filled),
+        "-".repeat(METER_WIDTH - filled),
+        db.max(METER_FLOOR_DB),
+        clip
+    )
+}
+
+// An effect as listed in the effects pane
+struct EffectEntry {
+    address: EffectAddress,
+    label: String,
+}
+
+fn effect_entries(synth: &mut Synth) -> Vec<EffectEntry> {
+    let mut entries = Vec::new();
+    let bus_names: Vec<String> = synth.buses.iter().map(|bus| bus.name.clone()).collect();
+    for bus in std::iter::once(None).chain((0..bus_names.len()).map(Some)) {
+        let Some(stack) = synth.stack_mut(bus) else {
+            continue;
+        };
+        let root = EffectAddress { bus, path: Vec::new() };
+        stack.for_each_slot(&root, &mut |address, slot| {
+            let location = bus.map_or(String::new(), |bus| format!("{} ", bus_names[bus]));
+            let slots: Vec<String> = address.path.iter().map(|index| (index + 1).to_string()).collect();
+            // Indent effects inside split branches
+            let indent = "  ".repeat(address.path.len() / 2);
+            let bypass = if slot.bypass { " (bypassed)" } else { "" };
+            entries.push(EffectEntry {
+                address: address.clone(),
+                label: format!("{indent}{location}{} {}{bypass}", slots.join("."), slot.effect.name()),
+            });
+        });
+    }
+    entries
+}
+
+// Restores the terminal however the front end exits
+struct TerminalGuard {
+    enhanced: bool,
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let mut stdout = io::stdout();
+        if self.enhanced {
+            let _ = execute!(stdout, PopKeyboardEnhancementFlags);
+        }
+        let _ = execute!(stdout, LeaveAlternateScreen, cursor::Show);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+struct Tui {
+    synth: std::sync::Arc<std::sync::Mutex<Synth>>,
+    midi_port: Option<String>,
+    keyboard: keyboard::KeyboardMap,
+    key_notes: HashMap<char, u8>,
+    // Whether a key release has arrived; until one does, notes time out
+    // even when releases were asked for
+    releases: bool,
+    // Note keys down, with the note they started and the time of their
+    // last press
+    held: HashMap<char, (u8, Instant)>,
+    pane: Pane,
+    synth_row: usize,
+    effect_row: usize,
+    parameter_row: usize,
+    // Index into EFFECT_PRESETS of the effect Insert adds
+    add_kind: usize,
+    quit: bool,
+}
+
+pub fn run() -> io::Result<()> {
+    let (synth, _stream) = crate::start_audio().map_err(io::Error::other)?;
+    let midi = midi::connect(synth.clone());
+
+    terminal::enable_raw_mode()?;
+    let mut stdout = io::stdout();
+    execute!(stdout, EnterAlternateScreen, cursor::Hide)?;
+    let enhanced = terminal::supports_keyboard_enhancement().unwrap_or(false);
+    if enhanced {
+        // Keys that type text only send releases when every key is reported
+        // as an escape code
+        execute!(
+            stdout,
+            PushKeyboardEnhancementFlags(
+                KeyboardEnhancementFlags::DISAMBIGUATE_ESCAPE_CODES
+                    | KeyboardEnhancementFlags::REPORT_ALL_KEYS_AS_ESCAPE_CODES
+                    | KeyboardEnhancementFlags::REPORT_EVENT_TYPES
+            )
+        )?;
+    }
+    let _guard = TerminalGuard { enhanced };
+    let (keyboard, _) = keyboard::load_or_default();
+
+    let mut tui = Tui {
+        synth,
+        midi_port: midi.as_ref().map(|connection| connection.port_name.clone()),
+        key_notes: keyboard.notes().into_iter().collect(),
+        keyboard,
+        releases: false,
+        held: HashMap::new(),
+        pane: Pane::Synth,
+        synth_row: 0,
+        effect_row: 0,
+        parameter_row: 0,
+        add_kind: 0,
+        quit: false,
+    };
+    while !tui.quit {
+        tui.draw(&mut stdout)?;
+        let deadline = Instant::now() + FRAME_TIME;
+        while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+            if !event::poll(timeout)? {
+                break;
+            }
+            if let Event::Key(key) = event::read()? {
+                tui.key(key);
+            }
+        }
+        tui.release_stale_notes();
+    }
+    Ok(())
+}
+
+impl Tui {
+    fn key(&mut self, key: KeyEvent) {
+        if key.kind == KeyEventKind::Release {
+            self.releases = true;
+        }
+        // Raw mode delivers Ctrl+C as a key rather than a signal
+        if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) {
+            self.quit = true;
+            return;
+        }
+        let mut synth = self.synth.lock().unwrap();
+        if let KeyCode::Char(character) = key.code {
+            let character = character.to_ascii_lowercase();
+            if character == ' ' {
+                // Held like a pedal when releases are reported, toggled otherwise
+                match key.kind {
+                    KeyEventKind::Press if self.releases => synth.sustain(true),
+                    KeyEventKind::Press => {
+                        let down = !synth.sustain_pedal;
+                        synth.sustain(down);
+                    }
+                    KeyEventKind::Release => synth.sustain(false),
+                    KeyEventKind::Repeat => {}
+                }
+                return;
+            }
+            if let Some(&note) = self.key_notes.get(&character) {
+                match key.kind {
+                    KeyEventKind::Press | KeyEventKind::Repeat => match self.held.get_mut(&character) {
+                        Some((_, pressed)) => *pressed = Instant::now(),
+                        None => {
+                            synth.note_on(note);
+                            self.held.insert(character, (note, Instant::now()));
+                        }
+                    },
+                    KeyEventKind::Release => {
+                        if let Some((played, _)) = self.held.remove(&character) {
+                            synth.note_off(played);
+                        }
+                    }
+                }
+                return;
+            }
+        }
+        if key.kind == KeyEventKind::Release {
+            return;
+        }
+
+        let coarse = key.modifiers.contains(KeyModifiers::SHIFT);
+        let entries = effect_entries(&mut synth);
+        self.effect_row = self.effect_row.min(entries.len().saturating_sub(1));
+        let selected = entries.get(self.effect_row).map(|entry| entry.address.clone());
+        let parameter_count = selected
+            .as_ref()
+            .and_then(|address| synth.effect_mut(address))
+            .map_or(0, |effect| effect.parameters_mut().len());
+        self.parameter_row = self.parameter_row.min(parameter_count.saturating_sub(1));
+
+        let mut removed = None;
+        match key.code {
+            KeyCode::Esc => self.quit = true,
+            KeyCode::Tab => {
+                self.pane = match self.pane {
+                    Pane::Synth => Pane::Effects,
+                    Pane::Effects => Pane::Parameters,
+                    Pane::Parameters => Pane::Synth,
+                }
+            }
+            KeyCode::BackTab => {
+                self.pane = match self.pane {
+                    Pane::Synth => Pane::Parameters,
+                    Pane::Effects => Pane::Synth,
+                    Pane::Parameters => Pane::Effects,
+                }
+            }
+            KeyCode::Up | KeyCode::Down => {
+                let (row, count) = match self.pane {
+                    Pane::Synth => (&mut self.synth_row, SYNTH_ROWS.len()),
+                    Pane::Effects => (&mut self.effect_row, entries.len()),
+                    Pane::Parameters => (&mut self.parameter_row, parameter_count),
+                };
+                if key.code == KeyCode::Up {
+                    *row = row.saturating_sub(1);
+                } else {
+                    *row = (*row + 1).min(count.saturating_sub(1));
+                }
+            }
+            KeyCode::Left | KeyCode::Right => {
+                let direction = if key.code == KeyCode::Left { -1.0 } else { 1.0 };
+                match self.pane {
+                    Pane::Synth => SYNTH_ROWS[self.synth_row].adjust(&mut synth, direction, coarse),
+                    Pane::Effects | Pane::Parameters => {
+                        let parameter = selected.as_ref().and_then(|address| {
+                            synth.effect_mut(address)?.parameters_mut().into_iter().nth(self.parameter_row)
+                        });
+                        if let Some(parameter) = parameter {
+                            parameter.param.target =
+                                step_value(parameter.param.target, &parameter.range, direction, coarse);
+                        }
+                    }
+                }
+            }
+            KeyCode::Enter => {
+                if let Some(address) = &selected {
+                    if let Some(slot) = synth.stack_mut(address.bus).and_then(|stack| stack.slot_mut(&address.path)) {
+                        slot.bypass = !slot.bypass;
+                    }
+                }
+            }
+            // Freeing the slot waits until the lock is let go
+            KeyCode::Delete => removed = selected,
+            KeyCode::PageUp => self.add_kind = (self.add_kind + EFFECT_PRESETS.len() - 1) % EFFECT_PRESETS.len(),
+            KeyCode::PageDown => self.add_kind = (self.add_kind + 1) % EFFECT_PRESETS.len(),
+            // Octave down and up; the arrows that do it in the GUI move
+            // around the panes here
+            KeyCode::F(1) | KeyCode::F(2) => {
+                self.keyboard.shift_octave(if key.code == KeyCode::F(2) { 1 } else { -1 });
+                self.key_notes = self.keyboard.notes().into_iter().collect();
+            }
+            KeyCode::Insert => {
+                let (_, build) = EFFECT_PRESETS[self.add_kind];
+                let effect = build(synth.sample_rate);
+                synth.effects.add_effect(effect);
+            }
+            _ => {}
+        }
+        drop(synth);
+        if let Some(address) = removed {
+            crate::edit_slot(&self.synth, &address, crate::SlotAction::Remove);
+        }
+    }
+
+    fn release_stale_notes(&mut self) {
+        if self.releases {
+            return;
+        }
+        let mut synth = self.synth.lock().unwrap();
+        self.held.retain(|_, (played, pressed)| {
+            let keep = pressed.elapsed() < HOLD_WITHOUT_RELEASE;
+            if !keep {
+                synth.note_off(*played);
+            }
+            keep
+        });
+    }
+
+    fn draw(&mut self, stdout: &mut io::Stdout) -> io::Result<()> {
+        let mut synth = self.synth.lock().unwrap();
+        let mut lines = Vec::new();
+        let midi = self.midi_port.as_deref().unwrap_or("no input");
+        let keys = if self.releases { "key releases" } else { "no key releases, notes time out" };
+        let layout = self.keyboard.layout.label();
+        let octave = self.keyboard.octave;
+        lines.push(format!("Synthesizer   MIDI: {midi}   Keyboard: {layout}, octave {octave:+}, {keys}"));
+        lines.push(
+            "Tab pane  Up/Down select  Left/Right adjust (Shift coarse)  Enter bypass  Del remove  \
+             PgUp/PgDn/Ins add effect  F1/F2 octave  Space sustain  Esc/Ctrl+C quit"
+                .to_string(),
+        );
+        lines.push(String::new());
+        lines.push(format!("L {}", meter(synth.output_level[0])));
+        lines.push(format!("R {}", meter(synth.output_level[1])));
+        lines.push(format!("Voices: {}", synth.voices.len()));
+        lines.push(String::new());
+
+        let marker = |pane: Pane, row: usize, selected: usize| {
+            match (pane == self.pane, row == selected) {
+                (true, true) => "> ",
+                (false, true) => "* ",
+                _ => "  ",
+            }
+        };
+        let synth_column: Vec<String> = SYNTH_ROWS
+            .iter()
+            .enumerate()
+            .map(|(index, row)| {
+                format!(
+                    "{}{:<15}{}",
+                    marker(Pane::Synth, index, self.synth_row),
+                    row.label(),
+                    row.value(&synth)
+                )
+            })
+            .collect();
+        let entries = effect_entries(&mut synth);
+        let mut effects_column: Vec<String> = entries
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| format!("{}{}", marker(Pane::Effects, index, self.effect_row), entry.label))
+            .collect();
+        if effects_column.is_empty() {
+            effects_column.push("  (no effects)".to_string());
+        }
+        effects_column.push(String::new());
+        effects_column.push(format!("  Add: < {} >", EFFECT_PRESETS[self.add_kind].0));
+        let parameters_column: Vec<String> = entries
+            .get(self.effect_row)
+            .and_then(|entry| synth.effect_mut(&entry.address))
+            .map(|effect| {
+                effect
+                    .parameters_mut()
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, parameter)| {
+                        format!(
+                            "{}{:<18}{:.3}",
+                            marker(Pane::Parameters, index, self.parameter_row),
+                            parameter.name,
+                            parameter.param.target
+                        )
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let titles = ["Synth", "Effects", "Parameters"];
+        lines.push(
+            titles
+                .iter()
+                .map(|title| format!("{title:<COLUMN_WIDTH$}"))
+                .collect::<String>(),
+        );
+        let rows = synth_column.len().max(effects_column.len()).max(parameters_column.len());
+        for row in 0..rows {
+            let cell = |column: &Vec<String>| {
+                let text: String = column.get(row).map_or("", String::as_str).chars().take(COLUMN_WIDTH - 1).collect();
+                format!("{text:<COLUMN_WIDTH$}")
+            };
+            lines.push(format!("{}{}{}", cell(&synth_column), cell(&effects_column), cell(&parameters_column)));
+        }
+        drop(synth);
+
+        let (width, height) = terminal::size()?;
+        queue!(stdout, cursor::MoveTo(0, 0))?;
+        for (index, line) in lines.iter().take(height as usize).enumerate() {
+            let line: String = line.chars().take(width as usize).collect();
+            queue!(stdout, cursor::MoveTo(0, index as u16), Print(line), Clear(ClearType::UntilNewLine))?;
+        }
+        queue!(stdout, Clear(ClearType::FromCursorDown))?;
+        stdout.flush()
+    }
+}